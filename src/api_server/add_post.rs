@@ -2,7 +2,7 @@ use std::sync::Mutex;
 use rocket::{State, response::status::BadRequest};
 use rocket_contrib::Json;
 
-use images::{Image, Size, SizedImage};
+use images::{Image, SizedImage};
 use worker::{WorkerId, WorkerManager};
 use post::{BluummPost, Hashtag};
 use error::Error;
@@ -13,19 +13,15 @@ fn handler(
     id: u64,
     json: Json<RawAddBluummPostArg>,
     worker_manager: State<Mutex<WorkerManager<OriginImageSize, PieceImageSize>>>,
-) -> Result<&'static str, BadRequest<()>> {
-    match worker_manager
-        .inner()
-        .lock()
-        .unwrap()
-        .get_worker(WorkerId::from_raw(id))
-    {
-        Some(worker) => match encode_arg(json.into_inner()) {
+) -> Result<&'static str, BadRequest<String>> {
+    let worker_manager = worker_manager.inner().lock().unwrap();
+    match worker_manager.get_worker(WorkerId::from_raw(id)) {
+        Some(worker) => match encode_arg(json.into_inner(), &worker_manager) {
             Ok(post) => {
                 worker.add_bluumm_post(post);
                 Ok("Success")
             }
-            Err(_e) => Err(BadRequest(None)),
+            Err(e) => Err(BadRequest(Some(e.to_string()))),
         },
         None => Err(BadRequest(None)),
     }
@@ -38,13 +34,19 @@ struct RawAddBluummPostArg {
     hashtag: String,
 }
 
-fn encode_arg<SS: Size>(arg: RawAddBluummPostArg) -> Result<BluummPost<SS>, Error> {
+// Image of any size is resized into a piece after its quality is checked.
+// Small images such as 30x30 pieces are accepted as before.
+fn encode_arg(
+    arg: RawAddBluummPostArg,
+    worker_manager: &WorkerManager<OriginImageSize, PieceImageSize>,
+) -> Result<BluummPost<PieceImageSize>, Error> {
     let image = encode_image(arg.image.as_str())?;
-    let sized_image = SizedImage::new(image)?;
+    let hashtag = Hashtag::new(arg.hashtag)?;
+    worker_manager.check_quality(&image, arg.user_name.as_str(), &hashtag)?;
     Ok(BluummPost::new(
-        sized_image,
+        SizedImage::with_resize(image),
         arg.user_name,
-        Hashtag::new(arg.hashtag),
+        hashtag,
     ))
 }
 
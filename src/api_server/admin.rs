@@ -0,0 +1,56 @@
+use rocket::{Outcome, State, http::Status, request::{self, FromRequest, Request}};
+
+const BEARER_PREFIX: &str = "Bearer ";
+
+/// Token which admin APIs require. Admin APIs are not found if it is `None`.
+pub struct AdminToken(pub Option<String>);
+
+/// Request guard of admin APIs such as moderation and takedown.
+/// `Authorization: Bearer <token>` is required.
+pub struct Admin;
+
+impl<'a, 'r> FromRequest<'a, 'r> for Admin {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Admin, ()> {
+        let token = match request.guard::<State<AdminToken>>() {
+            Outcome::Success(token) => token,
+            _ => return Outcome::Failure((Status::InternalServerError, ())),
+        };
+        let expected = match token.inner().0 {
+            Some(ref expected) => expected.as_str(),
+            None => return Outcome::Failure((Status::NotFound, ())),
+        };
+        let given = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|v| match v.starts_with(BEARER_PREFIX) {
+                true => Some(&v[BEARER_PREFIX.len()..]),
+                false => None,
+            });
+        match given {
+            Some(given) if is_same_token(given.as_bytes(), expected.as_bytes()) => {
+                Outcome::Success(Admin)
+            }
+            _ => Outcome::Failure((Status::Unauthorized, ())),
+        }
+    }
+}
+
+// Compared in constant time so that the token can not be guessed from response time.
+fn is_same_token(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compare_tokens() {
+        assert!(is_same_token(b"secret", b"secret"));
+        assert!(!is_same_token(b"secret", b"secreT"));
+        assert!(!is_same_token(b"secret", b"secret2"));
+        assert!(!is_same_token(b"", b"secret"));
+    }
+}
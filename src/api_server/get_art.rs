@@ -1,10 +1,12 @@
-use std::sync::{Arc, Mutex};
-use rocket::{State, response::status::NotFound};
+use std::{collections::HashMap, str::FromStr, sync::{Arc, Mutex}};
+use rocket::{State, http::Status, response::{Failure, status::NotFound}};
 use rocket_contrib::Json;
+use image::Rgba;
 
-use mosaic::MosaicArt;
-use post::{BluummPost, GenericPost, Hashtag, HashtagList, InstaPost, InstaPostId, Post};
-use images::Size;
+use mosaic::{MosaicArt, Shadow, TileStyle, style::parse_hex_color};
+use post::{BluummPost, ExternalPost, ExternalPostId, GenericPost, Hashtag, HashtagList, InstaPost,
+           InstaPostId, LocalPost, LocalPostId, MastodonPost, MastodonPostId, Post};
+use images::{BlendMode, Overlay, Size, SizedImage};
 use worker::{WorkerId, WorkerManager};
 use util::{IdHashMap, Id};
 
@@ -14,7 +16,7 @@ use super::{OriginImageSize, PieceImageSize};
 // get mosaic art API
 // =================================
 
-#[get("/worker/<id>/mosaic_art")]
+#[get("/worker/<id>/mosaic_art", rank = 2)]
 fn handler(
     id: u64,
     worker_manager: State<Mutex<WorkerManager<OriginImageSize, PieceImageSize>>>,
@@ -34,7 +36,7 @@ fn handler(
         Some(ref worker) => {
             let art = worker.get_art();
             let id = art.id;
-            let res = construct_response(art);
+            let res = construct_response(art, &RenderOption::default());
             art_response_cache.lock().unwrap().insert(id, res.clone());
             Ok(Json(res))
         }
@@ -42,13 +44,155 @@ fn handler(
     }
 }
 
-fn construct_response<S, SS>(art: Arc<MosaicArt<S, SS>>) -> MosaicArtResponse
+// Rendering the full size art is heavy, so that rendered responses are cached for each query
+// until the art is updated.
+#[get("/worker/<id>/mosaic_art?<query>", rank = 1)]
+fn handler_with_render_option(
+    id: u64,
+    query: RenderQuery,
+    worker_manager: State<Mutex<WorkerManager<OriginImageSize, PieceImageSize>>>,
+    rendered_cache: State<Mutex<IdHashMap<RenderedArts>>>,
+) -> Result<Json<MosaicArtResponse>, Failure> {
+    let option = RenderOption::from(query).map_err(|_| Failure(Status::BadRequest))?;
+    let art = match worker_manager
+        .inner()
+        .lock()
+        .unwrap()
+        .get_worker(WorkerId::from_raw(id))
+    {
+        Some(ref worker) => worker.get_art(),
+        None => return Err(Failure(Status::NotFound)),
+    };
+
+    let worker_id = Id::from_raw(id);
+    let key = format!("{:?}", option);
+    if let Some(rendered) = rendered_cache.lock().unwrap().get(&worker_id) {
+        if rendered.art_id == art.id {
+            if let Some(res) = rendered.responses.get(&key) {
+                return Ok(Json(res.clone()));
+            }
+        }
+    }
+
+    let art_id = art.id;
+    let res = construct_response(art, &option);
+    let mut cache = rendered_cache.lock().unwrap();
+    // Responses of an old art are dropped.
+    let mut rendered = cache
+        .remove(&worker_id)
+        .filter(|r| r.art_id == art_id && r.responses.len() < MAX_RENDERED_RESPONSES)
+        .unwrap_or_else(|| RenderedArts {
+            art_id: art_id,
+            responses: HashMap::new(),
+        });
+    rendered.responses.insert(key, res.clone());
+    cache.insert(worker_id, rendered);
+    Ok(Json(res))
+}
+
+// Max number of queries whose responses are cached for each worker.
+const MAX_RENDERED_RESPONSES: usize = 8;
+
+/// Responses of the latest art of a worker rendered with queries.
+pub struct RenderedArts {
+    art_id: Id,
+    // Keyed by debug representation of render option.
+    responses: HashMap<String, MosaicArtResponse>,
+}
+
+#[derive(FromForm)]
+struct RenderQuery {
+    opacity: Option<f64>,
+    blend: Option<String>, // "normal", "multiply" or "soft-light"
+    grout_width: Option<u32>,
+    grout_color: Option<String>, // "rrggbb" or "rrggbbaa"
+    corner_radius: Option<u32>,
+    shadow_offset: Option<u32>,
+    shadow_opacity: Option<f64>,
+}
+
+#[derive(Debug, Default)]
+struct RenderOption {
+    tile_style: Option<TileStyle>,
+    overlay: Option<Overlay>,
+}
+
+impl RenderOption {
+    fn from(query: RenderQuery) -> Result<RenderOption, String> {
+        let shadow = match (query.shadow_offset, query.shadow_opacity) {
+            (None, None) => None,
+            (offset, opacity) => Some(Shadow {
+                offset: offset.unwrap_or(DEFAULT_SHADOW_OFFSET),
+                opacity: opacity.unwrap_or(DEFAULT_SHADOW_OPACITY),
+            }),
+        };
+        let tile_style = if query.grout_width.is_some() || query.grout_color.is_some()
+            || query.corner_radius.is_some() || shadow.is_some()
+        {
+            let grout_color = match query.grout_color {
+                Some(s) => parse_hex_color(s.as_str())
+                    .ok_or_else(|| format!("Invalid grout color : {}", s))?,
+                None => DEFAULT_GROUT_COLOR,
+            };
+            Some(TileStyle {
+                grout_width: query.grout_width.unwrap_or(DEFAULT_GROUT_WIDTH),
+                grout_color: grout_color,
+                corner_radius: query.corner_radius.unwrap_or(0),
+                shadow: shadow,
+            })
+        } else {
+            None
+        };
+        let overlay = match (query.opacity, query.blend) {
+            (None, None) => None,
+            (opacity, blend) => {
+                let mode = match blend {
+                    Some(s) => BlendMode::from_str(s.as_str())?,
+                    None => BlendMode::Normal,
+                };
+                Some(Overlay::new(opacity.unwrap_or(DEFAULT_OVERLAY_OPACITY), mode))
+            }
+        };
+        Ok(RenderOption {
+            tile_style: tile_style,
+            overlay: overlay,
+        })
+    }
+}
+
+const DEFAULT_OVERLAY_OPACITY: f64 = 0.2;
+const DEFAULT_GROUT_WIDTH: u32 = 2;
+const DEFAULT_GROUT_COLOR: Rgba<u8> = Rgba {
+    data: [255, 255, 255, 255],
+};
+const DEFAULT_SHADOW_OFFSET: u32 = 1;
+const DEFAULT_SHADOW_OPACITY: f64 = 0.4;
+
+fn render<S, SS>(art: &MosaicArt<S, SS>, option: &RenderOption) -> Vec<u8>
+where
+    S: Size,
+    SS: Size,
+{
+    let styled = option
+        .tile_style
+        .map(|style| SizedImage::<S>::new(style.apply::<S, SS>(&art.image)).unwrap());
+    let image = styled.as_ref().unwrap_or(&art.image);
+    match option.overlay {
+        Some(ref overlay) => overlay.apply(image, &art.origin).to_png_bytes(),
+        None => image.to_png_bytes(),
+    }
+}
+
+fn construct_response<S, SS>(
+    art: Arc<MosaicArt<S, SS>>,
+    option: &RenderOption,
+) -> MosaicArtResponse
 where
     S: Size,
     SS: Size,
 {
     let mosaic_art = {
-        let png_img = art.image.to_png_bytes();
+        let png_img = render(&art, option);
         ::base64::encode(png_img.as_slice())
     };
     let piece_posts = art.posts
@@ -78,10 +222,13 @@ pub struct MosaicArtResponseInner {
 pub enum PostResponse {
     BluummPost(BluummPostResponse),
     InstaPost(InstaPostResponse),
+    LocalPost(LocalPostResponse),
+    MastodonPost(MastodonPostResponse),
+    ExternalPost(ExternalPostResponse),
 }
 
 impl PostResponse {
-    fn from<SS: Size>(post: &GenericPost<SS>) -> PostResponse {
+    pub fn from<SS: Size>(post: &GenericPost<SS>) -> PostResponse {
         match post {
             &GenericPost::BluummPost(ref post) => {
                 PostResponse::BluummPost(BluummPostResponse::from(post))
@@ -89,26 +236,47 @@ impl PostResponse {
             &GenericPost::InstaPost(ref post) => {
                 PostResponse::InstaPost(InstaPostResponse::from(post))
             }
+            &GenericPost::LocalPost(ref post) => {
+                PostResponse::LocalPost(LocalPostResponse::from(post))
+            }
+            &GenericPost::MastodonPost(ref post) => {
+                PostResponse::MastodonPost(MastodonPostResponse::from(post))
+            }
+            &GenericPost::ExternalPost(ref post) => {
+                PostResponse::ExternalPost(ExternalPostResponse::from(post))
+            }
         }
     }
 }
 
+// Fields which posts of every kind have.
 #[derive(Serialize)]
-pub struct BluummPostResponse {
-    image: String,
+pub struct PostFields {
+    image: String, // base64 encoded
     user_name: String,
     hashtag: Hashtag,
 }
 
+impl PostFields {
+    fn from<P: Post>(post: &P) -> PostFields {
+        PostFields {
+            image: ::base64::encode(post.image().to_png_bytes().as_slice()),
+            user_name: post.user_name().into(),
+            hashtag: post.hashtag().clone(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct BluummPostResponse {
+    #[serde(flatten)]
+    fields: PostFields,
+}
+
 impl BluummPostResponse {
     fn from<SS: Size>(post: &BluummPost<SS>) -> BluummPostResponse {
-        let image = ::base64::encode(post.image().to_png_bytes().as_slice());
-        let user_name = post.user_name().into();
-        let hashtag = post.hashtag().clone();
         BluummPostResponse {
-            image: image,
-            user_name: user_name,
-            hashtag: hashtag,
+            fields: PostFields::from(post),
         }
     }
 }
@@ -116,21 +284,75 @@ impl BluummPostResponse {
 #[derive(Serialize)]
 pub struct InstaPostResponse {
     post_id: InstaPostId,
-    image: String,
-    user_name: String,
-    hashtag: Hashtag,
+    shortcode: String,
+    #[serde(flatten)]
+    fields: PostFields,
+    // Every hashtag on the post including the caption.
+    hashtags: Vec<Hashtag>,
+    is_video: bool,
 }
 
 impl InstaPostResponse {
     fn from<SS: Size>(post: &InstaPost<SS>) -> InstaPostResponse {
-        let image = ::base64::encode(post.image().to_png_bytes().as_slice());
-        let user_name = post.user_name().into();
-        let hashtag = post.hashtag().clone();
         InstaPostResponse {
             post_id: post.post_id.clone(),
-            image: image,
-            user_name: user_name,
-            hashtag: hashtag,
+            shortcode: post.post_id.shortcode().to_string(),
+            fields: PostFields::from(post),
+            hashtags: post.hashtags(),
+            is_video: post.is_video,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct LocalPostResponse {
+    post_id: LocalPostId,
+    #[serde(flatten)]
+    fields: PostFields,
+}
+
+impl LocalPostResponse {
+    fn from<SS: Size>(post: &LocalPost<SS>) -> LocalPostResponse {
+        LocalPostResponse {
+            post_id: post.post_id.clone(),
+            fields: PostFields::from(post),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct MastodonPostResponse {
+    post_id: MastodonPostId,
+    status_url: String,
+    #[serde(flatten)]
+    fields: PostFields,
+    // Every hashtag on the status.
+    hashtags: Vec<Hashtag>,
+}
+
+impl MastodonPostResponse {
+    fn from<SS: Size>(post: &MastodonPost<SS>) -> MastodonPostResponse {
+        MastodonPostResponse {
+            post_id: post.post_id.clone(),
+            status_url: post.status_url.clone(),
+            fields: PostFields::from(post),
+            hashtags: post.hashtags(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ExternalPostResponse {
+    post_id: ExternalPostId,
+    #[serde(flatten)]
+    fields: PostFields,
+}
+
+impl ExternalPostResponse {
+    fn from<SS: Size>(post: &ExternalPost<SS>) -> ExternalPostResponse {
+        ExternalPostResponse {
+            post_id: post.post_id.clone(),
+            fields: PostFields::from(post),
         }
     }
 }
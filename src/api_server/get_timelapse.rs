@@ -0,0 +1,50 @@
+use std::{sync::Mutex, time::Duration};
+use rocket::{State, http::{ContentType, Status}, response::{Failure, content::Content}};
+
+use worker::{WorkerId, WorkerManager};
+use super::{OriginImageSize, PieceImageSize};
+
+const FRAME_DELAY_MILLIS: u64 = 200;
+
+// =================================
+// get timelapse API
+// =================================
+// `format` is "gif" or "zip" of PNG frames. APNG is not supported
+// because neither image nor gif crate can encode it.
+// Responds 404 until the first frame is recorded.
+
+#[get("/worker/<id>/timelapse/<format>")]
+fn handler(
+    id: u64,
+    format: String,
+    worker_manager: State<Mutex<WorkerManager<OriginImageSize, PieceImageSize>>>,
+) -> Result<Content<Vec<u8>>, Failure> {
+    let timelapse = match worker_manager
+        .inner()
+        .lock()
+        .unwrap()
+        .get_worker(WorkerId::from_raw(id))
+    {
+        Some(worker) => worker.get_timelapse().ok_or(Failure(Status::NotFound))?,
+        None => return Err(Failure(Status::NotFound)),
+    };
+
+    // Do not block the worker while encoding.
+    let timelapse = timelapse.lock().unwrap().clone();
+    if timelapse.len() == 0 {
+        return Err(Failure(Status::NotFound));
+    }
+    let res = match format.as_str() {
+        "gif" => timelapse
+            .to_gif(Duration::from_millis(FRAME_DELAY_MILLIS))
+            .map(|bytes| Content(ContentType::GIF, bytes)),
+        "zip" => timelapse
+            .to_zip()
+            .map(|bytes| Content(ContentType::new("application", "zip"), bytes)),
+        _ => return Err(Failure(Status::BadRequest)),
+    };
+    res.map_err(|e| {
+        error!("Fail to encode timelapse : {:?}", e);
+        Failure(Status::InternalServerError)
+    })
+}
@@ -0,0 +1,83 @@
+use std::{io::Read, sync::Mutex};
+use rocket::{Data, Outcome, State, http::Status,
+             request::{self, FromRequest, Request},
+             response::{Failure, status::Accepted}};
+
+use worker::WorkerManager;
+use error::ErrorKind;
+use super::{OriginImageSize, PieceImageSize};
+
+const SIGNATURE_HEADER: &str = "X-Bluumm-Signature";
+const TIMESTAMP_HEADER: &str = "X-Bluumm-Timestamp";
+// Base64 encoded image may be included.
+const MAX_BODY_BYTES: u64 = 16 * 1024 * 1024;
+
+// =================================
+// ingest API
+// =================================
+
+/// Receives a post pushed by a third-party provider.
+/// "<timestamp>.<body>" must be signed by HMAC-SHA256 with the shared secret,
+/// where timestamp is unix time in seconds sent in `X-Bluumm-Timestamp`.
+/// A request is rejected if it was signed more than 5 minutes ago or has been accepted before.
+#[post("/ingest", format = "application/json", data = "<data>")]
+fn handler(
+    signature: Signature,
+    data: Data,
+    worker_manager: State<Mutex<WorkerManager<OriginImageSize, PieceImageSize>>>,
+) -> Result<Accepted<&'static str>, Failure> {
+    // Lock is released before reading body.
+    let webhook = worker_manager
+        .inner()
+        .lock()
+        .unwrap()
+        .webhook()
+        .ok_or(Failure(Status::NotFound))?;
+
+    // One more byte is read to detect oversized body.
+    let mut body = Vec::new();
+    data.open()
+        .take(MAX_BODY_BYTES + 1)
+        .read_to_end(&mut body)
+        .map_err(|_| Failure(Status::BadRequest))?;
+    if body.len() as u64 > MAX_BODY_BYTES {
+        return Err(Failure(Status::PayloadTooLarge));
+    }
+
+    match webhook.ingest(
+        body.as_slice(),
+        signature.timestamp.as_str(),
+        signature.signature.as_str(),
+    ) {
+        Ok(()) => Ok(Accepted(Some("Accepted"))),
+        Err(e) => {
+            warn!("Reject ingested post : {}", e);
+            match e.kind() {
+                &ErrorKind::InvalidSignature => Err(Failure(Status::Unauthorized)),
+                &ErrorKind::IngestReplayed(_) => Err(Failure(Status::Unauthorized)),
+                &ErrorKind::IngestUnavailable => Err(Failure(Status::ServiceUnavailable)),
+                _ => Err(Failure(Status::BadRequest)),
+            }
+        }
+    }
+}
+
+struct Signature {
+    timestamp: String,
+    signature: String,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Signature {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Signature, ()> {
+        let headers = request.headers();
+        match (headers.get_one(TIMESTAMP_HEADER), headers.get_one(SIGNATURE_HEADER)) {
+            (Some(timestamp), Some(signature)) => Outcome::Success(Signature {
+                timestamp: timestamp.to_string(),
+                signature: signature.to_string(),
+            }),
+            _ => Outcome::Failure((Status::Unauthorized, ())),
+        }
+    }
+}
@@ -1,32 +1,49 @@
+mod admin;
 mod start_worker;
 mod stop_worker;
 mod get_art;
 mod add_post;
+mod get_timelapse;
+mod ingest;
+mod moderation;
+mod takedown;
 
 use std::sync::Mutex;
 use worker::WorkerManager;
 use db::Mongodb;
+use config::Config;
 use images::size::{Size3000x3000, Size30x30};
 use util::IdHashMap;
-use self::get_art::MosaicArtResponse;
+use self::get_art::{MosaicArtResponse, RenderedArts};
+use self::admin::AdminToken;
 
 type OriginImageSize = Size3000x3000;
 type PieceImageSize = Size30x30;
 
-pub fn run(mongodb: Mongodb) {
+pub fn run(mongodb: Mongodb, config: Config) {
     let cors = ::rocket_cors::Cors::default();
     ::rocket::ignite()
         .manage(Mutex::new(
-            WorkerManager::<OriginImageSize, PieceImageSize>::new(mongodb),
+            WorkerManager::<OriginImageSize, PieceImageSize>::new(mongodb, &config),
         ))
         .manage(Mutex::new(IdHashMap::<MosaicArtResponse>::new()))
+        .manage(Mutex::new(IdHashMap::<RenderedArts>::new()))
+        .manage(AdminToken(config.admin_token.clone()))
         .mount(
             "/",
             routes![
                 start_worker::handler,
                 get_art::handler,
+                get_art::handler_with_render_option,
                 stop_worker::handler,
                 add_post::handler,
+                get_timelapse::handler,
+                ingest::handler,
+                moderation::list_handler,
+                moderation::approve_handler,
+                moderation::reject_handler,
+                takedown::insta_post_handler,
+                takedown::user_handler,
             ],
         )
         .attach(cors)
@@ -0,0 +1,92 @@
+use std::sync::Mutex;
+use rocket::{State, response::status::NotFound};
+use rocket_contrib::Json;
+
+use worker::{WorkerId, WorkerManager};
+use moderation::PendingPostId;
+use super::{OriginImageSize, PieceImageSize};
+use super::get_art::PostResponse;
+use super::admin::Admin;
+
+// Max number of pending posts returned at once.
+const MAX_PENDING_POSTS: i64 = 100;
+
+// =================================
+// moderation API
+// =================================
+// Every endpoint requires the admin token.
+
+#[get("/worker/<id>/pending_posts")]
+fn list_handler(
+    _admin: Admin,
+    id: u64,
+    worker_manager: State<Mutex<WorkerManager<OriginImageSize, PieceImageSize>>>,
+) -> Result<Json<Vec<PendingPostResponse>>, NotFound<&'static str>> {
+    match worker_manager
+        .inner()
+        .lock()
+        .unwrap()
+        .get_worker(WorkerId::from_raw(id))
+    {
+        Some(worker) => {
+            let res = worker
+                .pending_posts(MAX_PENDING_POSTS)
+                .iter()
+                .map(|p| PendingPostResponse {
+                    pending_post_id: p.id.clone(),
+                    post: PostResponse::from(&p.post),
+                })
+                .collect();
+            Ok(Json(res))
+        }
+        None => Err(NotFound("Worker is not found")),
+    }
+}
+
+#[post("/worker/<id>/pending_posts/<pending_post_id>/approve")]
+fn approve_handler(
+    _admin: Admin,
+    id: u64,
+    pending_post_id: String,
+    worker_manager: State<Mutex<WorkerManager<OriginImageSize, PieceImageSize>>>,
+) -> Result<&'static str, NotFound<&'static str>> {
+    match worker_manager
+        .inner()
+        .lock()
+        .unwrap()
+        .get_worker(WorkerId::from_raw(id))
+    {
+        Some(worker) => match worker.approve_post(&PendingPostId(pending_post_id)) {
+            true => Ok("Post has been approved"),
+            false => Err(NotFound("Pending post is not found")),
+        },
+        None => Err(NotFound("Worker is not found")),
+    }
+}
+
+#[post("/worker/<id>/pending_posts/<pending_post_id>/reject")]
+fn reject_handler(
+    _admin: Admin,
+    id: u64,
+    pending_post_id: String,
+    worker_manager: State<Mutex<WorkerManager<OriginImageSize, PieceImageSize>>>,
+) -> Result<&'static str, NotFound<&'static str>> {
+    match worker_manager
+        .inner()
+        .lock()
+        .unwrap()
+        .get_worker(WorkerId::from_raw(id))
+    {
+        Some(worker) => match worker.reject_post(&PendingPostId(pending_post_id)) {
+            true => Ok("Post has been rejected"),
+            false => Err(NotFound("Pending post is not found")),
+        },
+        None => Err(NotFound("Worker is not found")),
+    }
+}
+
+#[derive(Serialize)]
+struct PendingPostResponse {
+    pending_post_id: PendingPostId,
+    post: PostResponse,
+}
@@ -1,15 +1,20 @@
-use std::sync::Mutex;
+use std::{sync::Mutex, time::Duration};
 use rocket::{State, response::status::{BadRequest, Created}};
 use rocket_contrib::Json;
 
 use images::{Image, MultipleOf, Size, SizedImage, SmallerThan, size::{Size3000x3000, Size30x30}};
-use worker::{WorkerId, WorkerManager};
+use worker::{WorkerId, WorkerManager, WorkerOption};
+use mosaic::TimelapseOption;
+use moderation::ModerationOption;
 use post::HashtagList;
-use error::Error;
+use query::{HashtagQuery, RawHashtagQuery};
+use error::{Error, ErrorKind};
 use super::{OriginImageSize, PieceImageSize};
 
 const HOST: &str = "";
 
+const DEFAULT_TIMELAPSE_FRAME_WIDTH: u32 = 300;
+
 // =================================
 // start worker API
 // =================================
@@ -18,12 +23,13 @@ const HOST: &str = "";
 fn handler(
     json: Json<RawStartWorkerOption>,
     worker_manager: State<Mutex<WorkerManager<OriginImageSize, PieceImageSize>>>,
-) -> Result<Created<String>, BadRequest<()>> {
-    let option = StartWorkerOption::from(json.into_inner()).map_err(|_| BadRequest(None))?;
+) -> Result<Created<String>, BadRequest<String>> {
+    let option = StartWorkerOption::from(json.into_inner())
+        .map_err(|e| BadRequest(Some(e.to_string())))?;
 
     debug!(
-        "Accept start_worker request. hashtags = {:?}",
-        option.hashtags
+        "Accept start_worker request. query = {:?}",
+        option.query
     );
 
     let origin_size = (option.origin.width(), option.origin.height());
@@ -32,40 +38,47 @@ fn handler(
         /*
         ((1500, 1500), Some((30, 30))) => start_worker::<Size1500x1500, Size30x30>(
             SizedImage::new(option.origin).unwrap(),
-            option.hashtags,
+            option.query,
+            option.worker_option,
             worker_manager,
         ),
         ((1500, 1500), Some((50, 50))) => start_worker::<Size1500x1500, Size50x50>(
             SizedImage::new(option.origin).unwrap(),
-            option.hashtags,
+            option.query,
+            option.worker_option,
             worker_manager,
         ),
         ((1500, 1500), None) => start_worker::<Size1500x1500, Size30x30>(
             SizedImage::new(option.origin).unwrap(),
-            option.hashtags,
+            option.query,
+            option.worker_option,
             worker_manager,
         ),
         */
         ((3000, 3000), Some((30, 30))) => start_worker::<Size3000x3000, Size30x30>(
             SizedImage::new(option.origin).unwrap(),
-            option.hashtags,
+            option.query,
+            option.worker_option,
             worker_manager,
         ),
         /*
         ((3000, 3000), Some((50, 50))) => start_worker::<Size3000x3000, Size50x50>(
             SizedImage::new(option.origin).unwrap(),
-            option.hashtags,
+            option.query,
+            option.worker_option,
             worker_manager,
         ),
         ((3000, 3000), Some((100, 100))) => start_worker::<Size3000x3000, Size100x100>(
             SizedImage::new(option.origin).unwrap(),
-            option.hashtags,
+            option.query,
+            option.worker_option,
             worker_manager,
         ),
         */
         ((3000, 3000), None) => start_worker::<Size3000x3000, Size30x30>(
             SizedImage::new(option.origin).unwrap(),
-            option.hashtags,
+            option.query,
+            option.worker_option,
             worker_manager,
         ),
         _ => return Err(BadRequest(None)),
@@ -77,7 +90,8 @@ fn handler(
 
 fn start_worker<S, SS>(
     origin: SizedImage<S>,
-    hashtags: Vec<String>,
+    query: HashtagQuery,
+    worker_option: WorkerOption,
     worker_manager: State<Mutex<WorkerManager<S, SS>>>,
 ) -> WorkerId
 where
@@ -88,7 +102,7 @@ where
         .inner()
         .lock()
         .unwrap()
-        .start_worker(origin, HashtagList::new(hashtags));
+        .start_worker(origin, query, worker_option);
     info!("Run a new worker");
 
     id
@@ -97,22 +111,76 @@ where
 #[derive(Deserialize)]
 struct RawStartWorkerOption {
     origin: String, // base64 encoded
+    // Posts having any of hashtags are used.
+    #[serde(default)]
     hashtags: Vec<String>,
+    // Posts matching the query are used. Combined with `hashtags` by AND if both are given.
+    query: Option<RawHashtagQuery>,
     piece_size: Option<(u32, u32)>,
+    timelapse: Option<RawTimelapseOption>,
+    // Thumbnails of videos are used by default.
+    include_videos: Option<bool>,
+    // Posts are used without review if not given.
+    moderation: Option<RawModerationOption>,
+    // Reposts are skipped unless false.
+    dedupe: Option<bool>,
+    // Max Hamming distance of perceptual hashes between duplicates.
+    duplicate_distance: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct RawModerationOption {
+    trusted_users: Option<Vec<String>>,
+}
+
+#[derive(Deserialize)]
+struct RawTimelapseOption {
+    interval_sec: u64,
+    frame_width: Option<u32>,
 }
 
 struct StartWorkerOption {
     origin: Image,
-    hashtags: Vec<String>,
+    query: HashtagQuery,
     piece_size: Option<(u32, u32)>,
+    worker_option: WorkerOption,
 }
 
 impl StartWorkerOption {
     fn from(raw: RawStartWorkerOption) -> Result<StartWorkerOption, Error> {
+        let default = WorkerOption::default();
+        let timelapse = raw.timelapse.map(|t| TimelapseOption {
+            interval: Duration::from_secs(t.interval_sec),
+            frame_width: t.frame_width.unwrap_or(DEFAULT_TIMELAPSE_FRAME_WIDTH),
+        });
+        if let Some(ref timelapse) = timelapse {
+            timelapse.validate()?;
+        }
+        let hashtags = HashtagList::new(raw.hashtags)?;
+        let query = match (hashtags.len(), raw.query) {
+            (0, None) => bail!(ErrorKind::InvalidHashtagQuery("no hashtag is given".into())),
+            (0, Some(query)) => HashtagQuery::from_raw(query)?,
+            (_, None) => HashtagQuery::any_of(&hashtags),
+            (_, Some(query)) => HashtagQuery::And(vec![
+                HashtagQuery::any_of(&hashtags),
+                HashtagQuery::from_raw(query)?,
+            ]),
+        };
         Ok(StartWorkerOption {
             origin: encode_image(raw.origin.as_str())?,
-            hashtags: raw.hashtags,
+            query: query,
             piece_size: raw.piece_size,
+            worker_option: WorkerOption {
+                timelapse: timelapse,
+                include_videos: raw.include_videos.unwrap_or(true),
+                moderation: raw.moderation.map(|m| ModerationOption {
+                    trusted_users: m.trusted_users.unwrap_or(Vec::new()),
+                }),
+                duplicate_distance: match raw.dedupe {
+                    Some(false) => None,
+                    _ => raw.duplicate_distance.or(default.duplicate_distance),
+                },
+            },
         })
     }
 }
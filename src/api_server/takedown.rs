@@ -0,0 +1,41 @@
+use std::sync::Mutex;
+use rocket::State;
+
+use worker::WorkerManager;
+use post::InstaPostId;
+use takedown::Takedown;
+use super::{OriginImageSize, PieceImageSize};
+use super::admin::Admin;
+
+// =================================
+// takedown API
+// =================================
+// Every endpoint requires the admin token.
+
+#[delete("/insta_post/<post_id>")]
+fn insta_post_handler(
+    _admin: Admin,
+    post_id: String,
+    worker_manager: State<Mutex<WorkerManager<OriginImageSize, PieceImageSize>>>,
+) -> &'static str {
+    worker_manager
+        .inner()
+        .lock()
+        .unwrap()
+        .take_down(Takedown::InstaPost(InstaPostId(post_id)));
+    "Post has been taken down"
+}
+
+#[delete("/user/<user_name>")]
+fn user_handler(
+    _admin: Admin,
+    user_name: String,
+    worker_manager: State<Mutex<WorkerManager<OriginImageSize, PieceImageSize>>>,
+) -> &'static str {
+    worker_manager
+        .inner()
+        .lock()
+        .unwrap()
+        .take_down(Takedown::User(user_name));
+    "Posts of the user have been taken down"
+}
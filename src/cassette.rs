@@ -0,0 +1,157 @@
+use std::{fs, path::PathBuf, str::FromStr};
+
+use util::{redact_url, sha256_hex};
+use error::{Error, ErrorKind};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CassetteMode {
+    // Stores every response fetched from network.
+    Record,
+    // Serves stored responses without network.
+    Replay,
+}
+
+impl FromStr for CassetteMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<CassetteMode, String> {
+        match s {
+            "record" => Ok(CassetteMode::Record),
+            "replay" => Ok(CassetteMode::Replay),
+            s => Err(format!("Unknown cassette mode : {}", s)),
+        }
+    }
+}
+
+/// Record / replay layer of HTTP responses.
+///
+/// Each response is stored in `<dir>/<sha256 of url>.json` so that
+/// fixtures recorded in production can be replayed deterministically.
+/// Secrets in urls such as `access_token` are redacted before hashing and storing
+/// so that fixtures can be committed.
+#[derive(Debug, Clone)]
+pub struct Cassette {
+    pub mode: CassetteMode,
+    pub dir: PathBuf,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Recording {
+    pub url: String,
+    pub status: u16,
+    pub content_type: Option<String>,
+    // Base64 encoded response body.
+    body: String,
+}
+
+impl Recording {
+    pub fn body(&self) -> Result<Vec<u8>, Error> {
+        Ok(::base64::decode(self.body.as_str())?)
+    }
+}
+
+impl Cassette {
+    pub fn new(mode: CassetteMode, dir: PathBuf) -> Cassette {
+        Cassette {
+            mode: mode,
+            dir: dir,
+        }
+    }
+
+    pub fn is_replay(&self) -> bool {
+        self.mode == CassetteMode::Replay
+    }
+
+    pub fn is_record(&self) -> bool {
+        self.mode == CassetteMode::Record
+    }
+
+    pub fn load(&self, url: &str) -> Result<Recording, Error> {
+        let url = redact_url(url);
+        let path = self.path(url.as_str());
+        if !path.exists() {
+            bail!(ErrorKind::CassetteMiss(url));
+        }
+        let bytes = fs::read(path)?;
+        Ok(::serde_json::from_slice(bytes.as_slice())?)
+    }
+
+    // Failure of recording should not stop fetching so it is just logged.
+    pub fn record(&self, url: &str, status: u16, content_type: Option<String>, body: &[u8]) {
+        let url = redact_url(url);
+        let url = url.as_str();
+        let recording = Recording {
+            url: url.into(),
+            status: status,
+            content_type: content_type,
+            body: ::base64::encode(body),
+        };
+        let res = fs::create_dir_all(&self.dir)
+            .map_err(Error::from)
+            .and_then(|_| Ok(::serde_json::to_vec_pretty(&recording)?))
+            .and_then(|bytes| Ok(fs::write(self.path(url), bytes)?));
+        match res {
+            Ok(()) => debug!("Record response of {}", url),
+            Err(e) => warn!("Fail to record response of {} : {:?}", url, e),
+        }
+    }
+
+    fn path(&self, url: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", sha256_hex(url.as_bytes())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn cassette(mode: CassetteMode, name: &str) -> Cassette {
+        let dir = env::temp_dir().join(format!("bluumm-cassette-{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        Cassette::new(mode, dir)
+    }
+
+    #[test]
+    fn replay_recorded_response() {
+        let recorder = cassette(CassetteMode::Record, "replay");
+        recorder.record("https://example.com/a", 200, Some("image/png".into()), b"abc");
+
+        let player = Cassette::new(CassetteMode::Replay, recorder.dir.clone());
+        let recording = player.load("https://example.com/a").unwrap();
+        assert_eq!(recording.status, 200);
+        assert_eq!(recording.content_type, Some("image/png".to_string()));
+        assert_eq!(recording.body().unwrap(), b"abc".to_vec());
+    }
+
+    #[test]
+    fn redact_access_token() {
+        let url = "https://graph.example.com/h/recent_media?user_id=1&access_token=secret&after=a";
+        let recorder = cassette(CassetteMode::Record, "redact");
+        recorder.record(url, 200, Some("application/json".into()), b"{}");
+
+        let files: Vec<_> = fs::read_dir(&recorder.dir).unwrap().collect();
+        assert_eq!(files.len(), 1);
+        let stored = fs::read_to_string(files[0].as_ref().unwrap().path()).unwrap();
+        assert!(!stored.contains("secret"));
+
+        // Recorded with another token is replayed.
+        let player = Cassette::new(CassetteMode::Replay, recorder.dir.clone());
+        let recording = player
+            .load("https://graph.example.com/h/recent_media?user_id=1&access_token=new&after=a")
+            .unwrap();
+        assert_eq!(
+            recording.url,
+            "https://graph.example.com/h/recent_media?user_id=1&access_token=REDACTED&after=a"
+        );
+    }
+
+    #[test]
+    fn miss_unrecorded_url() {
+        let player = cassette(CassetteMode::Replay, "miss");
+        match player.load("https://example.com/b") {
+            Err(Error(ErrorKind::CassetteMiss(url), _)) => assert_eq!(url, "https://example.com/b"),
+            res => panic!("Unexpected result : {:?}", res),
+        }
+    }
+}
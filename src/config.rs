@@ -0,0 +1,216 @@
+use std::{env, fmt::Debug, path::PathBuf, str::FromStr, time::Duration};
+
+use images::{ImageCacheConfig, ImageFetcherConfig, QualityConfig};
+use insta::{graph, GraphApiConfig, InstaApiConfig, InstaBackend, InstaFeederConfig,
+            RateLimitConfig};
+use source::{LocalDirConfig, MastodonConfig, WebhookConfig};
+use cassette::{Cassette, CassetteMode};
+
+const DEFAULT_IMAGE_CACHE_MAX_BYTES: u64 = 1024 * 1024 * 1024; // 1 GiB
+const DEFAULT_LOCAL_POST_POLL_SEC: u64 = 5;
+const DEFAULT_MASTODON_POLL_SEC: u64 = 30;
+const DEFAULT_MASTODON_BACKFILL_PAGES: usize = 5;
+const DEFAULT_HTTP_CASSETTE_DIR: &str = "fixtures/cassettes";
+const DEFAULT_INSTA_GRAPH_BASE_URL: &str = "https://graph.facebook.com/v3.1";
+
+/// Process wide configuration which is read from env vars.
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    pub image_cache: Option<ImageCacheConfig>,
+    pub image_fetcher: ImageFetcherConfig,
+    pub insta_feeder: InstaFeederConfig,
+    // Applied to images of Instagram posts and posts added through API.
+    pub quality: QualityConfig,
+    pub insta_api: InstaApiConfig,
+    pub local_dir: Option<LocalDirConfig>,
+    pub mastodon: Option<MastodonConfig>,
+    // `POST /ingest` is enabled only if secret is configured.
+    pub webhook: Option<WebhookConfig>,
+    // Admin APIs such as moderation are enabled only if token is configured.
+    pub admin_token: Option<String>,
+}
+
+impl Config {
+    pub fn from_env() -> Config {
+        // Shared by Instagram API client and image fetcher.
+        let cassette = get_env_opt("HTTP_CASSETTE_MODE").map(|mode| {
+            let mode = mode.parse::<CassetteMode>()
+                .expect(format!("{} is not valid value for HTTP_CASSETTE_MODE", mode).as_str());
+            let dir = get_env_opt("HTTP_CASSETTE_DIR")
+                .unwrap_or(DEFAULT_HTTP_CASSETTE_DIR.to_string());
+            Cassette::new(mode, PathBuf::from(dir))
+        });
+        let image_cache = get_env_opt("IMAGE_CACHE_DIR").map(|dir| ImageCacheConfig {
+            dir: PathBuf::from(dir),
+            max_bytes: get_env_parse_or("IMAGE_CACHE_MAX_BYTES", DEFAULT_IMAGE_CACHE_MAX_BYTES),
+        });
+        let image_fetcher = {
+            let default = ImageFetcherConfig::default();
+            ImageFetcherConfig {
+                connect_timeout: get_env_millis_or(
+                    "IMAGE_FETCH_CONNECT_TIMEOUT_MS",
+                    default.connect_timeout,
+                ),
+                read_timeout: get_env_millis_or("IMAGE_FETCH_READ_TIMEOUT_MS", default.read_timeout),
+                max_retries: get_env_parse_or("IMAGE_FETCH_MAX_RETRIES", default.max_retries),
+                retry_backoff: get_env_millis_or(
+                    "IMAGE_FETCH_RETRY_BACKOFF_MS",
+                    default.retry_backoff,
+                ),
+                max_body_bytes: get_env_parse_or(
+                    "IMAGE_FETCH_MAX_BODY_BYTES",
+                    default.max_body_bytes,
+                ),
+                max_redirects: get_env_parse_or("IMAGE_FETCH_MAX_REDIRECTS", default.max_redirects),
+                dns_threads: get_env_parse_or("IMAGE_FETCH_DNS_THREADS", default.dns_threads),
+                max_connections_per_host: get_env_parse_or(
+                    "IMAGE_FETCH_MAX_CONNECTIONS_PER_HOST",
+                    default.max_connections_per_host,
+                ),
+                cassette: cassette.clone(),
+                allow_file_uri: get_env_parse_or(
+                    "IMAGE_FETCH_ALLOW_FILE_URI",
+                    default.allow_file_uri,
+                ),
+            }
+        };
+        let insta_feeder = {
+            let default = InstaFeederConfig::default();
+            InstaFeederConfig {
+                metadata_concurrency: get_env_parse_or(
+                    "INSTA_METADATA_CONCURRENCY",
+                    default.metadata_concurrency,
+                ).max(1),
+                download_concurrency: get_env_parse_or(
+                    "IMAGE_DOWNLOAD_CONCURRENCY",
+                    default.download_concurrency,
+                ).max(1),
+            }
+        };
+        let quality = {
+            let default = QualityConfig::default();
+            QualityConfig {
+                min_width: get_env_parse_or("QUALITY_MIN_WIDTH", default.min_width),
+                min_height: get_env_parse_or("QUALITY_MIN_HEIGHT", default.min_height),
+                min_luminance_variance: get_env_parse_or(
+                    "QUALITY_MIN_LUMINANCE_VARIANCE",
+                    default.min_luminance_variance,
+                ),
+                max_single_color_fraction: get_env_parse_or(
+                    "QUALITY_MAX_SINGLE_COLOR_FRACTION",
+                    default.max_single_color_fraction,
+                ),
+            }
+        };
+        let insta_api = {
+            let default = InstaApiConfig::default();
+            let backend = match get_env_opt("INSTA_BACKEND").as_ref().map(|s| s.as_str()) {
+                None | Some("web") => InstaBackend::Web,
+                Some("graph") => InstaBackend::Graph(GraphApiConfig {
+                    base_url: get_env_opt("INSTA_GRAPH_BASE_URL")
+                        .map(|url| url.trim_right_matches('/').to_string())
+                        .unwrap_or(DEFAULT_INSTA_GRAPH_BASE_URL.to_string()),
+                    access_token: get_env_opt("INSTA_GRAPH_TOKEN")
+                        .expect("INSTA_GRAPH_TOKEN is required for graph backend"),
+                    user_id: get_env_opt("INSTA_GRAPH_USER_ID")
+                        .expect("INSTA_GRAPH_USER_ID is required for graph backend"),
+                }),
+                Some(s) => panic!("{} is not valid value for INSTA_BACKEND", s),
+            };
+            let rate_limit = {
+                let default = match backend {
+                    InstaBackend::Graph(_) => RateLimitConfig {
+                        rate: graph::DEFAULT_RATE,
+                        ..default.rate_limit.clone()
+                    },
+                    InstaBackend::Web => default.rate_limit.clone(),
+                };
+                // INSTA_API_INTERVAL_MS is still accepted for compatibility.
+                let interval_ms: Option<u64> = get_env_opt("INSTA_API_INTERVAL_MS")
+                    .map(|_| get_env_parse_or("INSTA_API_INTERVAL_MS", 0));
+                let rate = interval_ms
+                    .map(|ms| 1000.0 / ms.max(1) as f64)
+                    .unwrap_or(default.rate);
+                let rate: f64 = get_env_parse_or("INSTA_API_RATE", rate);
+                // Interval between requests is the inverse of the rate.
+                assert!(
+                    rate.is_finite() && rate > 0.0,
+                    "{} is not valid value for INSTA_API_RATE",
+                    rate
+                );
+                RateLimitConfig {
+                    rate: rate,
+                    burst: get_env_parse_or("INSTA_API_BURST", default.burst).max(1),
+                    cooling: get_env_millis_or("INSTA_API_COOLING_MS", default.cooling),
+                }
+            };
+            InstaApiConfig {
+                backend: backend,
+                base_url: get_env_opt("INSTA_BASE_URL")
+                    .map(|url| url.trim_right_matches('/').to_string())
+                    .unwrap_or(default.base_url),
+                user_agent: get_env_opt("INSTA_USER_AGENT"),
+                cookie: get_env_opt("INSTA_COOKIE"),
+                rate_limit: rate_limit,
+                max_retries: get_env_parse_or("INSTA_API_MAX_RETRIES", default.max_retries),
+                retry_backoff: get_env_millis_or("INSTA_API_RETRY_BACKOFF_MS", default.retry_backoff),
+                cassette: cassette,
+            }
+        };
+        let local_dir = get_env_opt("LOCAL_POST_DIR").map(|dir| LocalDirConfig {
+            dir: PathBuf::from(dir),
+            default_hashtag: get_env_opt("LOCAL_POST_HASHTAG"),
+            poll_interval: Duration::from_secs(get_env_parse_or(
+                "LOCAL_POST_POLL_SEC",
+                DEFAULT_LOCAL_POST_POLL_SEC,
+            )),
+        });
+        let mastodon = get_env_opt("MASTODON_INSTANCE_URL").map(|url| MastodonConfig {
+            instance_url: url.trim_right_matches('/').to_string(),
+            access_token: get_env_opt("MASTODON_ACCESS_TOKEN"),
+            poll_interval: Duration::from_secs(get_env_parse_or(
+                "MASTODON_POLL_SEC",
+                DEFAULT_MASTODON_POLL_SEC,
+            )),
+            backfill_pages: get_env_parse_or(
+                "MASTODON_BACKFILL_PAGES",
+                DEFAULT_MASTODON_BACKFILL_PAGES,
+            ),
+        });
+        let webhook = get_env_opt("INGEST_SECRET").map(|secret| WebhookConfig { secret: secret });
+        Config {
+            image_cache: image_cache,
+            image_fetcher: image_fetcher,
+            insta_feeder: insta_feeder,
+            quality: quality,
+            insta_api: insta_api,
+            local_dir: local_dir,
+            mastodon: mastodon,
+            webhook: webhook,
+            admin_token: get_env_opt("ADMIN_TOKEN"),
+        }
+    }
+}
+
+fn get_env_opt(key: &str) -> Option<String> {
+    env::var(key).ok()
+}
+
+fn get_env_parse_or<T>(key: &str, default: T) -> T
+where
+    T: FromStr,
+    T::Err: Debug,
+{
+    match get_env_opt(key) {
+        Some(s) => s.parse()
+            .expect(format!("{} is not valid value for {}", s, key).as_str()),
+        None => default,
+    }
+}
+
+fn get_env_millis_or(key: &str, default: Duration) -> Duration {
+    match get_env_opt(key) {
+        Some(_) => Duration::from_millis(get_env_parse_or(key, 0)),
+        None => default,
+    }
+}
@@ -1,15 +1,37 @@
 use std::{sync::Arc, time::{SystemTime, UNIX_EPOCH}};
-use mongodb::{Client, ThreadedClient, coll::{Collection, options::FindOptions},
-              db::ThreadedDatabase};
-use bson::{Bson, Document, spec::BinarySubtype};
+use mongodb::{Client, ThreadedClient, coll::{Collection, options::{FindOptions, UpdateOptions}},
+              db::{Database, ThreadedDatabase}};
+use bson::{Bson, Document, oid::ObjectId, spec::BinarySubtype};
 
-use images::{Image, Size, SizedImage};
-use post::{BluummPost, Hashtag, HashtagList, InstaPost, InstaPostId, Post};
+use images::{Image, ImageHash, Size, SizedImage};
+use post::{BluummPost, ExternalPost, ExternalPostId, GenericPost, Hashtag, HashtagList, InstaPost,
+           InstaPostId, LocalPost, LocalPostId, MastodonPost, MastodonPostId, Post};
+use moderation::{PendingPost, PendingPostId};
+use takedown::Takedown;
+
+/// Progress of reading posts of a hashtag from Instagram.
+#[derive(Debug, Clone, Default)]
+pub struct InstaCursor {
+    // Cursor of the page where backfill resumes.
+    pub backfill_cursor: Option<String>,
+    // True if backfill has read the oldest page.
+    pub backfill_done: bool,
+    // Newest post which has been read by polling.
+    pub newest_id: Option<InstaPostId>,
+}
+
+// Posts were stored in a collection for each kind before other sources were added.
+const LEGACY_POST_COLLECTIONS: &[(&str, &str)] =
+    &[("insta_post", "insta"), ("bluumm_post", "bluumm")];
 
 #[derive(Clone)]
 pub struct Mongodb {
-    insta_post: Arc<Collection>,
-    bluumm_post: Arc<Collection>,
+    // Posts of every kind distinguished by `kind` field.
+    post: Arc<Collection>,
+    insta_cursor: Arc<Collection>,
+    pending_post: Arc<Collection>,
+    blocklist: Arc<Collection>,
+    rejected_post: Arc<Collection>,
 }
 
 impl Mongodb {
@@ -20,46 +42,105 @@ impl Mongodb {
         );
         let client = Client::connect(host, port).expect("Fail to create mongodb client");
         let db = client.db(db);
+        let post = db.collection("post");
+        migrate_legacy_posts(&db, &post);
+        migrate_once(&db, "normalize_hashtags", || {
+            for name in &["post", "pending_post", "rejected_post", "insta_cursor"] {
+                normalize_stored_hashtags(&db.collection(name));
+            }
+        });
         Mongodb {
-            insta_post: Arc::new(db.collection("insta_post")),
-            bluumm_post: Arc::new(db.collection("bluumm_post")),
+            post: Arc::new(post),
+            insta_cursor: Arc::new(db.collection("insta_cursor")),
+            pending_post: Arc::new(db.collection("pending_post")),
+            blocklist: Arc::new(db.collection("blocklist")),
+            rejected_post: Arc::new(db.collection("rejected_post")),
         }
     }
 
-    pub fn insert_one_insta_post<S: Size>(&self, post: &InstaPost<S>) {
-        debug!("Insert new insta post into mongodb");
-        let doc = doc! {
-            "id": post.post_id.as_str(),
-            "username": post.user_name(),
-            "image": (BinarySubtype::Generic, post.image().to_png_bytes()),
-            "hashtag": post.hashtag().as_str(),
-            "inserted_time": SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
-        };
-        self.insta_post
+    /// Stores a post of any kind.
+    /// `image_url` is where the original image was fetched from, so that the cached one
+    /// can be evicted when the post is taken down.
+    pub fn insert_post<S: Size>(&self, post: &GenericPost<S>, image_url: Option<&str>) {
+        debug!("Insert new {} post into mongodb", post.kind());
+        let mut doc = post_2_doc(post);
+        if let Some(image_url) = image_url {
+            doc.insert("image_url", image_url);
+        }
+        self.post
             .insert_one(doc, None)
             .expect("Should delegate this error");
     }
 
-    pub fn contains_insta_post(&self, post_id: &InstaPostId) -> bool {
-        let filter = doc! { "id": post_id.as_str() };
-        self.insta_post
+    /// `kind` is the name of the source such as "insta".
+    pub fn contains_post(&self, kind: &str, post_id: &str) -> bool {
+        let filter = doc! { "kind": kind, "id": post_id };
+        self.post
             .find_one(Some(filter), None)
             .expect("Should handle this error")
             .is_some()
     }
 
-    pub fn find_insta_posts_by_hashtags<S: Size>(
+    /// Newest posts of every kind which have any of hashtags.
+    /// Posts in `exclude` given as pairs of kind and id are skipped.
+    pub fn find_posts_by_hashtags<S: Size>(
         &self,
         hashtags: &HashtagList,
+        exclude: &[(&str, &str)],
         limit: i64,
-    ) -> Vec<InstaPost<S>> {
+    ) -> Vec<GenericPost<S>> {
         debug!("Find posts by hashtags : {:?}", hashtags);
-        let hashtags_filter: Vec<Bson> = hashtags
+        self.find_posts(hashtags_filter(hashtags), exclude, limit)
+    }
+
+    /// Same as `find_posts_by_hashtags` except that posts which have not been approved
+    /// are excluded unless they are posted by `trusted_users`.
+    pub fn find_approved_posts_by_hashtags<S: Size>(
+        &self,
+        hashtags: &HashtagList,
+        trusted_users: &[String],
+        exclude: &[(&str, &str)],
+        limit: i64,
+    ) -> Vec<GenericPost<S>> {
+        debug!("Find approved posts by hashtags : {:?}", hashtags);
+        let trusted_users: Vec<Bson> = trusted_users
             .iter()
-            .map(|h| bson!(doc!{ "hashtag": h.as_str() }))
+            .map(|u| Bson::String(u.clone()))
             .collect();
+        let approved_filter = vec![
+            bson!(doc!{ "approved": true }),
+            bson!(doc!{ "username": doc!{ "$in": trusted_users } }),
+        ];
+        let conditions = vec![
+            Bson::Document(hashtags_filter(hashtags)),
+            bson!(doc!{ "$or": approved_filter }),
+        ];
         let filter = doc! {
-            "$or": hashtags_filter,
+            "$and": conditions,
+        };
+        self.find_posts(filter, exclude, limit)
+    }
+
+    fn find_posts<S: Size>(
+        &self,
+        filter: Document,
+        exclude: &[(&str, &str)],
+        limit: i64,
+    ) -> Vec<GenericPost<S>> {
+        let mut excluded: Vec<Bson> = exclude
+            .iter()
+            .map(|&(kind, id)| bson!(doc!{ "kind": kind, "id": id }))
+            .collect();
+        // Posts which were stored before they are blocked, or by a source which does not
+        // check blocklist, are never used.
+        excluded.extend(self.blocklist().iter().map(|t| Bson::Document(takedown_filter(t))));
+        let filter = if excluded.is_empty() {
+            filter
+        } else {
+            let conditions = vec![Bson::Document(filter), bson!(doc!{ "$nor": excluded })];
+            doc! {
+                "$and": conditions,
+            }
         };
         let option = {
             let mut op = FindOptions::new();
@@ -67,72 +148,648 @@ impl Mongodb {
             op.sort = Some(doc!{"inserted_time": -1});
             op
         };
-        self.insta_post
+        self.post
             .find(Some(filter), Some(option))
             .expect("Fail to execute find operation")
-            .map(|res| doc_2_insta_post(res.expect("Invalid document")))
+            .map(|res| doc_2_post(res.expect("Invalid document")))
             .collect()
     }
 
-    pub fn insert_one_bluumm_post<S: Size>(&self, post: &BluummPost<S>) {
-        debug!("Insert new bluumm post into mongodb");
-        let doc = doc! {
-            "username": post.user_name(),
-            "image": (BinarySubtype::Generic, post.image().to_png_bytes()),
-            "hashtag": post.hashtag().as_str(),
-            "inserted_time": SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+    /// Kind, id and perceptual hash of stored posts which have any of `hashtags`,
+    /// from the oldest one. Images are not read so that every stored post can be compared.
+    pub fn find_phashes_by_hashtags(
+        &self,
+        hashtags: &HashtagList,
+    ) -> Vec<(String, Option<String>, ImageHash)> {
+        let option = {
+            let mut op = FindOptions::new();
+            op.projection = Some(doc!{"kind": 1, "id": 1, "phash": 1});
+            op.sort = Some(doc!{"inserted_time": 1});
+            op
+        };
+        self.post
+            .find(Some(hashtags_filter(hashtags)), Some(option))
+            .expect("Fail to execute find operation")
+            .filter_map(|res| {
+                let doc = res.expect("Invalid document");
+                let phash = ImageHash(doc.get_i64("phash").ok()? as u64);
+                let kind = doc.get_str("kind").ok()?.to_string();
+                let id = doc.get_str("id").ok().map(|id| id.to_string());
+                Some((kind, id, phash))
+            })
+            .collect()
+    }
+
+    pub fn find_insta_cursor(&self, hashtag: &Hashtag) -> InstaCursor {
+        let filter = doc! { "hashtag": hashtag.as_str() };
+        let doc = self.insta_cursor
+            .find_one(Some(filter), None)
+            .expect("Should handle this error");
+        match doc {
+            Some(doc) => InstaCursor {
+                backfill_cursor: doc.get_str("backfill_cursor").ok().map(|s| s.to_string()),
+                backfill_done: doc.get_bool("backfill_done").unwrap_or(false),
+                newest_id: doc.get_str("newest_id").ok().map(|s| InstaPostId(s.to_string())),
+            },
+            None => InstaCursor::default(),
+        }
+    }
+
+    pub fn save_insta_backfill_cursor(&self, hashtag: &Hashtag, cursor: &str) {
+        self.update_insta_cursor(hashtag, doc! { "backfill_cursor": cursor });
+    }
+
+    pub fn finish_insta_backfill(&self, hashtag: &Hashtag) {
+        self.update_insta_cursor(hashtag, doc! { "backfill_done": true });
+    }
+
+    pub fn save_insta_newest_id(&self, hashtag: &Hashtag, post_id: &InstaPostId) {
+        self.update_insta_cursor(hashtag, doc! { "newest_id": post_id.as_str() });
+    }
+
+    fn update_insta_cursor(&self, hashtag: &Hashtag, fields: Document) {
+        debug!("Update insta cursor of {:?} : {:?}", hashtag, fields);
+        let filter = doc! { "hashtag": hashtag.as_str() };
+        let update = doc! { "$set": fields };
+        let option = {
+            let mut op = UpdateOptions::new();
+            op.upsert = Some(true);
+            op
         };
-        self.bluumm_post
+        self.insta_cursor
+            .update_one(filter, update, Some(option))
+            .expect("Should delegate this error");
+    }
+
+    /// Puts a post in the pending queue of a worker.
+    pub fn insert_pending_post<S: Size>(&self, worker_id: u64, post: &GenericPost<S>) {
+        debug!("Insert new pending post into mongodb");
+        let mut doc = post_2_doc(post);
+        doc.insert("_id", ObjectId::new().expect("Fail to generate object id"));
+        // Bson does not have unsigned integer.
+        doc.insert("worker_id", worker_id as i64);
+        self.pending_post
             .insert_one(doc, None)
             .expect("Should delegate this error");
     }
 
-    pub fn find_bluumm_posts_by_hashtags<S: Size>(
-        &self,
-        hashtags: &HashtagList,
-        limit: i64,
-    ) -> Vec<BluummPost<S>> {
-        debug!("Find posts by hashtags : {:?}", hashtags);
-        let hashtags_filter: Vec<Bson> = hashtags
-            .iter()
-            .map(|h| bson!(doc!{ "hashtag": h.as_str() }))
-            .collect();
-        let filter = doc! {
-            "$or": hashtags_filter,
-        };
+    /// Oldest pending posts of a worker.
+    pub fn find_pending_posts<S: Size>(&self, worker_id: u64, limit: i64) -> Vec<PendingPost<S>> {
+        let filter = doc! { "worker_id": worker_id as i64 };
         let option = {
             let mut op = FindOptions::new();
             op.limit = Some(limit);
-            op.sort = Some(doc!{"inserted_time": -1});
+            op.sort = Some(doc!{"inserted_time": 1});
             op
         };
-        self.bluumm_post
+        self.pending_post
             .find(Some(filter), Some(option))
             .expect("Fail to execute find operation")
-            .map(|res| doc_2_bluumm_post(res.expect("Invalid document")))
+            .map(|res| {
+                let doc = res.expect("Invalid document");
+                let id = PendingPostId(doc.get_object_id("_id").unwrap().to_hex());
+                PendingPost {
+                    id: id,
+                    post: doc_2_post(doc),
+                }
+            })
+            .collect()
+    }
+
+    /// Removes a post from the pending queue and returns it.
+    /// Returns `None` if the post is not in the pending queue of the worker.
+    pub fn take_pending_post<S: Size>(
+        &self,
+        worker_id: u64,
+        id: &PendingPostId,
+    ) -> Option<GenericPost<S>> {
+        let filter = pending_post_filter(worker_id, id)?;
+        self.pending_post
+            .find_one_and_delete(filter, None)
+            .expect("Fail to execute find_one_and_delete operation")
+            .map(doc_2_post)
+    }
+
+    /// Records that a stored post has been approved by moderation.
+    pub fn approve_post<S: Size>(&self, post: &GenericPost<S>) {
+        let id = match post.id() {
+            Some(id) => id,
+            None => return,
+        };
+        let filter = doc! { "kind": post.kind(), "id": id };
+        self.post
+            .update_one(filter, doc! { "$set": doc!{ "approved": true } }, None)
+            .expect("Fail to execute update operation");
+    }
+
+    /// Workers do not survive restart of process,
+    /// so that posts in pending queues at startup are detached until another worker takes them.
+    pub fn detach_all_pending_posts(&self) {
+        self.detach_pending_posts(doc! { "worker_id": doc! { "$exists": true } });
+    }
+
+    /// Detaches the pending queue when its worker stops.
+    pub fn detach_pending_posts_of_worker(&self, worker_id: u64) {
+        self.detach_pending_posts(doc! { "worker_id": worker_id as i64 });
+    }
+
+    // Posts added to a worker directly are deleted since they are not for other workers.
+    fn detach_pending_posts(&self, filter: Document) {
+        let mut bluumm = filter.clone();
+        bluumm.insert("kind", "bluumm");
+        self.pending_post
+            .delete_many(bluumm, None)
+            .expect("Fail to execute delete operation");
+        self.pending_post
+            .update_many(filter, doc! { "$unset": doc! { "worker_id": "" } }, None)
+            .expect("Fail to execute update operation");
+    }
+
+    /// Puts detached pending posts selected by `select` in the pending queue of a worker.
+    /// Returns the number of posts taken.
+    pub fn attach_pending_posts<S, F>(&self, worker_id: u64, select: F) -> usize
+    where
+        S: Size,
+        F: Fn(&GenericPost<S>) -> bool,
+    {
+        let detached = doc! { "worker_id": doc! { "$exists": false } };
+        let cursor = self.pending_post
+            .find(Some(detached.clone()), None)
+            .expect("Fail to execute find operation");
+        let mut taken = 0;
+        for doc in cursor {
+            let doc = doc.expect("Invalid document");
+            let mut filter = detached.clone();
+            filter.insert("_id", doc.get("_id").unwrap().clone());
+            if !select(&doc_2_post(doc)) {
+                continue;
+            }
+            let update = doc! { "$set": doc! { "worker_id": worker_id as i64 } };
+            // Another worker may have taken it.
+            let result = self.pending_post
+                .update_one(filter, update, None)
+                .expect("Fail to execute update operation");
+            taken += result.modified_count as usize;
+        }
+        taken
+    }
+
+    pub fn add_to_blocklist(&self, takedown: &Takedown) {
+        let entry = blocklist_entry(takedown);
+        let option = {
+            let mut op = UpdateOptions::new();
+            op.upsert = Some(true);
+            op
+        };
+        self.blocklist
+            .update_one(entry.clone(), doc! { "$set": entry }, Some(option))
+            .expect("Fail to execute update operation");
+    }
+
+    pub fn is_blocked_insta_post(&self, post_id: &InstaPostId) -> bool {
+        let filter = doc! { "insta_post_id": post_id.shortcode() };
+        self.blocklist
+            .find_one(Some(filter), None)
+            .expect("Should handle this error")
+            .is_some()
+    }
+
+    pub fn is_blocked_user(&self, user_name: &str) -> bool {
+        let filter = doc! { "user_name": user_name };
+        self.blocklist
+            .find_one(Some(filter), None)
+            .expect("Should handle this error")
+            .is_some()
+    }
+
+    fn blocklist(&self) -> Vec<Takedown> {
+        self.blocklist
+            .find(None, None)
+            .expect("Fail to execute find operation")
+            .filter_map(|res| doc_2_takedown(&res.expect("Invalid document")))
             .collect()
     }
+
+    pub fn is_blocked<S: Size>(&self, post: &GenericPost<S>) -> bool {
+        let blocked_post = match post {
+            &GenericPost::InstaPost(ref p) => self.is_blocked_insta_post(&p.post_id),
+            _ => false,
+        };
+        blocked_post || self.is_blocked_user(post.user_name())
+    }
+
+    /// URLs of original images of stored posts to be taken down.
+    pub fn find_image_urls(&self, takedown: &Takedown) -> Vec<String> {
+        let option = {
+            let mut op = FindOptions::new();
+            op.projection = Some(doc!{"image_url": 1});
+            op
+        };
+        self.post
+            .find(Some(takedown_filter(takedown)), Some(option))
+            .expect("Fail to execute find operation")
+            .filter_map(|res| {
+                let doc = res.expect("Invalid document");
+                doc.get_str("image_url").ok().map(|url| url.to_string())
+            })
+            .collect()
+    }
+
+    /// Deletes posts to be taken down from stored posts and pending queues.
+    pub fn delete_posts(&self, takedown: &Takedown) {
+        let filter = takedown_filter(takedown);
+        for collection in &[&self.post, &self.pending_post] {
+            collection
+                .delete_many(filter.clone(), None)
+                .expect("Fail to execute delete operation");
+        }
+    }
+
+    /// Records a post which is not ingested because its image does not pass quality gates.
+    /// `kind` is the name of the source such as "insta".
+    pub fn insert_rejected_post(
+        &self,
+        kind: &str,
+        post_id: Option<&str>,
+        user_name: &str,
+        hashtag: &Hashtag,
+        reason: &str,
+    ) {
+        debug!("Insert new rejected post into mongodb");
+        let doc = doc! {
+            "kind": kind,
+            "id": post_id.map(Bson::from).unwrap_or(Bson::Null),
+            "username": user_name,
+            "hashtag": hashtag.as_str(),
+            "reason": reason,
+            "inserted_time": SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+        };
+        self.rejected_post
+            .insert_one(doc, None)
+            .expect("Should delegate this error");
+    }
+
+    pub fn contains_rejected_post(&self, kind: &str, post_id: &str) -> bool {
+        let filter = doc! { "kind": kind, "id": post_id };
+        self.rejected_post
+            .find_one(Some(filter), None)
+            .expect("Should handle this error")
+            .is_some()
+    }
 }
 
-fn doc_2_insta_post<S: Size>(doc: Document) -> InstaPost<S> {
-    let id = InstaPostId(doc.get_str("id").unwrap().into());
-    let image = {
-        let binary = doc.get_binary_generic("image").unwrap();
-        let image = Image::from_bytes(binary).unwrap();
-        SizedImage::with_resize(image)
+/// Connects to a cleared database for tests which need MongoDB.
+/// Such tests are ignored by default and run by `cargo test -- --ignored`
+/// with MongoDB at MONGODB_HOST and MONGODB_PORT (localhost:27017 by default).
+#[cfg(test)]
+pub fn test_db(name: &str) -> Mongodb {
+    let host = ::std::env::var("MONGODB_HOST").unwrap_or("localhost".to_string());
+    let port = ::std::env::var("MONGODB_PORT")
+        .ok()
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(27017);
+    let db = format!("bluumm_test_{}", name);
+    Client::connect(host.as_str(), port)
+        .expect("Fail to create mongodb client")
+        .drop_database(db.as_str())
+        .expect("Fail to drop test database");
+    Mongodb::new(host.as_str(), port, db.as_str())
+}
+
+// A post found by another hashtag is also found by a hashtag in its caption.
+fn hashtags_filter(hashtags: &HashtagList) -> Document {
+    let hashtags_filter: Vec<Bson> = hashtags
+        .iter()
+        .flat_map(|h| {
+            vec![
+                bson!(doc!{ "hashtag": h.as_str() }),
+                bson!(doc!{ "tags": h.as_str() }),
+            ]
+        })
+        .collect();
+    doc! {
+        "$or": hashtags_filter,
+    }
+}
+
+fn takedown_filter(takedown: &Takedown) -> Document {
+    match takedown {
+        // Images of a carousel except the first one have ids prefixed by the shortcode.
+        &Takedown::InstaPost(ref id) => {
+            let prefix = format!("^{}:", id.shortcode());
+            let ids_filter = vec![
+                bson!(doc!{ "id": id.shortcode() }),
+                bson!(doc!{ "id": doc!{ "$regex": prefix } }),
+            ];
+            doc! {
+                "kind": "insta",
+                "$or": ids_filter,
+            }
+        }
+        &Takedown::User(ref user_name) => doc! { "username": user_name.as_str() },
+    }
+}
+
+fn blocklist_entry(takedown: &Takedown) -> Document {
+    match takedown {
+        &Takedown::InstaPost(ref id) => doc! { "insta_post_id": id.shortcode() },
+        &Takedown::User(ref user_name) => doc! { "user_name": user_name.as_str() },
+    }
+}
+
+fn doc_2_takedown(doc: &Document) -> Option<Takedown> {
+    if let Ok(id) = doc.get_str("insta_post_id") {
+        return Some(Takedown::InstaPost(InstaPostId(id.to_string())));
+    }
+    doc.get_str("user_name")
+        .ok()
+        .map(|user_name| Takedown::User(user_name.to_string()))
+}
+
+fn pending_post_filter(worker_id: u64, id: &PendingPostId) -> Option<Document> {
+    let oid = ObjectId::with_string(id.as_str()).ok()?;
+    Some(doc! {
+        "_id": oid,
+        "worker_id": worker_id as i64,
+    })
+}
+
+// Posts of any kind are stored in one collection with the kind of post.
+// Fields only some kinds have are added after the common fields.
+fn post_2_doc<S: Size>(post: &GenericPost<S>) -> Document {
+    let mut doc = doc! {
+        "kind": post.kind(),
+        "username": post.user_name(),
+        "image": (BinarySubtype::Generic, post.image().to_png_bytes()),
+        "hashtag": post.hashtag().as_str(),
+        "phash": post.phash().0 as i64,
+        "inserted_time": SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
     };
-    let username = doc.get_str("username").unwrap();
-    let hashtag = Hashtag::new(doc.get_str("hashtag").unwrap());
-    InstaPost::new(id, image, username, hashtag)
+    if let Some(id) = post.id() {
+        doc.insert("id", id);
+    }
+    match post {
+        &GenericPost::InstaPost(ref p) => {
+            doc.insert("tags", tags_2_bson(p.tags()));
+            doc.insert("is_video", p.is_video);
+        }
+        &GenericPost::MastodonPost(ref p) => {
+            doc.insert("tags", tags_2_bson(p.tags()));
+            doc.insert("status_url", p.status_url.as_str());
+        }
+        _ => {}
+    }
+    doc
+}
+
+fn tags_2_bson(tags: &[Hashtag]) -> Vec<Bson> {
+    tags.iter()
+        .map(|h| Bson::String(h.as_str().to_string()))
+        .collect()
 }
 
-fn doc_2_bluumm_post<S: Size>(doc: Document) -> BluummPost<S> {
+fn doc_2_post<S: Size>(doc: Document) -> GenericPost<S> {
     let image = {
         let binary = doc.get_binary_generic("image").unwrap();
         let image = Image::from_bytes(binary).unwrap();
         SizedImage::with_resize(image)
     };
     let username = doc.get_str("username").unwrap();
-    let hashtag = Hashtag::new(doc.get_str("hashtag").unwrap());
-    BluummPost::new(image, username, hashtag)
+    let hashtag = doc_2_hashtag(&doc);
+    let id = || doc.get_str("id").unwrap().to_string();
+    // Posts inserted before hashtags of captions or statuses were read do not have tags.
+    let tags = || {
+        doc.get_array("tags")
+            .map(|tags| {
+                tags.iter()
+                    .filter_map(|t| t.as_str())
+                    .filter_map(|t| Hashtag::new(t).ok())
+                    .collect()
+            })
+            .unwrap_or(Vec::new())
+    };
+    let post = match doc.get_str("kind").unwrap() {
+        "bluumm" => GenericPost::BluummPost(BluummPost::new(image, username, hashtag)),
+        "insta" => {
+            let tags = tags();
+            // Posts inserted before videos were distinguished do not have the flag.
+            let is_video = doc.get_bool("is_video").unwrap_or(false);
+            let id = InstaPostId(id());
+            GenericPost::InstaPost(InstaPost::new(id, image, username, hashtag, tags, is_video))
+        }
+        "local" => {
+            let id = LocalPostId(id());
+            GenericPost::LocalPost(LocalPost::new(id, image, username, hashtag))
+        }
+        "mastodon" => {
+            let id = MastodonPostId(id());
+            let status_url = doc.get_str("status_url").unwrap();
+            let post = MastodonPost::new(id, status_url, image, username, hashtag, tags());
+            GenericPost::MastodonPost(post)
+        }
+        "external" => {
+            let id = ExternalPostId(id());
+            GenericPost::ExternalPost(ExternalPost::new(id, image, username, hashtag))
+        }
+        kind => panic!("Unknown kind of post : {}", kind),
+    };
+    // Bson does not have unsigned integer.
+    match doc.get_i64("phash") {
+        Ok(phash) => post.with_phash(ImageHash(phash as u64)),
+        Err(_) => post,
+    }
+}
+
+// Runs a migration unless it is recorded as done.
+fn migrate_once<F: FnOnce()>(db: &Database, name: &str, migrate: F) {
+    let migration = db.collection("migration");
+    let entry = doc! { "name": name };
+    let done = migration
+        .find_one(Some(entry.clone()), None)
+        .expect("Should handle this error")
+        .is_some();
+    if done {
+        return;
+    }
+    info!("Run migration {}", name);
+    migrate();
+    migration
+        .insert_one(entry, None)
+        .expect("Should delegate this error");
+}
+
+// Rewrites hashtags stored before normalization was introduced into normalized form.
+// A stored hashtag which is not valid anymore is kept as it is.
+fn normalize_stored_hashtags(collection: &Collection) {
+    let normalize = |raw: &str| Hashtag::new(raw).map(|h| h.as_str().to_string()).ok();
+    let option = {
+        let mut op = FindOptions::new();
+        op.projection = Some(doc!{"hashtag": 1, "tags": 1});
+        op
+    };
+    let docs = collection
+        .find(None, Some(option))
+        .expect("Fail to execute find operation");
+    for doc in docs {
+        let doc = doc.expect("Invalid document");
+        let mut fields = Document::new();
+        if let Ok(raw) = doc.get_str("hashtag") {
+            match normalize(raw) {
+                Some(ref hashtag) if hashtag != raw => {
+                    fields.insert("hashtag", hashtag.as_str());
+                }
+                _ => {}
+            }
+        }
+        if let Ok(tags) = doc.get_array("tags") {
+            let normalized: Vec<Bson> = tags.iter()
+                .map(|t| match t.as_str().and_then(|t| normalize(t)) {
+                    Some(tag) => Bson::String(tag),
+                    None => t.clone(),
+                })
+                .collect();
+            if &normalized != tags {
+                fields.insert("tags", normalized);
+            }
+        }
+        if !fields.is_empty() {
+            let filter = doc! { "_id": doc.get("_id").unwrap().clone() };
+            collection
+                .update_one(filter, doc! { "$set": fields }, None)
+                .expect("Fail to execute update operation");
+        }
+    }
+}
+
+// Moves posts in the collections for each kind into one collection.
+// The legacy collections are dropped so that this runs only once.
+// Posts are copied one by one, since legacy collections may not fit in memory.
+fn migrate_legacy_posts(db: &Database, post: &Collection) {
+    for &(name, kind) in LEGACY_POST_COLLECTIONS {
+        let legacy = db.collection(name);
+        let cursor = legacy
+            .find(None, None)
+            .expect("Fail to execute find operation");
+        let mut migrated = 0;
+        for doc in cursor {
+            let mut doc = doc.expect("Invalid document");
+            doc.insert("kind", kind);
+            let filter = doc! { "_id": doc.get("_id").unwrap().clone() };
+            let option = {
+                let mut op = UpdateOptions::new();
+                op.upsert = Some(true);
+                op
+            };
+            post.replace_one(filter, doc, Some(option))
+                .expect("Fail to execute replace operation");
+            migrated += 1;
+        }
+        if migrated == 0 {
+            continue;
+        }
+        info!("Migrate {} posts from {} collection", migrated, name);
+        legacy.drop().expect("Fail to drop legacy collection");
+    }
+}
+
+// A stored hashtag which is not valid anymore is kept as it is.
+fn doc_2_hashtag(doc: &Document) -> Hashtag {
+    let raw = doc.get_str("hashtag").unwrap();
+    Hashtag::new(raw).unwrap_or_else(|_| Hashtag(Arc::new(raw.to_string())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use images::size::Size100x100;
+    use post::fixtures::{insta_post, local_post};
+
+    fn stored_ids(db: &Mongodb) -> Vec<String> {
+        let hashtags = HashtagList::new(vec!["tokyo".into()]).unwrap();
+        let mut ids: Vec<String> = db.find_posts_by_hashtags::<Size100x100>(&hashtags, &[], 10)
+            .iter()
+            .map(|p| p.id().unwrap().to_string())
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    #[ignore] // requires MongoDB
+    fn delete_every_image_of_insta_post() {
+        let db = test_db("delete_insta_post");
+        db.insert_post(&insta_post("abc", "alice"), Some("http://a/1.jpg"));
+        db.insert_post(&insta_post("abc:2", "alice"), Some("http://a/2.jpg"));
+        db.insert_post(&insta_post("abcd", "alice"), Some("http://a/3.jpg"));
+        db.insert_post(&local_post("abc", "alice"), None);
+        db.insert_pending_post(1, &insta_post("abc", "alice"));
+
+        let takedown = Takedown::InstaPost(InstaPostId("abc".into()));
+        let mut urls = db.find_image_urls(&takedown);
+        urls.sort();
+        assert_eq!(urls, vec!["http://a/1.jpg", "http://a/2.jpg"]);
+
+        db.delete_posts(&takedown);
+        assert_eq!(stored_ids(&db), vec!["abc", "abcd"]);
+        assert!(db.find_pending_posts::<Size100x100>(1, 10).is_empty());
+    }
+
+    #[test]
+    #[ignore] // requires MongoDB
+    fn delete_posts_of_user() {
+        let db = test_db("delete_user_posts");
+        db.insert_post(&insta_post("abc", "alice"), None);
+        db.insert_post(&local_post("def", "alice"), None);
+        db.insert_post(&local_post("ghi", "bob"), None);
+
+        db.delete_posts(&Takedown::User("alice".into()));
+        assert_eq!(stored_ids(&db), vec!["ghi"]);
+    }
+
+    #[test]
+    #[ignore] // requires MongoDB
+    fn attach_detached_pending_posts() {
+        let db = test_db("attach_pending_posts");
+        db.insert_pending_post(1, &insta_post("abc", "alice"));
+        db.insert_pending_post(1, &insta_post("def", "bob"));
+        db.insert_pending_post(2, &local_post("ghi", "alice"));
+        db.detach_pending_posts_of_worker(1);
+        assert!(db.find_pending_posts::<Size100x100>(1, 10).is_empty());
+
+        let alice = |p: &GenericPost<Size100x100>| p.user_name() == "alice";
+        assert_eq!(db.attach_pending_posts(3, alice), 1);
+        assert_eq!(db.attach_pending_posts(4, alice), 0);
+        let pending = db.find_pending_posts::<Size100x100>(3, 10);
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].post.id(), Some("abc"));
+
+        db.detach_all_pending_posts();
+        assert_eq!(db.attach_pending_posts(5, |_: &GenericPost<Size100x100>| true), 3);
+    }
+
+    #[test]
+    #[ignore] // requires MongoDB
+    fn read_stored_phash() {
+        let db = test_db("read_stored_phash");
+        db.insert_post(&local_post("abc", "alice").with_phash(ImageHash(42)), None);
+
+        let hashtags = HashtagList::new(vec!["tokyo".into()]).unwrap();
+        let posts = db.find_posts_by_hashtags::<Size100x100>(&hashtags, &[], 1);
+        assert_eq!(posts[0].phash(), ImageHash(42));
+    }
+
+    #[test]
+    #[ignore] // requires MongoDB
+    fn skip_excluded_posts() {
+        let db = test_db("skip_excluded_posts");
+        db.insert_post(&local_post("abc", "alice"), None);
+        db.insert_post(&insta_post("abc", "alice"), None);
+        db.insert_post(&local_post("def", "alice"), None);
+
+        let hashtags = HashtagList::new(vec!["tokyo".into()]).unwrap();
+        let exclude = [("local", "abc"), ("local", "def")];
+        let posts = db.find_posts_by_hashtags::<Size100x100>(&hashtags, &exclude, 1);
+        assert_eq!(posts.len(), 1);
+        assert_eq!(posts[0].kind(), "insta");
+    }
 }
@@ -8,8 +8,12 @@ error_chain! {
         SerdeJson(::serde_json::error::Error);
         Image(::image::ImageError);
         Uri(::http::uri::InvalidUri);
+        Http(::http::Error);
         Timer(::tokio::timer::Error);
+        Canceled(::futures::Canceled);
         Base64Decode(::base64::DecodeError);
+        Io(::std::io::Error);
+        Zip(::zip::result::ZipError);
     }
 
     errors {
@@ -17,5 +21,115 @@ error_chain! {
             description("Invalid image size")
             display("Size {} x {} is expected but found another", expected_w, expected_h)
         }
+
+        FetchTimeout(url: String) {
+            description("Timed out while fetching")
+            display("Timed out while fetching {}", url)
+        }
+
+        FetchTooLarge(url: String, limit: usize) {
+            description("Response body is too large")
+            display("Response body of {} exceeds {} bytes", url, limit)
+        }
+
+        FetchTooManyRedirects(url: String) {
+            description("Too many redirects")
+            display("Too many redirects while fetching {}", url)
+        }
+
+        FetchBadStatus(url: String, status: u16) {
+            description("Unexpected response status")
+            display("Unexpected status {} from {}", status, url)
+        }
+
+        FetchUnsupportedUri(url: String, reason: String) {
+            description("Unsupported URI")
+            display("Can not fetch {} : {}", url, reason)
+        }
+
+        InvalidDataUri {
+            description("Invalid data URI")
+            display("Invalid data URI")
+        }
+
+        FetchBadContentType(url: String, content_type: String) {
+            description("Unexpected content type")
+            display("Unexpected content type \"{}\" from {}", content_type, url)
+        }
+
+        GraphHashtagNotFound(hashtag: String) {
+            description("Hashtag is not found on Graph API")
+            display("Hashtag {} is not found on Graph API", hashtag)
+        }
+
+        InvalidHashtag(hashtag: String, reason: String) {
+            description("Invalid hashtag")
+            display("Invalid hashtag \"{}\" : {}", hashtag, reason)
+        }
+
+        InvalidHashtagQuery(reason: String) {
+            description("Invalid hashtag query")
+            display("Invalid hashtag query : {}", reason)
+        }
+
+        InvalidTimelapseOption(reason: String) {
+            description("Invalid timelapse option")
+            display("Invalid timelapse option : {}", reason)
+        }
+
+        LowQualityImage(reason: String) {
+            description("Low quality image")
+            display("Low quality image : {}", reason)
+        }
+
+        InvalidSignature {
+            description("Invalid signature")
+            display("Signature does not match body")
+        }
+
+        IngestReplayed(reason: String) {
+            description("Ingest request is stale or replayed")
+            display("Ingest request is rejected : {}", reason)
+        }
+
+        IngestUnavailable {
+            description("Ingest is unavailable")
+            display("Ingest thread has stopped")
+        }
+
+        InvalidIngestPost(reason: String) {
+            description("Invalid ingested post")
+            display("Invalid ingested post : {}", reason)
+        }
+
+        InstaNotFound(url: String) {
+            description("Instagram post or hashtag is not found")
+            display("{} is not found or has been deleted", url)
+        }
+
+        InstaLoginRequired(url: String) {
+            description("Instagram requires login")
+            display("Instagram requires login to read {}", url)
+        }
+
+        InstaSchemaMismatch(url: String, reason: String) {
+            description("Unexpected response schema of Instagram API")
+            display("Unexpected response of {} : {}", url, reason)
+        }
+
+        InstaRateLimited(url: String) {
+            description("Instagram API is limited")
+            display("Instagram API is limited while reading {}", url)
+        }
+
+        InstaRetryExhausted(url: String, attempts: u32) {
+            description("Gave up calling Instagram API")
+            display("Gave up reading {} after {} attempts", url, attempts)
+        }
+
+        CassetteMiss(url: String) {
+            description("Response is not recorded in cassette")
+            display("Response of {} is not recorded in cassette", url)
+        }
     }
 }
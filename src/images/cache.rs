@@ -0,0 +1,341 @@
+use std::{collections::HashMap, fs, path::{Path, PathBuf}, sync::Mutex, time::UNIX_EPOCH};
+
+use util::sha256_hex;
+use error::Error;
+
+#[derive(Debug, Clone)]
+pub struct ImageCacheConfig {
+    pub dir: PathBuf,
+    pub max_bytes: u64,
+}
+
+/// On-disk content addressed cache of original (not resized) image bytes.
+///
+/// Layout of the cache directory is
+/// - `objects/<sha256 of content>` : image bytes
+/// - `urls/<sha256 of url>` : sha256 of content fetched from the url
+///
+/// When total size of objects and url entries exceeds `max_bytes`, least recently used objects
+/// are removed together with url entries pointing to them.
+#[derive(Debug)]
+pub struct ImageCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    index: Mutex<CacheIndex>,
+}
+
+#[derive(Debug)]
+struct CacheIndex {
+    entries: HashMap<String, CacheEntry>,
+    // Hash of content of each url entry keyed by the name of the entry.
+    urls: HashMap<String, String>,
+    total_bytes: u64,
+    clock: u64,
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    size: u64,
+    last_used: u64,
+}
+
+impl ImageCache {
+    pub fn open(config: ImageCacheConfig) -> Result<ImageCache, Error> {
+        fs::create_dir_all(config.dir.join("objects"))?;
+        fs::create_dir_all(config.dir.join("urls"))?;
+
+        // Restore LRU order from modified time of each object.
+        let mut objects = Vec::new();
+        for entry in fs::read_dir(config.dir.join("objects"))? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if is_temporary(name.as_str()) {
+                let _ = fs::remove_file(entry.path());
+                continue;
+            }
+            let meta = entry.metadata()?;
+            let modified = meta.modified()?
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            objects.push((modified, name, meta.len()));
+        }
+        objects.sort();
+
+        let mut index = CacheIndex {
+            entries: HashMap::new(),
+            urls: HashMap::new(),
+            total_bytes: 0,
+            clock: 0,
+        };
+        for (_, name, size) in objects {
+            index.clock += 1;
+            index.total_bytes += size;
+            let entry = CacheEntry {
+                size: size,
+                last_used: index.clock,
+            };
+            index.entries.insert(name, entry);
+        }
+
+        for entry in fs::read_dir(config.dir.join("urls"))? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let hash = if is_temporary(name.as_str()) {
+                None
+            } else {
+                fs::read_to_string(entry.path()).ok()
+            };
+            match hash {
+                Some(ref hash) if index.entries.contains_key(hash.as_str()) => {
+                    index.total_bytes += hash.len() as u64;
+                    index.urls.insert(name, hash.clone());
+                }
+                // Entry of an evicted object or temporary file.
+                _ => {
+                    let _ = fs::remove_file(entry.path());
+                }
+            }
+        }
+        info!(
+            "Open image cache at {:?}. {} objects, {} urls, {} bytes",
+            config.dir,
+            index.entries.len(),
+            index.urls.len(),
+            index.total_bytes
+        );
+
+        Ok(ImageCache {
+            dir: config.dir,
+            max_bytes: config.max_bytes,
+            index: Mutex::new(index),
+        })
+    }
+
+    pub fn get(&self, url: &str) -> Option<Vec<u8>> {
+        let hash = {
+            let mut index = self.index.lock().unwrap();
+            let hash = index.urls.get(url_name(url).as_str())?.clone();
+            index.clock += 1;
+            let clock = index.clock;
+            index.entries.get_mut(hash.as_str())?.last_used = clock;
+            hash
+        };
+        match fs::read(self.object_path(hash.as_str())) {
+            Ok(bytes) => {
+                debug!("Image cache hit : {}", url);
+                Some(bytes)
+            }
+            Err(e) => {
+                warn!("Fail to read cached image of {} : {:?}", url, e);
+                None
+            }
+        }
+    }
+
+    /// Removes the object cached for `url`, e.g. because it turned out to be broken.
+    /// Other urls of the same content are also removed.
+    pub fn remove(&self, url: &str) {
+        let mut index = self.index.lock().unwrap();
+        let hash = match index.urls.get(url_name(url).as_str()) {
+            Some(hash) => hash.clone(),
+            None => return,
+        };
+        self.remove_object(&mut index, hash.as_str());
+        debug!("Remove cached image of {}", url);
+    }
+
+    /// Errors are just logged because cache is not essential.
+    pub fn put(&self, url: &str, bytes: &[u8]) {
+        if let Err(e) = self.try_put(url, bytes) {
+            warn!("Fail to cache image of {} : {:?}", url, e);
+        }
+    }
+
+    fn try_put(&self, url: &str, bytes: &[u8]) -> Result<(), Error> {
+        let hash = sha256_hex(bytes);
+        let mut index = self.index.lock().unwrap();
+        index.clock += 1;
+        let clock = index.clock;
+
+        if !index.entries.contains_key(hash.as_str()) {
+            write_atomic(&self.object_path(hash.as_str()), bytes)?;
+            index.total_bytes += bytes.len() as u64;
+            let entry = CacheEntry {
+                size: bytes.len() as u64,
+                last_used: clock,
+            };
+            index.entries.insert(hash.clone(), entry);
+        }
+        let name = url_name(url);
+        write_atomic(&self.url_path(name.as_str()), hash.as_bytes())?;
+        if index.urls.insert(name, hash.clone()).is_none() {
+            index.total_bytes += hash.len() as u64;
+        }
+
+        self.evict(&mut index);
+        Ok(())
+    }
+
+    fn evict(&self, index: &mut CacheIndex) {
+        while index.total_bytes > self.max_bytes {
+            let oldest = match index.entries.iter().min_by_key(|(_, e)| e.last_used) {
+                Some((hash, _)) => hash.clone(),
+                None => return,
+            };
+            self.remove_object(index, oldest.as_str());
+            debug!("Evict cached image {}", oldest);
+        }
+    }
+
+    // Removes an object and url entries pointing to it.
+    fn remove_object(&self, index: &mut CacheIndex, hash: &str) {
+        let names: Vec<String> = index
+            .urls
+            .iter()
+            .filter(|&(_, h)| h == hash)
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in names {
+            index.urls.remove(name.as_str());
+            index.total_bytes -= hash.len() as u64;
+            let _ = fs::remove_file(self.url_path(name.as_str()));
+        }
+        if let Some(entry) = index.entries.remove(hash) {
+            index.total_bytes -= entry.size;
+            if let Err(e) = fs::remove_file(self.object_path(hash)) {
+                warn!("Fail to remove cached image {} : {:?}", hash, e);
+            }
+        }
+    }
+
+    fn object_path(&self, hash: &str) -> PathBuf {
+        self.dir.join("objects").join(hash)
+    }
+
+    fn url_path(&self, name: &str) -> PathBuf {
+        self.dir.join("urls").join(name)
+    }
+}
+
+// Name of the url entry of `url`.
+fn url_name(url: &str) -> String {
+    sha256_hex(url.as_bytes())
+}
+
+// Temporary file left by a crash while writing.
+fn is_temporary(name: &str) -> bool {
+    name.contains('.')
+}
+
+// Bytes are written into a temporary file and renamed
+// so that a crash never leaves a partially written file at `path`.
+fn write_atomic(path: &Path, bytes: &[u8]) -> Result<(), Error> {
+    let tmp = path.with_extension(format!("tmp{}", ::rand::random::<u32>()));
+    fs::write(&tmp, bytes)?;
+    if let Err(e) = fs::rename(&tmp, path) {
+        let _ = fs::remove_file(&tmp);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use util::TempDir;
+
+    // Size of a url entry.
+    const URL_BYTES: u64 = 64;
+
+    fn open(max_bytes: u64) -> (TempDir, ImageCache) {
+        let dir = TempDir::new("image_cache");
+        let cache = ImageCache::open(ImageCacheConfig {
+            dir: dir.path().to_path_buf(),
+            max_bytes: max_bytes,
+        }).unwrap();
+        (dir, cache)
+    }
+
+    fn object_count(cache: &ImageCache) -> usize {
+        fs::read_dir(cache.dir.join("objects")).unwrap().count()
+    }
+
+    fn url_count(cache: &ImageCache) -> usize {
+        fs::read_dir(cache.dir.join("urls")).unwrap().count()
+    }
+
+    #[test]
+    fn hit_cached_url() {
+        let (_dir, cache) = open(1024);
+        assert_eq!(cache.get("http://a/1.png"), None);
+        cache.put("http://a/1.png", b"image1");
+        assert_eq!(cache.get("http://a/1.png"), Some(b"image1".to_vec()));
+        assert_eq!(cache.get("http://a/2.png"), None);
+    }
+
+    #[test]
+    fn share_object_of_same_content() {
+        let (_dir, cache) = open(1024);
+        cache.put("http://a/1.png", b"image");
+        cache.put("http://b/1.png", b"image");
+        assert_eq!(cache.get("http://b/1.png"), Some(b"image".to_vec()));
+        assert_eq!(object_count(&cache), 1);
+    }
+
+    #[test]
+    fn evict_least_recently_used() {
+        let (_dir, cache) = open(12 + 2 * URL_BYTES);
+        cache.put("http://a/1.png", b"image1");
+        cache.put("http://a/2.png", b"image2");
+        // 1.png becomes more recently used than 2.png.
+        assert!(cache.get("http://a/1.png").is_some());
+        cache.put("http://a/3.png", b"image3");
+        assert!(cache.get("http://a/1.png").is_some());
+        assert_eq!(cache.get("http://a/2.png"), None);
+        assert!(cache.get("http://a/3.png").is_some());
+        assert_eq!(object_count(&cache), 2);
+        assert_eq!(url_count(&cache), 2);
+    }
+
+    #[test]
+    fn count_url_entries_toward_limit() {
+        let (_dir, cache) = open(6 + 2 * URL_BYTES);
+        cache.put("http://a/1.png", b"image1");
+        cache.put("http://b/1.png", b"image1");
+        assert!(cache.get("http://a/1.png").is_some());
+        // The third url entry exceeds the limit.
+        cache.put("http://c/1.png", b"image1");
+        assert_eq!(cache.get("http://a/1.png"), None);
+        assert_eq!(object_count(&cache), 0);
+        assert_eq!(url_count(&cache), 0);
+    }
+
+    #[test]
+    fn remove_cached_url() {
+        let (_dir, cache) = open(1024);
+        cache.put("http://a/1.png", b"broken");
+        cache.put("http://b/1.png", b"broken");
+        cache.remove("http://a/1.png");
+        assert_eq!(cache.get("http://a/1.png"), None);
+        assert_eq!(cache.get("http://b/1.png"), None);
+        assert_eq!(object_count(&cache), 0);
+        assert_eq!(url_count(&cache), 0);
+    }
+
+    #[test]
+    fn restore_index_and_clean_temporary_files() {
+        let (_dir, cache) = open(1024);
+        cache.put("http://a/1.png", b"image1");
+        fs::write(cache.dir.join("objects").join("abc.tmp1"), b"partial").unwrap();
+        // Url entry of an object which has been removed.
+        fs::write(cache.dir.join("urls").join(url_name("http://a/2.png")), b"abc").unwrap();
+        let reopened = ImageCache::open(ImageCacheConfig {
+            dir: cache.dir.clone(),
+            max_bytes: 1024,
+        }).unwrap();
+        assert_eq!(reopened.get("http://a/1.png"), Some(b"image1".to_vec()));
+        assert_eq!(object_count(&reopened), 1);
+        assert_eq!(url_count(&reopened), 1);
+    }
+}
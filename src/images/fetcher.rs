@@ -1,33 +1,600 @@
-use std::str::FromStr;
-use hyper::{Uri, client::{Client, HttpConnector}};
+use std::{fs, str::FromStr, sync::Arc, time::{Duration, Instant}};
+use hyper::{Body, Response, Uri, client::{Client, HttpConnector},
+            header::{CONTENT_LENGTH, CONTENT_TYPE, LOCATION}};
 use hyper_tls::HttpsConnector;
-use futures::{Future, Stream};
+use futures::{Future, Stream, future::{self, loop_fn, Either, Loop}};
+use tokio::timer::{Delay, Timeout, timeout};
+use percent_encoding::percent_decode;
 
-use images::{Size, SizedImage, Image};
-use error::Error;
+use images::{HostLimiter, Size, SizedImage, Image, ImageCache};
+use cassette::Cassette;
+use error::{Error, ErrorKind};
+
+// Upper bound of retry interval however many retries are configured.
+const MAX_RETRY_BACKOFF_SECS: u64 = 60;
+
+#[derive(Debug, Clone)]
+pub struct ImageFetcherConfig {
+    // Timeout until response header arrives. It includes connection time.
+    pub connect_timeout: Duration,
+    // Timeout for reading whole response body.
+    pub read_timeout: Duration,
+    pub max_retries: u32,
+    // Initial interval of retry. It is doubled on each retry.
+    pub retry_backoff: Duration,
+    pub max_body_bytes: usize,
+    pub max_redirects: u32,
+    // Number of threads used to resolve DNS.
+    pub dns_threads: usize,
+    pub max_connections_per_host: usize,
+    pub cassette: Option<Cassette>,
+    // Local files can be read through "file://" URIs.
+    // Enable only if every url given to the fetcher is trusted.
+    pub allow_file_uri: bool,
+}
+
+impl Default for ImageFetcherConfig {
+    fn default() -> ImageFetcherConfig {
+        ImageFetcherConfig {
+            connect_timeout: Duration::from_secs(10),
+            read_timeout: Duration::from_secs(30),
+            max_retries: 3,
+            retry_backoff: Duration::from_secs(1),
+            max_body_bytes: 16 * 1024 * 1024,
+            max_redirects: 5,
+            dns_threads: 4,
+            max_connections_per_host: 8,
+            cassette: None,
+            allow_file_uri: false,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct ImageFetcher {
     client: Client<HttpsConnector<HttpConnector>>,
+    config: Arc<ImageFetcherConfig>,
+    cache: Option<Arc<ImageCache>>,
+    host_limiter: HostLimiter,
 }
 
 impl ImageFetcher {
-    pub fn new() -> ImageFetcher {
-        let https = HttpsConnector::new(1).unwrap();
+    pub fn new(config: ImageFetcherConfig, cache: Option<Arc<ImageCache>>) -> ImageFetcher {
+        let https = HttpsConnector::new(config.dns_threads).unwrap();
         let client = Client::builder().build(https);
-        ImageFetcher { client: client }
+        ImageFetcher {
+            client: client,
+            host_limiter: HostLimiter::new(config.max_connections_per_host),
+            config: Arc::new(config),
+            cache: cache,
+        }
     }
 
     pub fn fetch_image<S: Size>(
         &self,
         url: &str,
     ) -> Result<impl Future<Item = SizedImage<S>, Error = Error>, Error> {
+        Ok(self.fetch_original_image(url)?.map(SizedImage::<S>::with_resize))
+    }
+
+    /// Fetches an image without resizing so that its original resolution can be checked.
+    pub fn fetch_original_image(
+        &self,
+        url: &str,
+    ) -> Result<impl Future<Item = Image, Error = Error>, Error> {
+        // Images which can be read without network.
+        let cassette = self.config.cassette.clone();
+        let local_bytes = if url.starts_with("file://") {
+            if !self.config.allow_file_uri {
+                bail!(ErrorKind::FetchUnsupportedUri(
+                    url.into(),
+                    "file URI is not allowed".into()
+                ));
+            }
+            Some(read_file_uri(url)?)
+        } else if url.starts_with("data:") {
+            Some(decode_data_uri(url)?)
+        } else if let Some(cassette) = cassette.as_ref().filter(|c| c.is_replay()) {
+            Some(replay_image(cassette, url)?)
+        } else {
+            None
+        };
+        if let Some(bytes) = local_bytes {
+            let f = future::lazy(move || Image::from_bytes(&bytes));
+            return Ok(Either::A(f));
+        }
+
+        // Cache is not read while recording so that every image is recorded.
+        let cached = match (self.cache.as_ref(), cassette.as_ref()) {
+            (Some(cache), None) => cache.get(url).map(|bytes| (cache.clone(), bytes)),
+            _ => None,
+        };
+        let remote = self.fetch_remote_image(url)?;
+        match cached {
+            // Broken cached image is evicted and fetched again.
+            Some((cache, bytes)) => {
+                let url_str = url.to_string();
+                let f = future::lazy(move || Image::from_bytes(&bytes)).or_else(move |e| {
+                    warn!("Fail to decode cached image of {}. Fetch again : {}", url_str, e);
+                    cache.remove(url_str.as_str());
+                    remote
+                });
+                Ok(Either::B(Either::A(f)))
+            }
+            None => Ok(Either::B(Either::B(remote))),
+        }
+    }
+
+    // Original image is cached so that it can be resized into any size.
+    fn fetch_remote_image(
+        &self,
+        url: &str,
+    ) -> Result<impl Future<Item = Image, Error = Error>, Error> {
+        let cassette = self.config.cassette.clone();
+        let cache = self.cache.clone();
+        let url_str = url.to_string();
         let url = Uri::from_str(url)?;
-        let f = self.client
-            .get(url)
-            .and_then(|res| res.into_body().concat2())
-            .map_err(|e| Error::from(e))
-            .and_then(|data| Ok(SizedImage::with_resize(Image::from_bytes(&data)?)));
+        let f = fetch_with_retry(
+            self.client.clone(),
+            self.config.clone(),
+            self.host_limiter.clone(),
+            url,
+        ).and_then(move |(content_type, data)| {
+            let image = Image::from_bytes(&data)?;
+            if let Some(cache) = cache {
+                cache.put(url_str.as_str(), &data);
+            }
+            if let Some(cassette) = cassette {
+                cassette.record(url_str.as_str(), 200, content_type, &data);
+            }
+            Ok(image)
+        });
         Ok(f)
     }
 }
+
+type HttpsClient = Client<HttpsConnector<HttpConnector>>;
+
+// Accepts both "file:///path/to/img" and "file://localhost/path/to/img".
+fn read_file_uri(url: &str) -> Result<Vec<u8>, Error> {
+    let path = file_uri_path(url)?;
+    Ok(fs::read(path.as_str())?)
+}
+
+fn file_uri_path(url: &str) -> Result<String, Error> {
+    let path = url.trim_left_matches("file://");
+    let path = if path.starts_with("localhost/") {
+        &path["localhost".len()..]
+    } else if path.starts_with("/") {
+        path
+    } else {
+        bail!(ErrorKind::FetchUnsupportedUri(
+            url.into(),
+            "file URI of remote host".into()
+        ));
+    };
+    Ok(percent_decode(path.as_bytes()).decode_utf8_lossy().into_owned())
+}
+
+// data:[<mediatype>][;base64],<data>
+fn decode_data_uri(url: &str) -> Result<Vec<u8>, Error> {
+    let url = url.trim_left_matches("data:");
+    let comma = url.find(',').ok_or(ErrorKind::InvalidDataUri)?;
+    let (meta, data) = (&url[..comma], &url[comma + 1..]);
+    let (media_type, is_base64) = if meta.ends_with(";base64") {
+        (&meta[..meta.len() - ";base64".len()], true)
+    } else {
+        (meta, false)
+    };
+    let media_type = media_type.split(';').next().unwrap_or("");
+    if !media_type.is_empty() && !media_type.starts_with("image/") {
+        bail!(ErrorKind::FetchBadContentType(
+            "data URI".into(),
+            media_type.into()
+        ));
+    }
+    if is_base64 {
+        Ok(::base64::decode(data)?)
+    } else {
+        Ok(percent_decode(data.as_bytes()).collect())
+    }
+}
+
+fn replay_image(cassette: &Cassette, url: &str) -> Result<Vec<u8>, Error> {
+    let recording = cassette.load(url)?;
+    if recording.status < 200 || recording.status >= 300 {
+        bail!(ErrorKind::FetchBadStatus(url.into(), recording.status));
+    }
+    recording.body()
+}
+
+fn fetch_with_retry(
+    client: HttpsClient,
+    config: Arc<ImageFetcherConfig>,
+    host_limiter: HostLimiter,
+    url: Uri,
+) -> impl Future<Item = (Option<String>, Vec<u8>), Error = Error> {
+    let host = url.host().unwrap_or("").to_string();
+    loop_fn(0, move |retried| {
+        let delay = retry_delay(config.retry_backoff, retried);
+        let max_retries = config.max_retries;
+        let url_str = url.to_string();
+        Delay::new(Instant::now() + delay)
+            .map_err(Error::from)
+            .and_then({
+                let (host_limiter, host) = (host_limiter.clone(), host.clone());
+                move |_| host_limiter.acquire(host.as_str())
+            })
+            .and_then({
+                let (client, config, url) = (client.clone(), config.clone(), url.clone());
+                // Keep the permit until whole body is read.
+                move |permit| fetch(client, config, url).map(move |data| {
+                    drop(permit);
+                    data
+                })
+            })
+            .then(move |res| match res {
+                Ok(data) => Ok(Loop::Break(data)),
+                Err(ref e) if is_retriable(e) && retried < max_retries => {
+                    warn!("Fail to fetch {}. Retry : {}", url_str, e);
+                    Ok(Loop::Continue(retried + 1))
+                }
+                Err(e) => Err(e),
+            })
+    })
+}
+
+// Doubled on each retry without overflow.
+fn retry_delay(backoff: Duration, retried: u32) -> Duration {
+    let max = Duration::from_secs(MAX_RETRY_BACKOFF_SECS);
+    match retried {
+        0 => Duration::new(0, 0),
+        n => {
+            let factor = 2u32.checked_pow(n - 1).unwrap_or(u32::max_value());
+            backoff.checked_mul(factor).unwrap_or(max).min(max)
+        }
+    }
+}
+
+fn is_retriable(e: &Error) -> bool {
+    match e.kind() {
+        &ErrorKind::FetchTimeout(_) => true,
+        &ErrorKind::Hyper(_) => true,
+        &ErrorKind::FetchBadStatus(_, status) => status >= 500 || status == 429,
+        _ => false,
+    }
+}
+
+// Returns content type and body.
+fn fetch(
+    client: HttpsClient,
+    config: Arc<ImageFetcherConfig>,
+    url: Uri,
+) -> impl Future<Item = (Option<String>, Vec<u8>), Error = Error> {
+    let config2 = config.clone();
+    get_following_redirect(client, config, url)
+        .and_then(move |(url, res)| read_body(url, res, config2))
+}
+
+// Returns a final url and its response.
+fn get_following_redirect(
+    client: HttpsClient,
+    config: Arc<ImageFetcherConfig>,
+    url: Uri,
+) -> impl Future<Item = (Uri, Response<Body>), Error = Error> {
+    loop_fn((url, 0), move |(url, redirected): (Uri, u32)| {
+        let url_str = url.to_string();
+        let max_redirects = config.max_redirects;
+        let res = client.get(url.clone()).map_err(Error::from);
+        Timeout::new(res, config.connect_timeout)
+            .map_err({
+                let url_str = url_str.clone();
+                move |e| from_timeout_error(e, url_str)
+            })
+            .and_then(
+                move |res| -> Result<Loop<(Uri, Response<Body>), (Uri, u32)>, Error> {
+                    if !res.status().is_redirection() {
+                        return Ok(Loop::Break((url, res)));
+                    }
+                    if redirected >= max_redirects {
+                        bail!(ErrorKind::FetchTooManyRedirects(url_str));
+                    }
+                    let status = res.status().as_u16();
+                    let next = match res.headers().get(LOCATION).and_then(|v| v.to_str().ok()) {
+                        Some(location) => resolve_location(&url, location)?,
+                        None => bail!(ErrorKind::FetchBadStatus(url_str, status)),
+                    };
+                    debug!("Redirect to {}", next);
+                    Ok(Loop::Continue((next, redirected + 1)))
+                },
+            )
+    })
+}
+
+// `location` may be an absolute url, a scheme relative url "//host/path",
+// an absolute path or a path relative to the directory of `base`.
+fn resolve_location(base: &Uri, location: &str) -> Result<Uri, Error> {
+    if location.contains("://") {
+        return Ok(Uri::from_str(location)?);
+    }
+    let (scheme, authority) = match (base.scheme_part(), base.authority_part()) {
+        (Some(scheme), Some(authority)) => (scheme, authority),
+        _ => return Ok(Uri::from_str(location)?),
+    };
+    if location.starts_with("//") {
+        return Ok(Uri::from_str(format!("{}:{}", scheme, location).as_str())?);
+    }
+    let (path, query) = match location.find('?') {
+        Some(i) => (&location[..i], &location[i..]),
+        None => (location, ""),
+    };
+    let path = if path.starts_with('/') {
+        path.to_string()
+    } else {
+        let base_path = base.path();
+        let dir_len = base_path.rfind('/').map(|i| i + 1).unwrap_or(0);
+        format!("/{}{}", base_path[..dir_len].trim_left_matches('/'), path)
+    };
+    Ok(Uri::from_str(
+        format!("{}://{}{}{}", scheme, authority, normalize_path(path.as_str()), query).as_str(),
+    )?)
+}
+
+// Removes "." and ".." segments from an absolute path.
+fn normalize_path(path: &str) -> String {
+    let mut segments = Vec::new();
+    for segment in path.split('/').skip(1) {
+        match segment {
+            "." => {}
+            ".." => {
+                segments.pop();
+            }
+            s => segments.push(s),
+        }
+    }
+    let mut normalized = format!("/{}", segments.join("/"));
+    if (path.ends_with("/.") || path.ends_with("/..")) && !normalized.ends_with('/') {
+        normalized.push('/');
+    }
+    normalized
+}
+
+fn check_response(url: &str, res: &Response<Body>, max_bytes: usize) -> Result<(), Error> {
+    if !res.status().is_success() {
+        bail!(ErrorKind::FetchBadStatus(url.into(), res.status().as_u16()));
+    }
+    let content_type = res.headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+    if !content_type.starts_with("image/") && content_type != "application/octet-stream" {
+        bail!(ErrorKind::FetchBadContentType(url.into(), content_type.into()));
+    }
+    let content_length = res.headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|s| s.parse::<usize>().ok());
+    if content_length.map(|len| len > max_bytes).unwrap_or(false) {
+        bail!(ErrorKind::FetchTooLarge(url.into(), max_bytes));
+    }
+    Ok(())
+}
+
+fn read_body(
+    url: Uri,
+    res: Response<Body>,
+    config: Arc<ImageFetcherConfig>,
+) -> impl Future<Item = (Option<String>, Vec<u8>), Error = Error> {
+    let url_str = url.to_string();
+    let max_bytes = config.max_body_bytes;
+    if let Err(e) = check_response(url_str.as_str(), &res, max_bytes) {
+        return Either::A(future::err(e));
+    }
+    let content_type = res.headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|s| s.to_string());
+
+    let url_str2 = url_str.clone();
+    let body = res.into_body()
+        .map_err(Error::from)
+        .fold(Vec::new(), move |mut vec, chunk| -> Result<Vec<u8>, Error> {
+            if vec.len() + chunk.len() > max_bytes {
+                bail!(ErrorKind::FetchTooLarge(url_str2.clone(), max_bytes));
+            }
+            vec.extend_from_slice(&chunk);
+            Ok(vec)
+        });
+    let f = Timeout::new(body, config.read_timeout)
+        .map_err(move |e| from_timeout_error(e, url_str))
+        .map(move |body| (content_type, body));
+    Either::B(f)
+}
+
+fn from_timeout_error(e: timeout::Error<Error>, url: String) -> Error {
+    if e.is_elapsed() {
+        ErrorKind::FetchTimeout(url).into()
+    } else if e.is_timer() {
+        Error::from(e.into_timer().unwrap())
+    } else {
+        e.into_inner().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::SocketAddr, thread, sync::atomic::{AtomicUsize, Ordering}};
+    use hyper::{Request, Server, service::service_fn};
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn decode_base64_data_uri() {
+        let bytes = decode_data_uri("data:image/png;base64,iVBORw==").unwrap();
+        assert_eq!(bytes, vec![0x89, b'P', b'N', b'G']);
+    }
+
+    #[test]
+    fn decode_percent_encoded_data_uri() {
+        let bytes = decode_data_uri("data:,a%20b").unwrap();
+        assert_eq!(bytes, b"a b".to_vec());
+    }
+
+    #[test]
+    fn reject_non_image_data_uri() {
+        assert!(decode_data_uri("data:text/plain;base64,YQ==").is_err());
+        assert!(decode_data_uri("data:image/png;base64").is_err());
+    }
+
+    #[test]
+    fn parse_file_uri() {
+        assert_eq!(file_uri_path("file:///tmp/a%20b.png").unwrap(), "/tmp/a b.png");
+        assert_eq!(file_uri_path("file://localhost/tmp/a.png").unwrap(), "/tmp/a.png");
+        assert!(file_uri_path("file://host/tmp/a.png").is_err());
+        assert!(file_uri_path("file://tmp/a.png").is_err());
+    }
+
+    #[test]
+    fn reject_file_uri_by_default() {
+        let fetcher = ImageFetcher::new(ImageFetcherConfig::default(), None);
+        match fetcher.fetch_original_image("file:///etc/passwd") {
+            Err(e) => match e.kind() {
+                &ErrorKind::FetchUnsupportedUri(..) => {}
+                e => panic!("Unexpected error : {:?}", e),
+            },
+            Ok(_) => panic!("file URI is accepted"),
+        }
+    }
+
+    #[test]
+    fn cap_retry_delay() {
+        let backoff = Duration::from_secs(1);
+        assert_eq!(retry_delay(backoff, 0), Duration::new(0, 0));
+        assert_eq!(retry_delay(backoff, 1), Duration::from_secs(1));
+        assert_eq!(retry_delay(backoff, 3), Duration::from_secs(4));
+        assert_eq!(retry_delay(backoff, 40), Duration::from_secs(MAX_RETRY_BACKOFF_SECS));
+        assert_eq!(
+            retry_delay(backoff, u32::max_value()),
+            Duration::from_secs(MAX_RETRY_BACKOFF_SECS)
+        );
+    }
+
+    #[test]
+    fn resolve_redirect_location() {
+        let base = Uri::from_str("http://a.com/img/sub/x.png?size=1").unwrap();
+        let resolve = |location| resolve_location(&base, location).unwrap().to_string();
+        assert_eq!(resolve("https://b.com/y.png"), "https://b.com/y.png");
+        assert_eq!(resolve("//b.com/y.png"), "http://b.com/y.png");
+        assert_eq!(resolve("/y.png"), "http://a.com/y.png");
+        assert_eq!(resolve("y.png"), "http://a.com/img/sub/y.png");
+        assert_eq!(resolve("./y.png?q=1"), "http://a.com/img/sub/y.png?q=1");
+        assert_eq!(resolve("../y.png"), "http://a.com/img/y.png");
+        assert_eq!(resolve("../../../y.png"), "http://a.com/y.png");
+    }
+
+    // Serves a tiny PNG image and failure cases on a local port.
+    fn serve() -> SocketAddr {
+        let flaky_count = Arc::new(AtomicUsize::new(0));
+        let (addr_tx, addr_rx) = ::std::sync::mpsc::channel();
+        thread::spawn(move || {
+            let addr = ([127, 0, 0, 1], 0).into();
+            let server = Server::bind(&addr).serve(move || {
+                let flaky_count = flaky_count.clone();
+                service_fn(move |req: Request<Body>| {
+                    let png = Image::clear_image(2, 2).to_png_bytes();
+                    let res = |status: u16, location: &str, body: Vec<u8>| {
+                        let mut builder = Response::builder();
+                        builder.status(status).header(CONTENT_TYPE, "image/png");
+                        if !location.is_empty() {
+                            builder.header(LOCATION, location);
+                        }
+                        builder.body(Body::from(body)).unwrap()
+                    };
+                    let delay = match req.uri().path() {
+                        "/slow.png" => Duration::from_secs(5),
+                        _ => Duration::new(0, 0),
+                    };
+                    let res = match req.uri().path() {
+                        "/img/a.png" | "/slow.png" => res(200, "", png),
+                        "/img/sub/relative" => res(302, "../a.png", vec![]),
+                        "/loop" => res(302, "/loop", vec![]),
+                        "/large.png" => res(200, "", vec![0; 1024]),
+                        "/flaky.png" => match flaky_count.fetch_add(1, Ordering::SeqCst) {
+                            0 | 1 => res(503, "", vec![]),
+                            _ => res(200, "", png),
+                        },
+                        _ => res(404, "", vec![]),
+                    };
+                    Delay::new(Instant::now() + delay).map(move |_| res)
+                })
+            });
+            addr_tx.send(server.local_addr()).unwrap();
+            ::tokio::run(server.map_err(|e| error!("Test server error : {:?}", e)));
+        });
+        addr_rx.recv().unwrap()
+    }
+
+    fn fetch(addr: SocketAddr, path: &str, max_retries: u32) -> Result<Image, Error> {
+        let config = ImageFetcherConfig {
+            connect_timeout: Duration::from_millis(500),
+            max_retries: max_retries,
+            retry_backoff: Duration::from_millis(10),
+            max_body_bytes: 512,
+            max_redirects: 2,
+            ..ImageFetcherConfig::default()
+        };
+        let url = format!("http://{}{}", addr, path);
+        let f = ImageFetcher::new(config, None).fetch_original_image(url.as_str())?;
+        Runtime::new().unwrap().block_on(f)
+    }
+
+    #[test]
+    fn fetch_image_following_relative_redirect() {
+        let addr = serve();
+        assert_eq!(fetch(addr, "/img/a.png", 0).unwrap().width(), 2);
+        assert_eq!(fetch(addr, "/img/sub/relative", 0).unwrap().width(), 2);
+    }
+
+    #[test]
+    fn give_up_too_many_redirects() {
+        match fetch(serve(), "/loop", 0).unwrap_err().kind() {
+            &ErrorKind::FetchTooManyRedirects(_) => {}
+            e => panic!("Unexpected error : {:?}", e),
+        }
+    }
+
+    #[test]
+    fn reject_too_large_body() {
+        match fetch(serve(), "/large.png", 0).unwrap_err().kind() {
+            &ErrorKind::FetchTooLarge(_, 512) => {}
+            e => panic!("Unexpected error : {:?}", e),
+        }
+    }
+
+    #[test]
+    fn time_out_slow_response() {
+        match fetch(serve(), "/slow.png", 0).unwrap_err().kind() {
+            &ErrorKind::FetchTimeout(_) => {}
+            e => panic!("Unexpected error : {:?}", e),
+        }
+    }
+
+    #[test]
+    fn retry_server_error() {
+        let addr = serve();
+        match fetch(addr, "/flaky.png", 1).unwrap_err().kind() {
+            &ErrorKind::FetchBadStatus(_, 503) => {}
+            e => panic!("Unexpected error : {:?}", e),
+        }
+        // The third request succeeds.
+        assert!(fetch(addr, "/flaky.png", 1).is_ok());
+    }
+
+    #[test]
+    fn do_not_retry_client_error() {
+        match fetch(serve(), "/missing.png", 3).unwrap_err().kind() {
+            &ErrorKind::FetchBadStatus(_, 404) => {}
+            e => panic!("Unexpected error : {:?}", e),
+        }
+    }
+}
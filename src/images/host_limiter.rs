@@ -0,0 +1,168 @@
+use std::{collections::{HashMap, VecDeque}, sync::{Arc, Mutex}};
+use futures::{Future, future::{self, Either}, sync::oneshot::{self, Sender}};
+
+use error::Error;
+
+/// Limits the number of concurrent connections to each host.
+#[derive(Debug, Clone)]
+pub struct HostLimiter {
+    max_per_host: usize,
+    hosts: Arc<Mutex<HashMap<String, HostState>>>,
+}
+
+#[derive(Debug)]
+struct HostState {
+    active: usize,
+    waiters: VecDeque<Sender<HostPermit>>,
+}
+
+/// Connection slot of a host. Slot is released when this is dropped.
+#[derive(Debug)]
+pub struct HostPermit {
+    host: String,
+    // None after the slot has been handed over to another permit.
+    hosts: Option<Arc<Mutex<HashMap<String, HostState>>>>,
+}
+
+impl HostLimiter {
+    pub fn new(max_per_host: usize) -> HostLimiter {
+        HostLimiter {
+            max_per_host: max_per_host.max(1),
+            hosts: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn acquire(&self, host: &str) -> impl Future<Item = HostPermit, Error = Error> {
+        let mut hosts = self.hosts.lock().unwrap();
+        let state = hosts.entry(host.to_string()).or_insert(HostState {
+            active: 0,
+            waiters: VecDeque::new(),
+        });
+        if state.active < self.max_per_host {
+            state.active += 1;
+            Either::A(future::ok(HostPermit {
+                host: host.to_string(),
+                hosts: Some(self.hosts.clone()),
+            }))
+        } else {
+            // Permit is sent by a dropped permit.
+            let (tx, rx) = oneshot::channel();
+            state.waiters.push_back(tx);
+            Either::B(rx.map_err(Error::from))
+        }
+    }
+}
+
+impl Drop for HostPermit {
+    fn drop(&mut self) {
+        let hosts = match self.hosts.take() {
+            Some(hosts) => hosts,
+            None => return,
+        };
+        loop {
+            let waiter = {
+                let mut locked = hosts.lock().unwrap();
+                let (waiter, is_idle) = match locked.get_mut(self.host.as_str()) {
+                    Some(state) => match state.waiters.pop_front() {
+                        Some(waiter) => (Some(waiter), false),
+                        None => {
+                            state.active -= 1;
+                            (None, state.active == 0)
+                        }
+                    },
+                    None => (None, false),
+                };
+                if is_idle {
+                    locked.remove(self.host.as_str());
+                }
+                waiter
+            };
+            let waiter = match waiter {
+                Some(waiter) => waiter,
+                None => return,
+            };
+            // Slot is handed over as a new permit, so that it is released even if the waiter
+            // is dropped before receiving it. Sent without lock because it may drop the permit.
+            let permit = HostPermit {
+                host: self.host.clone(),
+                hosts: Some(hosts.clone()),
+            };
+            match waiter.send(permit) {
+                Ok(()) => return,
+                // Waiter has already gone. Try the next one.
+                Err(mut permit) => permit.hosts = None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn waiters(limiter: &HostLimiter, host: &str) -> usize {
+        limiter.hosts.lock().unwrap()[host].waiters.len()
+    }
+
+    fn is_idle(limiter: &HostLimiter, host: &str) -> bool {
+        !limiter.hosts.lock().unwrap().contains_key(host)
+    }
+
+    #[test]
+    fn limit_connections_per_host() {
+        let limiter = HostLimiter::new(1);
+        let _a = limiter.acquire("a").wait().unwrap();
+        let _b = limiter.acquire("b").wait().unwrap();
+        let _waiting = limiter.acquire("a");
+        assert_eq!(waiters(&limiter, "a"), 1);
+        assert_eq!(waiters(&limiter, "b"), 0);
+    }
+
+    #[test]
+    fn hand_over_slot_to_waiter() {
+        let limiter = HostLimiter::new(1);
+        let first = limiter.acquire("a").wait().unwrap();
+        let second = limiter.acquire("a");
+        drop(first);
+        let second = second.wait().unwrap();
+        assert_eq!(waiters(&limiter, "a"), 0);
+        drop(second);
+        assert!(is_idle(&limiter, "a"));
+    }
+
+    #[test]
+    fn skip_cancelled_waiter() {
+        let limiter = HostLimiter::new(1);
+        let first = limiter.acquire("a").wait().unwrap();
+        let cancelled = limiter.acquire("a");
+        let third = limiter.acquire("a");
+        drop(cancelled);
+        drop(first);
+        let third = third.wait().unwrap();
+        drop(third);
+        assert!(is_idle(&limiter, "a"));
+    }
+
+    #[test]
+    fn release_slot_handed_over_to_dropped_waiter() {
+        let limiter = HostLimiter::new(1);
+        let first = limiter.acquire("a").wait().unwrap();
+        let waiter = limiter.acquire("a");
+        drop(first);
+        // Permit has been sent but never received.
+        drop(waiter);
+        assert!(is_idle(&limiter, "a"));
+        assert!(limiter.acquire("a").wait().is_ok());
+    }
+
+    #[test]
+    fn remove_idle_host() {
+        let limiter = HostLimiter::new(2);
+        let a1 = limiter.acquire("a").wait().unwrap();
+        let a2 = limiter.acquire("a").wait().unwrap();
+        drop(a1);
+        assert!(!is_idle(&limiter, "a"));
+        drop(a2);
+        assert!(is_idle(&limiter, "a"));
+    }
+}
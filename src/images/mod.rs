@@ -1,7 +1,17 @@
 pub mod size;
 pub mod fetcher;
+pub mod cache;
+pub mod host_limiter;
 pub mod image;
+pub mod overlay;
+pub mod quality;
+pub mod phash;
 
 pub use self::size::{MultipleOf, Size, SmallerThan};
-pub use self::fetcher::ImageFetcher;
+pub use self::fetcher::{ImageFetcher, ImageFetcherConfig};
+pub use self::cache::{ImageCache, ImageCacheConfig};
+pub use self::host_limiter::{HostLimiter, HostPermit};
 pub use self::image::{Image, ImagePiece, ImagePieceIter, InvalidSizeError, Position, SizedImage};
+pub use self::overlay::{BlendMode, Overlay};
+pub use self::quality::QualityConfig;
+pub use self::phash::ImageHash;
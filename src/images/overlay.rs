@@ -0,0 +1,118 @@
+use std::str::FromStr;
+
+use images::Image;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlendMode {
+    Normal,
+    Multiply,
+    SoftLight,
+}
+
+impl FromStr for BlendMode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<BlendMode, String> {
+        match s {
+            "normal" => Ok(BlendMode::Normal),
+            "multiply" => Ok(BlendMode::Multiply),
+            "soft-light" | "soft_light" => Ok(BlendMode::SoftLight),
+            s => Err(format!("Unknown blend mode : {}", s)),
+        }
+    }
+}
+
+impl BlendMode {
+    // Each value is normalized into 0.0 ~ 1.0.
+    fn blend(&self, base: f64, top: f64) -> f64 {
+        match *self {
+            BlendMode::Normal => top,
+            BlendMode::Multiply => base * top,
+            // W3C compositing spec
+            BlendMode::SoftLight => if top <= 0.5 {
+                base - (1.0 - 2.0 * top) * base * (1.0 - base)
+            } else {
+                let d = if base <= 0.25 {
+                    ((16.0 * base - 12.0) * base + 4.0) * base
+                } else {
+                    base.sqrt()
+                };
+                base + (2.0 * top - 1.0) * (d - base)
+            },
+        }
+    }
+}
+
+/// Overlay an image on top of another image at given opacity.
+/// Alpha channel of base image is kept as it is.
+#[derive(Debug, Clone, Copy)]
+pub struct Overlay {
+    pub opacity: f64,
+    pub mode: BlendMode,
+}
+
+impl Overlay {
+    pub fn new(opacity: f64, mode: BlendMode) -> Overlay {
+        Overlay {
+            opacity: opacity.max(0.0).min(1.0),
+            mode: mode,
+        }
+    }
+
+    /// Both images must have same size.
+    pub fn apply(&self, base: &Image, top: &Image) -> Image {
+        assert_eq!(base.dimensions(), top.dimensions());
+        let mut res = base.clone();
+        for (dst, src) in res.pixels_mut().zip(top.pixels()) {
+            let alpha = self.opacity * src.data[3] as f64 / 255.0;
+            for c in 0..3 {
+                let b = dst.data[c] as f64 / 255.0;
+                let t = src.data[c] as f64 / 255.0;
+                let v = b * (1.0 - alpha) + self.mode.blend(b, t) * alpha;
+                dst.data[c] = (v * 255.0).round().max(0.0).min(255.0) as u8;
+            }
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgba, RgbaImage};
+
+    fn filled(v: u8) -> Image {
+        Image::new(RgbaImage::from_pixel(2, 2, Rgba { data: [v, v, v, 255] }))
+    }
+
+    #[test]
+    fn normal_blend_mixes_by_opacity() {
+        let res = Overlay::new(0.5, BlendMode::Normal).apply(&filled(0), &filled(255));
+        assert_eq!(res.get_pixel(0, 0).data, [128, 128, 128, 255]);
+    }
+
+    #[test]
+    fn soft_light_known_values() {
+        let blend = |base, top| BlendMode::SoftLight.blend(base, top);
+        // Dark backdrop is lightened by the polynomial of the spec.
+        assert!((blend(0.25, 0.75) - 0.375).abs() < 1e-9);
+        // Backdrop is darkened by dark source.
+        assert!((blend(0.5, 0.25) - 0.375).abs() < 1e-9);
+        // Square root is used for bright backdrop.
+        assert!((blend(0.64, 1.0) - 0.8).abs() < 1e-9);
+        // Middle gray source keeps backdrop.
+        assert!((blend(0.3, 0.5) - 0.3).abs() < 1e-9);
+    }
+
+    #[test]
+    fn soft_light_overlay_on_image() {
+        // 64 and 191 are about 0.25 and 0.75.
+        let res = Overlay::new(1.0, BlendMode::SoftLight).apply(&filled(64), &filled(191));
+        assert_eq!(res.get_pixel(0, 0).data, [96, 96, 96, 255]);
+    }
+
+    #[test]
+    fn multiply_with_white_keeps_base() {
+        let res = Overlay::new(1.0, BlendMode::Multiply).apply(&filled(100), &filled(255));
+        assert_eq!(res.get_pixel(1, 1).data, [100, 100, 100, 255]);
+    }
+}
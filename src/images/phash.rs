@@ -0,0 +1,72 @@
+use image::{FilterType, imageops::{grayscale, resize}};
+
+use images::Image;
+
+/// Perceptual hash (dHash) of an image.
+/// Similar images such as reposts of the same photo have hashes within small Hamming distance.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ImageHash(pub u64);
+
+impl ImageHash {
+    /// Each bit is whether a pixel is brighter than its right neighbor
+    /// in the 9x8 grayscale thumbnail.
+    pub fn dhash(image: &Image) -> ImageHash {
+        let small = resize(&grayscale(&**image), 9, 8, FilterType::Triangle);
+        let mut hash = 0u64;
+        for y in 0..8 {
+            for x in 0..8 {
+                let left = small.get_pixel(x, y).data[0];
+                let right = small.get_pixel(x + 1, y).data[0];
+                hash = (hash << 1) | (left > right) as u64;
+            }
+        }
+        ImageHash(hash)
+    }
+
+    /// Number of different bits.
+    pub fn distance(&self, other: &ImageHash) -> u32 {
+        (self.0 ^ other.0).count_ones()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgba, RgbaImage};
+
+    fn gradient(width: u32, height: u32, brightness: u8) -> Image {
+        Image::new(RgbaImage::from_fn(width, height, |x, y| {
+            let v = ((x * 7 + y * 3) * 200 / (width * 7 + height * 3)) as u8;
+            let v = v.saturating_add(brightness);
+            Rgba {
+                data: [v, 255 - v, (x * 255 / width) as u8, 255],
+            }
+        }))
+    }
+
+    fn checker(width: u32, height: u32) -> Image {
+        Image::new(RgbaImage::from_fn(width, height, |x, y| {
+            let v = if (x * 8 / width + y * 8 / height) % 2 == 0 { 0 } else { 255 };
+            Rgba {
+                data: [v, v, v, 255],
+            }
+        }))
+    }
+
+    #[test]
+    fn similar_images_have_close_hashes() {
+        let original = ImageHash::dhash(&gradient(300, 300, 0));
+        // Reposts are often resized or edited slightly.
+        let resized = ImageHash::dhash(&gradient(640, 640, 0));
+        let brightened = ImageHash::dhash(&gradient(300, 300, 20));
+        assert!(original.distance(&resized) <= 4);
+        assert!(original.distance(&brightened) <= 4);
+    }
+
+    #[test]
+    fn different_images_have_distant_hashes() {
+        let a = ImageHash::dhash(&gradient(300, 300, 0));
+        let b = ImageHash::dhash(&checker(300, 300));
+        assert!(a.distance(&b) > 10);
+    }
+}
@@ -0,0 +1,149 @@
+use image::imageops::grayscale;
+
+use images::Image;
+use error::{Error, ErrorKind};
+
+// Each channel is quantized into this number of bits to count similar colors as one color.
+const COLOR_QUANTIZE_BITS: u8 = 4;
+
+/// Gates which reject images making mosaic art look bad
+/// such as black screenshots, blank text posts or tiny originals.
+#[derive(Debug, Clone)]
+pub struct QualityConfig {
+    // Minimum resolution of the original image.
+    pub min_width: u32,
+    pub min_height: u32,
+    // Minimum variance of luminance in [0, 255].
+    pub min_luminance_variance: f64,
+    // Maximum fraction of pixels which have almost the same color.
+    pub max_single_color_fraction: f64,
+}
+
+impl Default for QualityConfig {
+    fn default() -> QualityConfig {
+        QualityConfig {
+            min_width: 150,
+            min_height: 150,
+            min_luminance_variance: 100.0,
+            max_single_color_fraction: 0.9,
+        }
+    }
+}
+
+impl QualityConfig {
+    /// Returns `LowQualityImage` error with the reason if `image` does not pass a gate.
+    pub fn check(&self, image: &Image) -> Result<(), Error> {
+        let (width, height) = (image.width(), image.height());
+        if width < self.min_width || height < self.min_height {
+            bail!(ErrorKind::LowQualityImage(format!(
+                "resolution {}x{} is smaller than {}x{}",
+                width, height, self.min_width, self.min_height
+            )));
+        }
+        self.check_content(image)
+    }
+
+    /// Same as `check` except that resolution is not checked,
+    /// e.g. for images which are sent already resized into a piece.
+    pub fn check_content(&self, image: &Image) -> Result<(), Error> {
+        let variance = luminance_variance(image);
+        if variance < self.min_luminance_variance {
+            bail!(ErrorKind::LowQualityImage(format!(
+                "luminance variance {:.1} is lower than {:.1}",
+                variance, self.min_luminance_variance
+            )));
+        }
+        let fraction = single_color_fraction(image);
+        if fraction > self.max_single_color_fraction {
+            bail!(ErrorKind::LowQualityImage(format!(
+                "{:.0}% of pixels have a single color",
+                fraction * 100.0
+            )));
+        }
+        Ok(())
+    }
+}
+
+fn luminance_variance(image: &Image) -> f64 {
+    let gray = grayscale(&**image);
+    let n = gray.len() as f64;
+    let (sum, sum_sq) = gray.iter().fold((0f64, 0f64), |(sum, sum_sq), l| {
+        let l = *l as f64;
+        (sum + l, sum_sq + l * l)
+    });
+    let mean = sum / n;
+    sum_sq / n - mean * mean
+}
+
+fn single_color_fraction(image: &Image) -> f64 {
+    let shift = 8 - COLOR_QUANTIZE_BITS;
+    let mut counts = vec![0u32; 1 << (COLOR_QUANTIZE_BITS * 3)];
+    for pixel in image.chunks(4) {
+        let idx = ((pixel[0] >> shift) as usize) << (COLOR_QUANTIZE_BITS * 2)
+            | ((pixel[1] >> shift) as usize) << COLOR_QUANTIZE_BITS
+            | (pixel[2] >> shift) as usize;
+        counts[idx] += 1;
+    }
+    let max = counts.iter().max().cloned().unwrap_or(0);
+    max as f64 / (image.len() / 4) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgba, RgbaImage};
+
+    fn image<F: Fn(u32, u32) -> [u8; 3]>(width: u32, height: u32, color: F) -> Image {
+        Image::new(RgbaImage::from_fn(width, height, |x, y| {
+            let c = color(x, y);
+            Rgba {
+                data: [c[0], c[1], c[2], 255],
+            }
+        }))
+    }
+
+    #[test]
+    fn accept_colorful_image() {
+        let img = image(200, 200, |x, y| [x as u8, y as u8, (x * y) as u8]);
+        assert!(QualityConfig::default().check(&img).is_ok());
+    }
+
+    #[test]
+    fn reject_tiny_image() {
+        let img = image(100, 200, |x, y| [x as u8, y as u8, (x * y) as u8]);
+        assert!(QualityConfig::default().check(&img).is_err());
+    }
+
+    #[test]
+    fn check_content_of_tiny_image() {
+        let config = QualityConfig::default();
+        let colorful = image(30, 30, |x, y| [x as u8 * 8, y as u8 * 8, (x * y) as u8]);
+        assert!(config.check_content(&colorful).is_ok());
+        let uniform = image(30, 30, |_, _| [0, 0, 0]);
+        assert!(config.check_content(&uniform).is_err());
+    }
+
+    #[test]
+    fn reject_uniform_image() {
+        let img = image(200, 200, |_, _| [0, 0, 0]);
+        assert!(QualityConfig::default().check(&img).is_err());
+    }
+
+    #[test]
+    fn reject_low_contrast_image() {
+        let img = image(200, 200, |x, _| {
+            let l = 120 + (x % 8) as u8;
+            [l, l, l]
+        });
+        assert!(QualityConfig::default().check(&img).is_err());
+    }
+
+    #[test]
+    fn reject_image_mostly_filled_by_single_color() {
+        // High variance because of a small black region in white.
+        let img = image(200, 200, |x, _| if x < 10 { [0, 0, 0] } else { [255, 255, 255] });
+        let config = QualityConfig::default();
+        assert!(luminance_variance(&img) > config.min_luminance_variance);
+        assert!(config.check(&img).is_err());
+    }
+}
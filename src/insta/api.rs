@@ -1,112 +1,386 @@
-use std::{str::FromStr, time::{Duration, Instant}};
-use futures::{Future, Stream, future::{loop_fn, Loop}, stream::iter_ok};
-use hyper::{Uri, client::{Client, HttpConnector}};
+use std::{str::FromStr, sync::Arc, time::{Duration, Instant}};
+use futures::{Future, Stream, future::{self, loop_fn, Either, Loop}};
+use hyper::{Body, Request, Uri, client::{Client, HttpConnector},
+            header::{HeaderName, CONTENT_TYPE, COOKIE, LOCATION, USER_AGENT}};
 use hyper_tls::HttpsConnector;
 use tokio::timer::Delay;
 use percent_encoding::{percent_encode, DEFAULT_ENCODE_SET};
 use serde::de::DeserializeOwned;
+use rand::{thread_rng, Rng};
 
+use insta::{graph::{self, GraphApi, GraphApiConfig}, rate_limit::{RateLimitConfig, RateLimiter}};
 use post::{Hashtag, InstaPostId};
-use error::Error;
+use cassette::Cassette;
+use util::redact_url;
+use error::{Error, ErrorKind};
 
-const API_INTERVAL_SEC: u64 = 3;
-const API_COOLING_SEC: u64 = 30;
+// Web app redirects to this path if login is required.
+const LOGIN_PATH: &str = "/accounts/login";
+// Backoff does not grow after this number of retries.
+const MAX_BACKOFF_EXP: u32 = 6;
 
+#[derive(Debug, Clone)]
+pub enum InstaBackend {
+    // Undocumented endpoints of web app (`?__a=1`).
+    Web,
+    // Official Graph API hashtag search.
+    // Media found by hashtag search do not have user names, so posts have no credit,
+    // trusted users of moderation and takedown by user do not apply to them.
+    Graph(GraphApiConfig),
+}
+
+#[derive(Debug, Clone)]
+pub struct InstaApiConfig {
+    pub backend: InstaBackend,
+    // e.g. "https://www.instagram.com"
+    pub base_url: String,
+    pub user_agent: Option<String>,
+    pub cookie: Option<String>,
+    pub rate_limit: RateLimitConfig,
+    // Max number of retries of a call which is limited or fails temporarily.
+    pub max_retries: u32,
+    // Base duration of exponential backoff between retries.
+    pub retry_backoff: Duration,
+    pub cassette: Option<Cassette>,
+}
+
+impl Default for InstaApiConfig {
+    fn default() -> InstaApiConfig {
+        InstaApiConfig {
+            backend: InstaBackend::Web,
+            base_url: "https://www.instagram.com".into(),
+            user_agent: None,
+            cookie: None,
+            rate_limit: RateLimitConfig::default(),
+            max_retries: 5,
+            retry_backoff: Duration::from_secs(1),
+            cassette: None,
+        }
+    }
+}
+
+pub type PageFuture = Box<Future<Item = (InstaPage, Option<String>), Error = Error> + Send>;
+pub type PageStream = Box<Stream<Item = (Hashtag, InstaPage), Error = Error> + Send>;
+
+/// Instagram API client.
+/// Every call made through an instance is limited by the same rate limiter,
+/// so a process should share one instance over workers.
 pub struct InstaApi {
-    delay: Duration,
+    config: Arc<InstaApiConfig>,
+    limiter: RateLimiter,
+    // Used instead of web endpoints if Graph API backend is selected.
+    graph: Option<GraphApi>,
 }
 
 impl InstaApi {
-    pub fn new() -> InstaApi {
+    pub fn new(config: InstaApiConfig) -> InstaApi {
+        let config = Arc::new(config);
+        let limiter = RateLimiter::new(config.rate_limit.clone());
+        let graph = match config.backend {
+            InstaBackend::Web => None,
+            InstaBackend::Graph(ref graph_config) => {
+                warn!(
+                    "Graph API does not provide user names. \
+                     Instagram posts are neither credited nor matched by user name"
+                );
+                Some(GraphApi::new(config.clone(), limiter.clone(), graph_config.clone()))
+            }
+        };
         InstaApi {
-            delay: Duration::new(API_INTERVAL_SEC, 0),
+            config: config,
+            limiter: limiter,
+            graph: graph,
         }
     }
 
-    pub fn get_posts_by_hashtag(
-        &self,
-        hashtag: &Hashtag,
-    ) -> impl Stream<Item = (Hashtag, InstaPartialPost), Error = Error> {
-        let delay = Delay::new(Instant::now() + self.delay.clone()).map_err(Error::from);
-        let res = get_posts_by_hashtag(hashtag, None);
-        let hashtag = hashtag.clone();
-        delay
-            .and_then(|_| res)
-            .map(|res| iter_ok::<_, Error>(res.posts))
-            .flatten_stream()
-            .map(move |post| (hashtag.clone(), post))
-    }
-
-    pub fn get_post_by_id(
-        &self,
-        id: &InstaPostId,
-    ) -> impl Future<Item = InstaPostResponse, Error = Error> {
-        let delay = Delay::new(Instant::now() + self.delay.clone()).map_err(Error::from);
-        let res = get_post_by_id(id);
-        delay.and_then(|_| res)
+    /// Returns a page of recent posts from `cursor` and cursor of the next page.
+    /// The newest page is returned if `cursor` is `None`.
+    pub fn get_page_by_hashtag(&self, hashtag: &Hashtag, cursor: Option<String>) -> PageFuture {
+        if let Some(ref graph) = self.graph {
+            return Box::new(graph.get_page_by_hashtag(hashtag, cursor));
+        }
+        let res = get_posts_by_hashtag(&self.config, &self.limiter, hashtag, cursor.clone());
+        Box::new(res.map(move |res| parse_page(res, cursor)))
     }
 
-    pub fn get_bunch_posts_by_hashtag(
+    /// Returns all pages which are read before a worker starts.
+    /// Paging resumes from `cursor` if it is given.
+    pub fn get_bunch_pages_by_hashtag(
         &self,
         hashtag: &Hashtag,
-    ) -> impl Stream<Item = (Hashtag, InstaPartialPost), Error = Error> {
-        let interval = self.delay.clone();
+        cursor: Option<String>,
+    ) -> PageStream {
+        if let Some(ref graph) = self.graph {
+            return Box::new(graph.get_bunch_pages_by_hashtag(hashtag, cursor));
+        }
+        let config = self.config.clone();
+        let limiter = self.limiter.clone();
         let hashtag2 = hashtag.clone();
-        let posts_stream = ::futures::stream::unfold((None, true), move |(max_id, has_next)| {
+        let pages = ::futures::stream::unfold((cursor, true), move |(max_id, has_next)| {
             if has_next == false {
                 None
             } else {
-                let delay = Delay::new(Instant::now() + interval.clone()).map_err(Error::from);
-                let res = get_posts_by_hashtag(&hashtag2, max_id);
-                Some(delay.and_then(move |_| {
-                    res.map(|res| (res.posts, (res.end_cursor, res.has_next_page)))
+                let res = get_posts_by_hashtag(&config, &limiter, &hashtag2, max_id.clone());
+                Some(res.map(move |res| {
+                    let (page, next) = parse_page(res, max_id);
+                    let has_next = next.is_some();
+                    (page, (next, has_next))
                 }))
             }
         });
         let hashtag2 = hashtag.clone();
-        posts_stream
-            .map(|posts| iter_ok::<_, Error>(posts))
-            .flatten()
-            .map(move |post| (hashtag2.clone(), post))
+        Box::new(pages.map(move |page| (hashtag2.clone(), page)))
+    }
+
+    /// Fetches metadata which is not included in hashtag page.
+    pub fn complete_post(
+        &self,
+        post: InstaPartialPost,
+    ) -> impl Future<Item = InstaPostResponse, Error = Error> {
+        match self.graph {
+            // Graph API does not provide any more metadata of other users' media.
+            // Even `username` field is not available on hashtag media, so user name is empty.
+            Some(_) => Either::A(future::ok(InstaPostResponse {
+                user_name: String::new(),
+                caption: post.caption,
+                images: vec![InstaImage {
+                    id: post.id.clone(),
+                    image_url: post.image_url,
+                    is_video: false,
+                }],
+                id: post.id,
+            })),
+            None => Either::B(self.get_post_by_id(&post.id)),
+        }
+    }
+
+    pub fn get_post_by_id(
+        &self,
+        id: &InstaPostId,
+    ) -> impl Future<Item = InstaPostResponse, Error = Error> {
+        get_post_by_id(&self.config, &self.limiter, id)
     }
 }
 
 /*
  * Internal api caller functions
  * Call corresponding API of instagram.
- * If call is failed because request limit, try to call again after API_COOLING_SEC seconds.
+ * Every call waits for a token of the rate limiter.
+ * If call is limited or fails temporarily, it is retried with exponential backoff
+ * up to `max_retries` times. The limiter also slows down if call is limited.
+ * Other failures such as removed posts are returned as errors immediately.
  */
 
 fn create_client() -> Client<HttpsConnector<HttpConnector>> {
     Client::builder().build(HttpsConnector::new(1).unwrap())
 }
 
-fn api_call<D: DeserializeOwned>(
+fn build_request(config: &InstaApiConfig, url: Uri) -> Result<Request<Body>, Error> {
+    let mut req = Request::get(url);
+    if let Some(ref user_agent) = config.user_agent {
+        req.header(USER_AGENT, user_agent.as_str());
+    }
+    if let Some(ref cookie) = config.cookie {
+        req.header(COOKIE, cookie.as_str());
+    }
+    Ok(req.body(Body::empty())?)
+}
+
+pub(super) fn api_call<D: DeserializeOwned>(
+    config: &Arc<InstaApiConfig>,
+    limiter: &RateLimiter,
     url: Uri,
-    cooling: Duration,
 ) -> impl Future<Item = D, Error = Error> {
-    loop_fn(Duration::new(0, 0), move |interval| {
-        let cooling = cooling.clone();
-        let delay = Delay::new(Instant::now() + interval).map_err(Error::from);
-        let api_fut = create_client()
-            .get(url.clone())
-            .and_then(|res| res.into_body().concat2())
-            .map_err(Error::from)
-            .map(move |chunk| match ::serde_json::from_slice::<D>(&chunk) {
-                Ok(item) => Loop::Break(item),
-                Err(_err) => {
-                    info!(
-                        "Instagram API may be limited. Try again after {:?}",
-                        cooling
-                    );
-                    Loop::Continue(cooling)
+    if let Some(ref cassette) = config.cassette {
+        if cassette.is_replay() {
+            let url_str = redact_url(url.to_string().as_str());
+            let res = cassette.load(url_str.as_str()).and_then(|rec| {
+                let res = RawResponse {
+                    status: rec.status,
+                    content_type: rec.content_type.clone(),
+                    location: None,
+                    body: rec.body()?,
+                };
+                classify(url_str.as_str(), &res).map_err(|f| f.into_error(url_str.as_str()))
+            });
+            return Either::A(future::result(res));
+        }
+    }
+
+    let config = config.clone();
+    let limiter = limiter.clone();
+    let f = loop_fn(0, move |attempt| {
+        let limiter2 = limiter.clone();
+        let (max_retries, retry_backoff) = (config.max_retries, config.retry_backoff);
+        let cassette = config.cassette.clone();
+        // Graph API urls have an access token.
+        let url_str = redact_url(url.to_string().as_str());
+        let client = create_client();
+        let api_fut = future::result(build_request(&config, url.clone()))
+            .and_then(move |req| client.request(req).map_err(Error::from))
+            .and_then(|res| {
+                let status = res.status().as_u16();
+                let (content_type, location) = {
+                    let header = |name: HeaderName| {
+                        res.headers()
+                            .get(name)
+                            .and_then(|v| v.to_str().ok())
+                            .map(|s| s.to_string())
+                    };
+                    (header(CONTENT_TYPE), header(LOCATION))
+                };
+                res.into_body()
+                    .concat2()
+                    .map_err(Error::from)
+                    .map(move |chunk| RawResponse {
+                        status: status,
+                        content_type: content_type,
+                        location: location,
+                        body: chunk.to_vec(),
+                    })
+            })
+            .then(move |res| {
+                let res = match res {
+                    Ok(res) => {
+                        if let Some(cassette) = cassette {
+                            let content_type = res.content_type.clone();
+                            cassette.record(url_str.as_str(), res.status, content_type, &res.body);
+                        }
+                        classify::<D>(url_str.as_str(), &res)
+                    }
+                    Err(e) => Err(CallFailure::Transient(e)),
+                };
+                let failure = match res {
+                    Ok(item) => {
+                        limiter2.reward();
+                        return Either::A(future::ok(Loop::Break(item)));
+                    }
+                    Err(CallFailure::Permanent(e)) => return Either::A(future::err(e)),
+                    Err(failure) => failure,
+                };
+                if attempt >= max_retries {
+                    let e = ErrorKind::InstaRetryExhausted(url_str, attempt + 1).into();
+                    return Either::A(future::err(e));
                 }
+                let wait = backoff(retry_backoff, attempt);
+                match failure {
+                    CallFailure::Limited => {
+                        info!("Instagram API is limited. Try again after {:?}", wait);
+                        limiter2.penalize();
+                    }
+                    CallFailure::Transient(e) => {
+                        info!("Fail to call Instagram API ({}). Try again after {:?}", e, wait);
+                    }
+                    CallFailure::Permanent(_) => unreachable!(),
+                }
+                let delay = Delay::new(Instant::now() + wait)
+                    .map_err(Error::from)
+                    .map(move |_| Loop::Continue(attempt + 1));
+                Either::B(delay)
             });
-        delay.and_then(|_| api_fut)
+        limiter.acquire().and_then(|_| api_fut)
+    });
+    Either::B(f)
+}
+
+struct RawResponse {
+    status: u16,
+    content_type: Option<String>,
+    location: Option<String>,
+    body: Vec<u8>,
+}
+
+// How a failed call is handled.
+#[derive(Debug)]
+enum CallFailure {
+    // Retried after the rate limiter slows down.
+    Limited,
+    // Retried with backoff. e.g. network errors and server errors.
+    Transient(Error),
+    // Never retried.
+    Permanent(Error),
+}
+
+impl CallFailure {
+    fn into_error(self, url: &str) -> Error {
+        match self {
+            CallFailure::Limited => ErrorKind::InstaRateLimited(url.to_string()).into(),
+            CallFailure::Transient(e) | CallFailure::Permanent(e) => e,
+        }
+    }
+}
+
+// Graph API responds 400 or 403 with one of the throttling error codes
+// when a call is throttled.
+fn is_graph_throttled(body: &[u8]) -> bool {
+    #[derive(Deserialize)]
+    struct Response {
+        error: GraphError,
+    }
+    #[derive(Deserialize)]
+    struct GraphError {
+        code: u32,
+    }
+    ::serde_json::from_slice::<Response>(body)
+        .map(|res| graph::THROTTLE_ERROR_CODES.contains(&res.error.code))
+        .unwrap_or(false)
+}
+
+fn classify<D: DeserializeOwned>(url: &str, res: &RawResponse) -> Result<D, CallFailure> {
+    let is_login_wall = |s: &str| s.contains(LOGIN_PATH);
+    let bad_status = || -> Error { ErrorKind::FetchBadStatus(url.to_string(), res.status).into() };
+    match res.status {
+        429 => return Err(CallFailure::Limited),
+        400 | 403 if is_graph_throttled(&res.body) => return Err(CallFailure::Limited),
+        404 | 410 => {
+            return Err(CallFailure::Permanent(
+                ErrorKind::InstaNotFound(url.to_string()).into(),
+            ))
+        }
+        300...399 => {
+            if res.location.as_ref().map(|l| is_login_wall(l.as_str())).unwrap_or(false) {
+                return Err(CallFailure::Permanent(
+                    ErrorKind::InstaLoginRequired(url.to_string()).into(),
+                ));
+            }
+            return Err(CallFailure::Transient(bad_status()));
+        }
+        500...599 => return Err(CallFailure::Transient(bad_status())),
+        200...299 => {}
+        _ => return Err(CallFailure::Permanent(bad_status())),
+    }
+
+    // Instagram responds a HTML page instead of JSON when a request is limited or blocked.
+    let is_html = res.content_type
+        .as_ref()
+        .map(|t| t.starts_with("text/html"))
+        .unwrap_or(false);
+    if is_html {
+        if is_login_wall(&*String::from_utf8_lossy(&res.body)) {
+            return Err(CallFailure::Permanent(
+                ErrorKind::InstaLoginRequired(url.to_string()).into(),
+            ));
+        }
+        return Err(CallFailure::Limited);
+    }
+
+    ::serde_json::from_slice::<D>(&res.body).map_err(|e| {
+        let e = ErrorKind::InstaSchemaMismatch(url.to_string(), e.to_string());
+        CallFailure::Permanent(e.into())
     })
 }
 
+// Exponential backoff with jitter.
+// Returns a random duration between a half of and full `base * 2^attempt`.
+fn backoff(base: Duration, attempt: u32) -> Duration {
+    let max = base * 2u32.pow(attempt.min(MAX_BACKOFF_EXP));
+    let max_millis = max.as_secs() * 1000 + max.subsec_millis() as u64;
+    Duration::from_millis(thread_rng().gen_range(max_millis / 2, max_millis + 1))
+}
+
 fn get_posts_by_hashtag(
+    config: &Arc<InstaApiConfig>,
+    limiter: &RateLimiter,
     hashtag: &Hashtag,
     max_id: Option<String>,
 ) -> impl Future<Item = InstaHashtagResponse, Error = Error> {
@@ -143,6 +417,7 @@ fn get_posts_by_hashtag(
         id: InstaPostId,
         #[serde(rename = "display_url")]
         image_url: String,
+        edge_media_to_caption: Option<Captions>,
     }
 
     fn parse_res(mut res: Response) -> InstaHashtagResponse {
@@ -165,6 +440,7 @@ fn get_posts_by_hashtag(
             .map(|edge| InstaPartialPost {
                 id: edge.node.id,
                 image_url: edge.node.image_url,
+                caption: edge.node.edge_media_to_caption.and_then(Captions::into_text),
             })
             .collect();
         InstaHashtagResponse {
@@ -180,20 +456,22 @@ fn get_posts_by_hashtag(
             percent_encode(hashtag.as_str().as_bytes(), DEFAULT_ENCODE_SET).to_string();
         let url_str = match max_id {
             Some(id) => format!(
-                "https://www.instagram.com/explore/tags/{}/?__a=1&max_id={}",
-                encoded_hashtag, id
+                "{}/explore/tags/{}/?__a=1&max_id={}",
+                config.base_url, encoded_hashtag, id
             ),
             None => format!(
-                "https://www.instagram.com/explore/tags/{}/?__a=1",
-                encoded_hashtag
+                "{}/explore/tags/{}/?__a=1",
+                config.base_url, encoded_hashtag
             ),
         };
         Uri::from_str(url_str.as_str()).unwrap()
     };
-    api_call(url, Duration::new(API_COOLING_SEC, 0)).map(parse_res)
+    api_call(config, limiter, url).map(parse_res)
 }
 
 pub fn get_post_by_id(
+    config: &Arc<InstaApiConfig>,
+    limiter: &RateLimiter,
     post_id: &InstaPostId,
 ) -> impl Future<Item = InstaPostResponse, Error = Error> {
     #[derive(Deserialize)]
@@ -208,26 +486,69 @@ pub fn get_post_by_id(
     struct Media {
         shortcode: InstaPostId,
         display_url: String,
+        #[serde(default)]
+        is_video: bool,
         owner: Owner,
+        edge_media_to_caption: Option<Captions>,
+        // Exists only if post is a carousel.
+        edge_sidecar_to_children: Option<Children>,
     }
     #[derive(Deserialize)]
     struct Owner {
         username: String,
     }
+    #[derive(Deserialize)]
+    struct Children {
+        edges: Vec<ChildEdge>,
+    }
+    #[derive(Deserialize)]
+    struct ChildEdge {
+        node: Child,
+    }
+    #[derive(Deserialize)]
+    struct Child {
+        display_url: String,
+        #[serde(default)]
+        is_video: bool,
+    }
 
+    // `display_url` of a video is its thumbnail.
     fn parse_res(res: Response) -> InstaPostResponse {
+        let media = res.graphql.shortcode_media;
+        let children = media
+            .edge_sidecar_to_children
+            .map(|c| c.edges)
+            .unwrap_or(Vec::new());
+        let images = if children.is_empty() {
+            vec![InstaImage {
+                id: media.shortcode.clone(),
+                image_url: media.display_url,
+                is_video: media.is_video,
+            }]
+        } else {
+            children
+                .into_iter()
+                .enumerate()
+                .map(|(i, edge)| InstaImage {
+                    id: media.shortcode.child(i),
+                    image_url: edge.node.display_url,
+                    is_video: edge.node.is_video,
+                })
+                .collect()
+        };
         InstaPostResponse {
-            id: res.graphql.shortcode_media.shortcode,
-            image_url: res.graphql.shortcode_media.display_url,
-            user_name: res.graphql.shortcode_media.owner.username,
+            id: media.shortcode,
+            user_name: media.owner.username,
+            caption: media.edge_media_to_caption.and_then(Captions::into_text),
+            images: images,
         }
     }
 
     let url = Uri::from_str(
-        format!("https://www.instagram.com/p/{}/?__a=1", post_id.as_str()).as_str(),
+        format!("{}/p/{}/?__a=1", config.base_url, post_id.as_str()).as_str(),
     ).unwrap();
 
-    api_call(url, Duration::new(API_COOLING_SEC, 0)).map(parse_res)
+    api_call(config, limiter, url).map(parse_res)
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -238,15 +559,287 @@ pub struct InstaHashtagResponse {
     pub has_next_page: bool,
 }
 
+/// A page of posts of a hashtag.
+#[derive(Debug, Clone)]
+pub struct InstaPage {
+    pub posts: Vec<InstaPartialPost>,
+    // Cursor which was used to request this page.
+    // `None` if this page can not be requested again by cursor.
+    pub cursor: Option<String>,
+}
+
+fn parse_page(res: InstaHashtagResponse, cursor: Option<String>) -> (InstaPage, Option<String>) {
+    let next = match res.has_next_page {
+        true => res.end_cursor,
+        false => None,
+    };
+    let page = InstaPage {
+        posts: res.posts,
+        cursor: cursor,
+    };
+    (page, next)
+}
+
+#[derive(Deserialize)]
+struct Captions {
+    edges: Vec<CaptionEdge>,
+}
+#[derive(Deserialize)]
+struct CaptionEdge {
+    node: Caption,
+}
+#[derive(Deserialize)]
+struct Caption {
+    text: String,
+}
+
+impl Captions {
+    // Only the first one is the caption written by the owner.
+    fn into_text(self) -> Option<String> {
+        self.edges.into_iter().next().map(|edge| edge.node.text)
+    }
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct InstaPartialPost {
     pub id: InstaPostId,
     pub image_url: String,
+    #[serde(default)]
+    pub caption: Option<String>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct InstaPostResponse {
     pub id: InstaPostId,
     pub user_name: String,
+    #[serde(default)]
+    pub caption: Option<String>,
+    // Each image of a carousel. A single image for other posts.
+    pub images: Vec<InstaImage>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct InstaImage {
+    pub id: InstaPostId,
     pub image_url: String,
+    // Image is the thumbnail of a video.
+    pub is_video: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use insta::mock::{post_id, MockInsta, MockOption};
+    use tokio::runtime::Runtime;
+
+    fn config(mock: &MockInsta) -> InstaApiConfig {
+        InstaApiConfig {
+            backend: InstaBackend::Web,
+            base_url: mock.base_url(),
+            user_agent: Some("bluumm-test".to_string()),
+            cookie: None,
+            rate_limit: RateLimitConfig {
+                rate: 1000.0,
+                burst: 10,
+                cooling: Duration::from_millis(10),
+            },
+            max_retries: 3,
+            retry_backoff: Duration::from_millis(10),
+            cassette: None,
+        }
+    }
+
+    #[test]
+    fn get_all_pages_of_hashtag_even_if_rate_limited() {
+        let mock = MockInsta::start(MockOption {
+            pages: 3,
+            posts_per_page: 2,
+            rate_limit_every: Some(2),
+            missing_image: None,
+        });
+        let api = InstaApi::new(config(&mock));
+        let pages = Runtime::new()
+            .unwrap()
+            .block_on(
+                api.get_bunch_pages_by_hashtag(&Hashtag::new("tokyo").unwrap(), None)
+                    .collect(),
+            )
+            .unwrap();
+
+        let ids: Vec<String> = pages
+            .into_iter()
+            .flat_map(|(_, page)| page.posts.into_iter().map(|p| p.id.0))
+            .collect();
+        let expected: Vec<String> = (0..3)
+            .flat_map(|page| (0..2).map(move |i| post_id(page, i)))
+            .collect();
+        assert_eq!(ids, expected);
+        // Requests for 2nd and 3rd page were rate limited once each.
+        assert_eq!(mock.requests().len(), 5);
+    }
+
+    #[test]
+    fn resume_paging_from_cursor() {
+        let mock = MockInsta::start(MockOption {
+            pages: 3,
+            posts_per_page: 1,
+            rate_limit_every: None,
+            missing_image: None,
+        });
+        let api = InstaApi::new(config(&mock));
+        let pages = Runtime::new()
+            .unwrap()
+            .block_on(
+                api.get_bunch_pages_by_hashtag(&Hashtag::new("tokyo").unwrap(), Some("1".into()))
+                    .collect(),
+            )
+            .unwrap();
+
+        let pages: Vec<(Option<String>, String)> = pages
+            .into_iter()
+            .map(|(_, page)| (page.cursor, page.posts[0].id.0.clone()))
+            .collect();
+        assert_eq!(
+            pages,
+            vec![
+                (Some("1".to_string()), post_id(1, 0)),
+                (Some("2".to_string()), post_id(2, 0)),
+            ]
+        );
+        assert_eq!(mock.requests().len(), 2);
+    }
+
+    fn raw(
+        status: u16,
+        content_type: Option<&str>,
+        location: Option<&str>,
+        body: &str,
+    ) -> RawResponse {
+        RawResponse {
+            status: status,
+            content_type: content_type.map(|s| s.to_string()),
+            location: location.map(|s| s.to_string()),
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn classify_responses() {
+        let check = |res: RawResponse| classify::<Vec<u32>>("url", &res);
+        assert_eq!(check(raw(200, None, None, "[1]")).unwrap(), vec![1]);
+        match check(raw(429, None, None, "")) {
+            Err(CallFailure::Limited) => {}
+            res => panic!("{:?}", res),
+        }
+        for status in &[400, 403] {
+            let body = r#"{ "error": { "message": "limit", "code": 613 } }"#;
+            match check(raw(*status, None, None, body)) {
+                Err(CallFailure::Limited) => {}
+                res => panic!("{:?}", res),
+            }
+        }
+        match check(raw(400, None, None, r#"{ "error": { "code": 100 } }"#)) {
+            Err(CallFailure::Permanent(_)) => {}
+            res => panic!("{:?}", res),
+        }
+        match check(raw(200, Some("text/html"), None, "<html></html>")) {
+            Err(CallFailure::Limited) => {}
+            res => panic!("{:?}", res),
+        }
+        match check(raw(503, None, None, "")) {
+            Err(CallFailure::Transient(_)) => {}
+            res => panic!("{:?}", res),
+        }
+        match check(raw(404, None, None, "{}")) {
+            Err(CallFailure::Permanent(Error(ErrorKind::InstaNotFound(_), _))) => {}
+            res => panic!("{:?}", res),
+        }
+        match check(raw(302, None, Some("https://www.instagram.com/accounts/login/"), "")) {
+            Err(CallFailure::Permanent(Error(ErrorKind::InstaLoginRequired(_), _))) => {}
+            res => panic!("{:?}", res),
+        }
+        match check(raw(200, None, None, "{}")) {
+            Err(CallFailure::Permanent(Error(ErrorKind::InstaSchemaMismatch(..), _))) => {}
+            res => panic!("{:?}", res),
+        }
+    }
+
+    #[test]
+    fn backoff_grows_exponentially() {
+        let base = Duration::from_millis(100);
+        for attempt in 0..4 {
+            let max = base * 2u32.pow(attempt);
+            let wait = backoff(base, attempt);
+            assert!(max / 2 <= wait && wait <= max);
+        }
+    }
+
+    #[test]
+    fn removed_post_is_not_retried() {
+        let mock = MockInsta::start(MockOption {
+            pages: 1,
+            posts_per_page: 1,
+            rate_limit_every: None,
+            missing_image: None,
+        });
+        let api = InstaApi::new(config(&mock));
+        let res = Runtime::new()
+            .unwrap()
+            .block_on(api.get_post_by_id(&InstaPostId("removed".to_string())));
+
+        match res {
+            Err(Error(ErrorKind::InstaNotFound(_), _)) => {}
+            res => panic!("{:?}", res),
+        }
+        assert_eq!(mock.requests().len(), 1);
+    }
+
+    #[test]
+    fn get_each_image_of_carousel() {
+        let mock = MockInsta::start(MockOption {
+            pages: 1,
+            posts_per_page: 2,
+            rate_limit_every: None,
+            missing_image: None,
+        });
+        let api = InstaApi::new(config(&mock));
+        let post = Runtime::new()
+            .unwrap()
+            .block_on(api.get_post_by_id(&InstaPostId(post_id(0, 1))))
+            .unwrap();
+
+        let images: Vec<(String, bool)> = post.images
+            .into_iter()
+            .map(|img| (img.id.0, img.is_video))
+            .collect();
+        assert_eq!(
+            images,
+            vec![
+                (post_id(0, 1), false),
+                (format!("{}:1", post_id(0, 1)), true),
+            ]
+        );
+    }
+
+    #[test]
+    fn get_post_with_configured_user_agent() {
+        let mock = MockInsta::start(MockOption {
+            pages: 1,
+            posts_per_page: 1,
+            rate_limit_every: None,
+            missing_image: None,
+        });
+        let api = InstaApi::new(config(&mock));
+        let post = Runtime::new()
+            .unwrap()
+            .block_on(api.get_post_by_id(&InstaPostId(post_id(0, 0))))
+            .unwrap();
+
+        assert_eq!(post.user_name, format!("user_{}", post_id(0, 0)));
+        assert_eq!(post.images.len(), 1);
+        let requests = mock.requests();
+        assert_eq!(requests[0].path_and_query, format!("/p/{}/?__a=1", post_id(0, 0)));
+        assert_eq!(requests[0].user_agent, Some("bluumm-test".to_string()));
+    }
 }
@@ -1,24 +1,71 @@
-use std::sync::Arc;
-use futures::{Future, IntoFuture, Stream, stream::iter_ok};
+use std::{collections::HashMap, sync::{Arc, Mutex}};
+use futures::{Future, IntoFuture, Stream, future::{self, Either},
+              stream::{empty, iter_ok, once, unfold}};
 
-use images::{ImageFetcher, size::Size};
-use insta::InstaApi;
-use post::{HashtagList, InstaPost};
-use db::Mongodb;
-use error::Error;
+use images::{ImageFetcher, QualityConfig, SizedImage, size::Size};
+use insta::{InstaApi, PageStream, api::InstaPartialPost};
+use post::{GenericPost, Hashtag, HashtagList, InstaPost, InstaPostId};
+use db::{InstaCursor, Mongodb};
+use config::Config;
+use error::{Error, ErrorKind};
+
+// Max number of pages read in a poll to catch up with posts posted while process stopped.
+const MAX_CATCH_UP_PAGES: usize = 10;
+
+#[derive(Debug, Clone)]
+pub struct InstaFeederConfig {
+    // Number of post metadata requested at the same time.
+    pub metadata_concurrency: usize,
+    // Number of images downloaded at the same time.
+    pub download_concurrency: usize,
+}
+
+impl Default for InstaFeederConfig {
+    fn default() -> InstaFeederConfig {
+        InstaFeederConfig {
+            metadata_concurrency: 1,
+            download_concurrency: 4,
+        }
+    }
+}
+
+// Progress of reading pages of a hashtag.
+// It is saved when every post before it has been stored,
+// so that posts being fetched are read again if process stops.
+#[derive(Debug, Clone, PartialEq)]
+enum Checkpoint {
+    // Cursor of the page whose posts follow.
+    BackfillCursor(Hashtag, String),
+    BackfillDone(Hashtag),
+    NewestId(Hashtag, InstaPostId),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Feed<T> {
+    Post(T),
+    Checkpoint(Checkpoint),
+}
+
+// Newest post of each hashtag which has been read by polling.
+// Saved newest id may fall behind this while posts are being fetched.
+type NewestIds = Arc<Mutex<HashMap<Hashtag, InstaPostId>>>;
 
 pub struct InstaFeeder {
     insta_api: Arc<InstaApi>,
     image_fetcher: Arc<ImageFetcher>,
     db: Mongodb,
+    config: InstaFeederConfig,
+    quality: Arc<QualityConfig>,
 }
 
 impl InstaFeeder {
-    pub fn new(db: Mongodb) -> InstaFeeder {
+    pub fn new(db: Mongodb, config: &Config, image_fetcher: Arc<ImageFetcher>) -> InstaFeeder {
         InstaFeeder {
-            insta_api: Arc::new(InstaApi::new()),
-            image_fetcher: Arc::new(ImageFetcher::new()),
+            insta_api: Arc::new(InstaApi::new(config.insta_api.clone())),
+            image_fetcher: image_fetcher,
             db: db,
+            config: config.insta_feeder.clone(),
+            quality: Arc::new(config.quality.clone()),
         }
     }
 
@@ -27,28 +74,14 @@ impl InstaFeeder {
         hashtags: &HashtagList,
     ) -> impl Stream<Item = InstaPost<SS>, Error = Error> {
         let insta_api = self.insta_api.clone();
-        let insta_api2 = self.insta_api.clone();
-        let image_fetcher = self.image_fetcher.clone();
         let db = self.db.clone();
-        let db2 = self.db.clone();
-
-        iter_ok::<_, Error>(hashtags.iter())
-            .map(move |hashtag| insta_api.get_bunch_posts_by_hashtag(&hashtag))
-            .flatten()
-            .filter(move |(_, p)| !db.contains_insta_post(&p.id))
-            .and_then(move |(hashtag, p)| {
-                info!("New post : {:?}", p);
-                insta_api2.get_post_by_id(&p.id).map(|post| (hashtag, post))
-            })
-            .and_then(move |(hashtag, p)| {
-                let db = db2.clone();
-                image_fetcher
-                    .fetch_image::<SS>(p.image_url.as_str())
-                    .into_future()
-                    .and_then(|img_fut| img_fut)
-                    .map(move |img| InstaPost::new(p.id, img, p.user_name, hashtag))
-                    .inspect(move |post| db.insert_one_insta_post(post))
+        let partial_posts = iter_ok::<_, Error>(hashtags.iter())
+            .map(move |hashtag| {
+                let cursor = db.find_insta_cursor(&hashtag);
+                backfill_posts(&insta_api, hashtag, cursor)
             })
+            .flatten();
+        self.complete_posts(partial_posts)
     }
 
     pub fn get_update_posts<SS: Size>(
@@ -56,27 +89,419 @@ impl InstaFeeder {
         hashtags: &HashtagList,
     ) -> impl Stream<Item = InstaPost<SS>, Error = Error> {
         let insta_api = self.insta_api.clone();
-        let insta_api2 = self.insta_api.clone();
+        let db = self.db.clone();
+        let newest_ids: NewestIds = Arc::new(Mutex::new(HashMap::new()));
+        let partial_posts = iter_ok::<_, Error>(hashtags.iter().cycle())
+            .map(move |hashtag| {
+                let polled = newest_ids.lock().unwrap().get(&hashtag).cloned();
+                let last_newest = polled.or_else(|| db.find_insta_cursor(&hashtag).newest_id);
+                new_posts(insta_api.clone(), hashtag, last_newest, newest_ids.clone())
+            })
+            .flatten();
+        self.complete_posts(partial_posts)
+    }
+
+    // Fetch metadata and image of each new post.
+    // Order of posts is preserved although requests are sent concurrently.
+    // Checkpoints are passed through with posts and saved when they come out.
+    fn complete_posts<SS, St>(
+        &self,
+        partial_posts: St,
+    ) -> impl Stream<Item = InstaPost<SS>, Error = Error>
+    where
+        SS: Size,
+        St: Stream<Item = Feed<(Hashtag, InstaPartialPost)>, Error = Error>,
+    {
+        let insta_api = self.insta_api.clone();
         let image_fetcher = self.image_fetcher.clone();
         let db = self.db.clone();
         let db2 = self.db.clone();
+        let db3 = self.db.clone();
+        let db4 = self.db.clone();
+        let db5 = self.db.clone();
+        let db6 = self.db.clone();
+        let quality = self.quality.clone();
 
-        iter_ok::<_, Error>(hashtags.iter().cycle())
-            .map(move |hashtag| insta_api.get_posts_by_hashtag(&hashtag))
+        partial_posts
+            .filter(move |f| match f {
+                &Feed::Post((_, ref p)) => {
+                    !db.contains_post("insta", p.id.as_str()) && !db3.is_blocked_insta_post(&p.id)
+                }
+                &Feed::Checkpoint(_) => true,
+            })
+            .map(move |f| match f {
+                Feed::Post((hashtag, p)) => {
+                    info!("New post : {:?}", p);
+                    let f = insta_api.complete_post(p).then(move |res| match res {
+                        Ok(post) => Ok(Some(Feed::Post((hashtag, post)))),
+                        Err(e) => {
+                            if !is_skippable(&e) {
+                                return Err(e);
+                            }
+                            warn!("Skip a post : {}", e);
+                            Ok(None)
+                        }
+                    });
+                    Either::A(f)
+                }
+                Feed::Checkpoint(c) => Either::B(future::ok(Some(Feed::Checkpoint(c)))),
+            })
+            .buffered(self.config.metadata_concurrency)
+            .filter_map(|f| f)
+            // Owner of a post is unknown until its metadata is fetched.
+            .filter(move |f| match f {
+                &Feed::Post((_, ref p)) => !db4.is_blocked_user(p.user_name.as_str()),
+                &Feed::Checkpoint(_) => true,
+            })
+            // Each image of a carousel becomes a post.
+            .map(|f| {
+                let feeds: Vec<_> = match f {
+                    Feed::Post((hashtag, p)) => {
+                        let user_name = p.user_name;
+                        let tags = p.caption
+                            .map(|caption| Hashtag::find_in(caption.as_str()))
+                            .unwrap_or(Vec::new());
+                        p.images
+                            .into_iter()
+                            .map(|img| {
+                                Feed::Post((hashtag.clone(), user_name.clone(), tags.clone(), img))
+                            })
+                            .collect()
+                    }
+                    Feed::Checkpoint(c) => vec![Feed::Checkpoint(c)],
+                };
+                iter_ok::<_, Error>(feeds)
+            })
             .flatten()
-            .filter(move |(_, p)| !db.contains_insta_post(&p.id))
-            .and_then(move |(hashtag, p)| {
-                info!("New post : {:?}", p);
-                insta_api2.get_post_by_id(&p.id).map(|post| (hashtag, post))
+            .filter(move |f| match f {
+                &Feed::Post((_, _, _, ref p)) => {
+                    !db5.contains_rejected_post("insta", p.id.as_str())
+                }
+                &Feed::Checkpoint(_) => true,
+            })
+            .map(move |f| match f {
+                Feed::Post((hashtag, user_name, tags, p)) => {
+                    let db = db2.clone();
+                    let quality = quality.clone();
+                    let is_video = p.is_video;
+                    let f = image_fetcher
+                        .fetch_original_image(p.image_url.as_str())
+                        .into_future()
+                        .and_then(|img_fut| img_fut)
+                        // Quality is checked on the original image before resized into a piece.
+                        .and_then(move |img| quality.check(&img).map(|()| img))
+                        // An image which can not be fetched or is low quality
+                        // does not stop feeding.
+                        .then(move |res| -> Result<_, Error> {
+                            match res {
+                                Ok(img) => {
+                                    let img = SizedImage::<SS>::with_resize(img);
+                                    let post = InstaPost::new(
+                                        p.id, img, user_name, hashtag, tags, is_video,
+                                    );
+                                    db.insert_post(
+                                        &GenericPost::InstaPost(post.clone()),
+                                        Some(p.image_url.as_str()),
+                                    );
+                                    Ok(Some(Feed::Post(post)))
+                                }
+                                Err(e) => {
+                                    info!("Reject a post {} : {}", p.id, e);
+                                    let reason = e.to_string();
+                                    let id = Some(p.id.as_str());
+                                    db.insert_rejected_post(
+                                        "insta", id, &user_name, &hashtag, &reason,
+                                    );
+                                    Ok(None)
+                                }
+                            }
+                        });
+                    Either::A(f)
+                }
+                Feed::Checkpoint(c) => Either::B(future::ok(Some(Feed::Checkpoint(c)))),
             })
-            .and_then(move |(hashtag, p)| {
-                let db = db2.clone();
-                image_fetcher
-                    .fetch_image::<SS>(p.image_url.as_str())
-                    .into_future()
-                    .and_then(|img_fut| img_fut)
-                    .map(move |img| InstaPost::new(p.id, img, p.user_name, hashtag))
-                    .inspect(move |post| db.insert_one_insta_post(post))
+            .buffered(self.config.download_concurrency)
+            .filter_map(move |f| match f {
+                Some(Feed::Post(post)) => Some(post),
+                Some(Feed::Checkpoint(c)) => {
+                    save_checkpoint(&db6, c);
+                    None
+                }
+                None => None,
+            })
+    }
+}
+
+// Reads pages from where the previous backfill of the hashtag stopped.
+fn backfill_posts(
+    insta_api: &InstaApi,
+    hashtag: Hashtag,
+    cursor: InstaCursor,
+) -> impl Stream<Item = Feed<(Hashtag, InstaPartialPost)>, Error = Error> {
+    let pages: PageStream = match cursor.backfill_done {
+        true => Box::new(empty()),
+        false => insta_api.get_bunch_pages_by_hashtag(&hashtag, cursor.backfill_cursor),
+    };
+    pages
+        // Cursor of a page is put before its posts so that the page is read again
+        // if process stops before all of its posts are stored.
+        .map(|(hashtag, page)| {
+            let hashtag2 = hashtag.clone();
+            let checkpoint = page.cursor
+                .map(move |cursor| Feed::Checkpoint(Checkpoint::BackfillCursor(hashtag2, cursor)));
+            let posts = page.posts
+                .into_iter()
+                .map(move |p| Feed::Post((hashtag.clone(), p)));
+            iter_ok::<_, Error>(checkpoint.into_iter().chain(posts))
+        })
+        .flatten()
+        .chain(once(Ok(Feed::Checkpoint(Checkpoint::BackfillDone(hashtag)))))
+}
+
+// Reads pages from the newest one until `last_newest` is found,
+// so that posts which were posted while process stopped are also read.
+fn new_posts(
+    insta_api: Arc<InstaApi>,
+    hashtag: Hashtag,
+    last_newest: Option<InstaPostId>,
+    newest_ids: NewestIds,
+) -> impl Stream<Item = Feed<(Hashtag, InstaPartialPost)>, Error = Error> {
+    // Only the newest page is read if the hashtag has never been polled.
+    let max_pages = match last_newest {
+        Some(_) => MAX_CATCH_UP_PAGES,
+        None => 1,
+    };
+    let newest = Arc::new(Mutex::new(None));
+    let newest2 = newest.clone();
+    let hashtag2 = hashtag.clone();
+    let hashtag3 = hashtag.clone();
+
+    let pages = unfold((None, 0, true), move |(cursor, n, has_next)| {
+        if has_next == false || n >= max_pages {
+            return None;
+        }
+        let last_newest = last_newest.clone();
+        let newest = newest2.clone();
+        let f = insta_api
+            .get_page_by_hashtag(&hashtag, cursor)
+            .map(move |(page, next)| {
+                let mut posts = page.posts;
+                if n == 0 {
+                    *newest.lock().unwrap() = posts.first().map(|p| p.id.clone());
+                }
+                let found = posts.iter().position(|p| Some(&p.id) == last_newest.as_ref());
+                if let Some(pos) = found {
+                    posts.truncate(pos);
+                }
+                let has_next = found.is_none() && next.is_some();
+                (posts, (next, n + 1, has_next))
+            });
+        Some(f)
+    });
+
+    pages
+        .map(move |posts| {
+            let hashtag = hashtag3.clone();
+            let posts = posts.into_iter().map(move |p| Feed::Post((hashtag.clone(), p)));
+            iter_ok::<_, Error>(posts)
+        })
+        .flatten()
+        // The next poll starts from the newest post at once,
+        // but it is saved after all of the posts are stored.
+        .chain(
+            future::lazy(move || {
+                let checkpoint = newest.lock().unwrap().take().map(|id| {
+                    newest_ids.lock().unwrap().insert(hashtag2.clone(), id.clone());
+                    Feed::Checkpoint(Checkpoint::NewestId(hashtag2, id))
+                });
+                Ok(iter_ok::<_, Error>(checkpoint))
+            }).flatten_stream(),
+        )
+}
+
+fn save_checkpoint(db: &Mongodb, checkpoint: Checkpoint) {
+    match checkpoint {
+        Checkpoint::BackfillCursor(hashtag, cursor) => {
+            db.save_insta_backfill_cursor(&hashtag, cursor.as_str())
+        }
+        Checkpoint::BackfillDone(hashtag) => db.finish_insta_backfill(&hashtag),
+        Checkpoint::NewestId(hashtag, id) => db.save_insta_newest_id(&hashtag, &id),
+    }
+}
+
+// A post which is removed after it was listed does not stop feeding.
+fn is_skippable(e: &Error) -> bool {
+    match e.kind() {
+        &ErrorKind::InstaNotFound(_) | &ErrorKind::InstaSchemaMismatch(..) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use insta::{InstaApiConfig, InstaBackend, RateLimitConfig,
+                mock::{post_id, MockInsta, MockOption}};
+    use tokio::runtime::Runtime;
+    use images::{ImageFetcherConfig, size::Size30x30};
+    use db::test_db;
+
+    fn insta_api(mock: &MockInsta) -> Arc<InstaApi> {
+        Arc::new(InstaApi::new(InstaApiConfig {
+            backend: InstaBackend::Web,
+            base_url: mock.base_url(),
+            user_agent: None,
+            cookie: None,
+            rate_limit: RateLimitConfig {
+                rate: 1000.0,
+                burst: 10,
+                cooling: Duration::from_millis(10),
+            },
+            max_retries: 0,
+            retry_backoff: Duration::from_millis(10),
+            cassette: None,
+        }))
+    }
+
+    // Posts are replaced with their ids.
+    fn collect<St>(feeds: St) -> Vec<Feed<String>>
+    where
+        St: Stream<Item = Feed<(Hashtag, InstaPartialPost)>, Error = Error> + Send + 'static,
+    {
+        Runtime::new()
+            .unwrap()
+            .block_on(feeds.collect())
+            .unwrap()
+            .into_iter()
+            .map(|f| match f {
+                Feed::Post((_, p)) => Feed::Post(p.id.0),
+                Feed::Checkpoint(c) => Feed::Checkpoint(c),
             })
+            .collect()
+    }
+
+    fn mock(pages: usize, posts_per_page: usize) -> MockInsta {
+        MockInsta::start(MockOption {
+            pages: pages,
+            posts_per_page: posts_per_page,
+            rate_limit_every: None,
+            missing_image: None,
+        })
+    }
+
+    #[test]
+    #[ignore] // requires MongoDB
+    fn reject_post_whose_image_is_not_found() {
+        let mock = MockInsta::start(MockOption {
+            pages: 2,
+            posts_per_page: 1,
+            rate_limit_every: None,
+            missing_image: Some(post_id(0, 0)),
+        });
+        let db = test_db("feeder_missing_image");
+        let feeder = InstaFeeder {
+            insta_api: insta_api(&mock),
+            image_fetcher: Arc::new(ImageFetcher::new(
+                ImageFetcherConfig {
+                    max_retries: 0,
+                    ..ImageFetcherConfig::default()
+                },
+                None,
+            )),
+            db: db.clone(),
+            config: InstaFeederConfig::default(),
+            quality: Arc::new(QualityConfig {
+                min_width: 0,
+                min_height: 0,
+                min_luminance_variance: 0.0,
+                max_single_color_fraction: 1.0,
+            }),
+        };
+        let hashtag = Hashtag::new("tokyo").unwrap();
+        let hashtags = HashtagList::new(vec!["tokyo".into()]).unwrap();
+        let posts = Runtime::new()
+            .unwrap()
+            .block_on(feeder.get_bunch_of_posts::<Size30x30>(&hashtags).collect())
+            .unwrap();
+
+        let ids: Vec<_> = posts.iter().map(|p| p.post_id.0.clone()).collect();
+        assert_eq!(ids, vec![post_id(1, 0)]);
+        assert!(db.contains_rejected_post("insta", post_id(0, 0).as_str()));
+        // Checkpoints after the rejected post are saved.
+        assert!(db.find_insta_cursor(&hashtag).backfill_done);
+    }
+
+    #[test]
+    fn read_new_posts_until_last_newest() {
+        let mock = mock(3, 2);
+        let hashtag = Hashtag::new("tokyo").unwrap();
+        let newest_ids: NewestIds = Arc::new(Mutex::new(HashMap::new()));
+        let last_newest = Some(InstaPostId(post_id(1, 1)));
+        let feeds = collect(new_posts(
+            insta_api(&mock),
+            hashtag.clone(),
+            last_newest,
+            newest_ids.clone(),
+        ));
+
+        let newest = InstaPostId(post_id(0, 0));
+        assert_eq!(
+            feeds,
+            vec![
+                Feed::Post(post_id(0, 0)),
+                Feed::Post(post_id(0, 1)),
+                Feed::Post(post_id(1, 0)),
+                // Saved after the posts before it are stored.
+                Feed::Checkpoint(Checkpoint::NewestId(hashtag.clone(), newest.clone())),
+            ]
+        );
+        // The next poll does not wait for the checkpoint to be saved.
+        assert_eq!(newest_ids.lock().unwrap().get(&hashtag), Some(&newest));
+        assert_eq!(mock.requests().len(), 2);
+    }
+
+    #[test]
+    fn read_only_newest_page_at_first_poll() {
+        let mock = mock(3, 2);
+        let hashtag = Hashtag::new("tokyo").unwrap();
+        let newest_ids: NewestIds = Arc::new(Mutex::new(HashMap::new()));
+        let feeds = collect(new_posts(insta_api(&mock), hashtag.clone(), None, newest_ids));
+
+        let newest = InstaPostId(post_id(0, 0));
+        assert_eq!(
+            feeds,
+            vec![
+                Feed::Post(post_id(0, 0)),
+                Feed::Post(post_id(0, 1)),
+                Feed::Checkpoint(Checkpoint::NewestId(hashtag, newest)),
+            ]
+        );
+        assert_eq!(mock.requests().len(), 1);
+    }
+
+    #[test]
+    fn put_backfill_cursor_before_posts_of_page() {
+        let mock = mock(3, 1);
+        let hashtag = Hashtag::new("tokyo").unwrap();
+        let feeds = collect(backfill_posts(
+            &insta_api(&mock),
+            hashtag.clone(),
+            InstaCursor::default(),
+        ));
+
+        let cursor =
+            |c: &str| Feed::Checkpoint(Checkpoint::BackfillCursor(hashtag.clone(), c.into()));
+        assert_eq!(
+            feeds,
+            vec![
+                Feed::Post(post_id(0, 0)),
+                cursor("1"),
+                Feed::Post(post_id(1, 0)),
+                cursor("2"),
+                Feed::Post(post_id(2, 0)),
+                Feed::Checkpoint(Checkpoint::BackfillDone(hashtag.clone())),
+            ]
+        );
     }
 }
@@ -0,0 +1,360 @@
+use std::{collections::{HashMap, HashSet}, str::FromStr, sync::{Arc, Mutex}};
+use futures::{Future, Stream, future::{self, Either}, stream::{empty, unfold}};
+use hyper::Uri;
+use percent_encoding::{utf8_percent_encode, QUERY_ENCODE_SET};
+
+use insta::{RateLimiter, api::{api_call, InstaApiConfig, InstaPage, InstaPartialPost}};
+use post::{Hashtag, InstaPostId};
+use error::{Error, ErrorKind};
+
+const MEDIA_FIELDS: &str = "id,caption,media_type,media_url,permalink";
+// Error codes of Graph API which mean that calls are throttled.
+pub const THROTTLE_ERROR_CODES: &[u32] = &[4, 17, 32, 613];
+
+/// Default rate of Graph API calls.
+/// Graph API allows 200 calls per hour for each user of an app.
+pub const DEFAULT_RATE: f64 = 200.0 / 3600.0;
+
+#[derive(Debug, Clone)]
+pub struct GraphApiConfig {
+    // e.g. "https://graph.facebook.com/v3.1"
+    pub base_url: String,
+    pub access_token: String,
+    // Instagram business account which performs hashtag search.
+    pub user_id: String,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum MediaEdge {
+    Recent,
+    Top,
+}
+
+impl MediaEdge {
+    fn as_str(&self) -> &'static str {
+        match self {
+            &MediaEdge::Recent => "recent_media",
+            &MediaEdge::Top => "top_media",
+        }
+    }
+}
+
+/// Instagram Graph API backend of `InstaApi`.
+///
+/// A business account can search only 30 unique hashtags in 7 days,
+/// so id of each hashtag is searched once and kept.
+pub struct GraphApi {
+    api_config: Arc<InstaApiConfig>,
+    limiter: RateLimiter,
+    config: Arc<GraphApiConfig>,
+    hashtag_ids: Arc<Mutex<HashMap<Hashtag, String>>>,
+}
+
+impl GraphApi {
+    pub fn new(
+        api_config: Arc<InstaApiConfig>,
+        limiter: RateLimiter,
+        config: GraphApiConfig,
+    ) -> GraphApi {
+        GraphApi {
+            api_config: api_config,
+            limiter: limiter,
+            config: Arc::new(config),
+            hashtag_ids: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // Returns a page of recent media from `after` and cursor of the next page.
+    pub fn get_page_by_hashtag(
+        &self,
+        hashtag: &Hashtag,
+        after: Option<String>,
+    ) -> impl Future<Item = (InstaPage, Option<String>), Error = Error> {
+        let (api_config, config) = (self.api_config.clone(), self.config.clone());
+        let limiter = self.limiter.clone();
+        self.hashtag_id(hashtag).and_then(move |id| {
+            get_media(&api_config, &limiter, &config, &id, MediaEdge::Recent, after.clone())
+                .map(move |(posts, next)| {
+                    let page = InstaPage {
+                        posts: posts,
+                        cursor: after,
+                    };
+                    (page, next)
+                })
+        })
+    }
+
+    // Returns all pages of top media and then all pages of recent media.
+    // Top media is skipped if paging resumes from `after`.
+    pub fn get_bunch_pages_by_hashtag(
+        &self,
+        hashtag: &Hashtag,
+        after: Option<String>,
+    ) -> impl Stream<Item = (Hashtag, InstaPage), Error = Error> {
+        let (api_config, config) = (self.api_config.clone(), self.config.clone());
+        let limiter = self.limiter.clone();
+        let hashtag2 = hashtag.clone();
+        let mut seen = HashSet::new();
+        self.hashtag_id(hashtag)
+            .map(move |id| {
+                let top: Box<Stream<Item = InstaPage, Error = Error> + Send> = match after {
+                    // Paging can not resume into top media.
+                    None => Box::new(
+                        all_media(
+                            api_config.clone(),
+                            limiter.clone(),
+                            config.clone(),
+                            id.clone(),
+                            MediaEdge::Top,
+                            None,
+                        ).map(|page| InstaPage {
+                            cursor: None,
+                            ..page
+                        }),
+                    ),
+                    Some(_) => Box::new(empty()),
+                };
+                let recent = all_media(api_config, limiter, config, id, MediaEdge::Recent, after);
+                top.chain(recent)
+            })
+            .flatten_stream()
+            // A popular post may be in both of top media and recent media.
+            .map(move |mut page| {
+                page.posts.retain(|post| seen.insert(post.id.clone()));
+                page
+            })
+            .map(move |page| (hashtag2.clone(), page))
+    }
+
+    fn hashtag_id(&self, hashtag: &Hashtag) -> impl Future<Item = String, Error = Error> {
+        if let Some(id) = self.hashtag_ids.lock().unwrap().get(hashtag) {
+            return Either::A(future::ok(id.clone()));
+        }
+
+        #[derive(Deserialize)]
+        struct Response {
+            data: Vec<Node>,
+        }
+        #[derive(Deserialize)]
+        struct Node {
+            id: String,
+        }
+
+        let url = format!(
+            "{}/ig_hashtag_search?user_id={}&q={}&access_token={}",
+            self.config.base_url,
+            self.config.user_id,
+            utf8_percent_encode(hashtag.as_str(), QUERY_ENCODE_SET),
+            self.config.access_token
+        );
+        let api_config = self.api_config.clone();
+        let limiter = self.limiter.clone();
+        let hashtag_ids = self.hashtag_ids.clone();
+        let hashtag = hashtag.clone();
+        let f = future::result(Uri::from_str(url.as_str()).map_err(Error::from))
+            .and_then(move |url| api_call::<Response>(&api_config, &limiter, url))
+            .and_then(move |res| -> Result<String, Error> {
+                let id = match res.data.into_iter().next() {
+                    Some(node) => node.id,
+                    None => bail!(ErrorKind::GraphHashtagNotFound(hashtag.as_str().into())),
+                };
+                hashtag_ids.lock().unwrap().insert(hashtag, id.clone());
+                Ok(id)
+            });
+        Either::B(f)
+    }
+}
+
+fn all_media(
+    api_config: Arc<InstaApiConfig>,
+    limiter: RateLimiter,
+    config: Arc<GraphApiConfig>,
+    hashtag_id: String,
+    edge: MediaEdge,
+    after: Option<String>,
+) -> impl Stream<Item = InstaPage, Error = Error> {
+    unfold((after, true), move |(after, has_next)| {
+        if has_next == false {
+            None
+        } else {
+            let res = get_media(&api_config, &limiter, &config, &hashtag_id, edge, after.clone());
+            Some(res.map(move |(posts, next)| {
+                let has_next = next.is_some();
+                let page = InstaPage {
+                    posts: posts,
+                    cursor: after,
+                };
+                (page, (next, has_next))
+            }))
+        }
+    })
+}
+
+// Returns posts and cursor of the next page.
+fn get_media(
+    api_config: &Arc<InstaApiConfig>,
+    limiter: &RateLimiter,
+    config: &Arc<GraphApiConfig>,
+    hashtag_id: &str,
+    edge: MediaEdge,
+    after: Option<String>,
+) -> impl Future<Item = (Vec<InstaPartialPost>, Option<String>), Error = Error> {
+    #[derive(Deserialize)]
+    struct Response {
+        data: Vec<Media>,
+        paging: Option<Paging>,
+    }
+    #[derive(Deserialize)]
+    struct Paging {
+        cursors: Option<Cursors>,
+        // Exists only if there is a next page.
+        next: Option<String>,
+    }
+    #[derive(Deserialize)]
+    struct Cursors {
+        after: Option<String>,
+    }
+    #[derive(Deserialize)]
+    struct Media {
+        id: String,
+        caption: Option<String>,
+        media_type: String,
+        media_url: Option<String>,
+        permalink: Option<String>,
+    }
+
+    fn parse_res(res: Response) -> (Vec<InstaPartialPost>, Option<String>) {
+        let after = res.paging.and_then(|paging| match paging.next {
+            Some(_) => paging.cursors.and_then(|c| c.after),
+            None => None,
+        });
+        let posts = res.data
+            .into_iter()
+            // Videos and carousel albums are not supported yet.
+            .filter(|media| media.media_type == "IMAGE")
+            .filter_map(|media| {
+                let id = media
+                    .permalink
+                    .as_ref()
+                    .and_then(|p| shortcode_from_permalink(p.as_str()))
+                    .unwrap_or(media.id);
+                let caption = media.caption;
+                media.media_url.map(|url| InstaPartialPost {
+                    id: InstaPostId(id),
+                    image_url: url,
+                    caption: caption,
+                })
+            })
+            .collect();
+        (posts, after)
+    }
+
+    let mut url = format!(
+        "{}/{}/{}?user_id={}&fields={}&access_token={}",
+        config.base_url,
+        hashtag_id,
+        edge.as_str(),
+        config.user_id,
+        MEDIA_FIELDS,
+        config.access_token
+    );
+    if let Some(after) = after {
+        url.push_str(format!("&after={}", after).as_str());
+    }
+
+    let api_config = api_config.clone();
+    let limiter = limiter.clone();
+    future::result(Uri::from_str(url.as_str()).map_err(Error::from))
+        .and_then(move |url| api_call(&api_config, &limiter, url))
+        .map(parse_res)
+}
+
+// Shortcode is used as id of post so that the same post has the same id on both backends.
+// e.g. "https://www.instagram.com/p/BsOGulcndj-/" -> "BsOGulcndj-"
+fn shortcode_from_permalink(permalink: &str) -> Option<String> {
+    let mut segments = permalink.split('/').skip_while(|s| *s != "p");
+    segments.next()?;
+    segments
+        .next()
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use insta::{InstaApi, InstaBackend, RateLimitConfig,
+                mock::{post_id, MockInsta, MockOption}};
+    use tokio::runtime::Runtime;
+
+    fn config(mock: &MockInsta) -> InstaApiConfig {
+        InstaApiConfig {
+            backend: InstaBackend::Graph(GraphApiConfig {
+                base_url: mock.base_url(),
+                access_token: "token".to_string(),
+                user_id: "1234".to_string(),
+            }),
+            rate_limit: RateLimitConfig {
+                rate: 1000.0,
+                burst: 10,
+                cooling: Duration::from_millis(10),
+            },
+            ..InstaApiConfig::default()
+        }
+    }
+
+    #[test]
+    fn extract_shortcode_from_permalink() {
+        assert_eq!(
+            shortcode_from_permalink("https://www.instagram.com/p/BsOGulcndj-/"),
+            Some("BsOGulcndj-".to_string())
+        );
+        assert_eq!(shortcode_from_permalink("https://www.instagram.com/"), None);
+    }
+
+    #[test]
+    fn get_all_media_of_hashtag() {
+        let mock = MockInsta::start(MockOption {
+            pages: 3,
+            posts_per_page: 2,
+            rate_limit_every: None,
+            missing_image: None,
+        });
+        let api = InstaApi::new(config(&mock));
+        let mut runtime = Runtime::new().unwrap();
+        let pages = runtime
+            .block_on(
+                api.get_bunch_pages_by_hashtag(&Hashtag::new("tokyo").unwrap(), None)
+                    .collect(),
+            )
+            .unwrap();
+        let (update, next) = runtime
+            .block_on(api.get_page_by_hashtag(&Hashtag::new("tokyo").unwrap(), None))
+            .unwrap();
+
+        // Top media is the first page of recent media and videos are skipped.
+        let ids: Vec<String> = pages
+            .iter()
+            .flat_map(|(_, page)| page.posts.iter().map(|p| p.id.0.clone()))
+            .collect();
+        let expected: Vec<String> = (0..3)
+            .flat_map(|page| (0..2).map(move |i| post_id(page, i)))
+            .collect();
+        assert_eq!(ids, expected);
+        // Cursors of recent media are kept to resume paging.
+        let cursors: Vec<Option<String>> = pages.into_iter().map(|(_, p)| p.cursor).collect();
+        let expected: Vec<Option<String>> =
+            vec![None, None, Some("1".into()), Some("2".into())];
+        assert_eq!(cursors, expected);
+        assert_eq!(update.posts.len(), 2);
+        assert_eq!(next, Some("1".to_string()));
+
+        // Hashtag is searched only once.
+        let searches = mock.requests()
+            .into_iter()
+            .filter(|r| r.path_and_query.starts_with("/ig_hashtag_search"))
+            .count();
+        assert_eq!(searches, 1);
+    }
+}
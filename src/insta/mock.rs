@@ -0,0 +1,253 @@
+//! Local stand-in of Instagram web API and Graph API for tests.
+//! It serves canned hashtag pages, post JSON and rate limit responses.
+
+use std::{net::SocketAddr, sync::{Arc, Mutex, atomic::{AtomicUsize, Ordering}}, thread};
+use futures::{Future, sync::oneshot};
+use hyper::{Body, Request, Response, Server, StatusCode, header::{HOST, USER_AGENT},
+            service::service_fn_ok};
+
+use images::Image;
+
+#[derive(Debug, Clone)]
+pub struct MockOption {
+    pub pages: usize,
+    pub posts_per_page: usize,
+    // Every n-th request is responded as rate limited.
+    pub rate_limit_every: Option<usize>,
+    // Image of this post is responded as not found.
+    pub missing_image: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+    pub path_and_query: String,
+    pub user_agent: Option<String>,
+}
+
+pub struct MockInsta {
+    pub addr: SocketAddr,
+    state: Arc<MockState>,
+    shutdown_tx: Option<oneshot::Sender<()>>,
+}
+
+struct MockState {
+    option: MockOption,
+    count: AtomicUsize,
+    requests: Mutex<Vec<RecordedRequest>>,
+}
+
+impl MockInsta {
+    pub fn start(option: MockOption) -> MockInsta {
+        let state = Arc::new(MockState {
+            option: option,
+            count: AtomicUsize::new(0),
+            requests: Mutex::new(Vec::new()),
+        });
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let (addr_tx, addr_rx) = ::std::sync::mpsc::channel();
+
+        let state2 = state.clone();
+        thread::spawn(move || {
+            let addr = ([127, 0, 0, 1], 0).into();
+            let server = Server::bind(&addr).serve(move || {
+                let state = state2.clone();
+                service_fn_ok(move |req| state.handle(req))
+            });
+            addr_tx.send(server.local_addr()).unwrap();
+            let work = server
+                .map_err(|e| error!("Mock server error : {:?}", e))
+                .select(shutdown_rx.map_err(|_| ()))
+                .map(|_| ())
+                .map_err(|_| ());
+            ::tokio::run(work);
+        });
+
+        MockInsta {
+            addr: addr_rx.recv().unwrap(),
+            state: state,
+            shutdown_tx: Some(shutdown_tx),
+        }
+    }
+
+    pub fn base_url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.state.requests.lock().unwrap().clone()
+    }
+}
+
+impl Drop for MockInsta {
+    fn drop(&mut self) {
+        if let Some(tx) = self.shutdown_tx.take() {
+            let _ = tx.send(());
+        }
+    }
+}
+
+impl MockState {
+    fn handle(&self, req: Request<Body>) -> Response<Body> {
+        let path = req.uri().path().to_string();
+        let query = req.uri().query().unwrap_or("").to_string();
+        let host = req.headers()
+            .get(HOST)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("")
+            .to_string();
+        self.requests.lock().unwrap().push(RecordedRequest {
+            path_and_query: format!("{}?{}", path, query),
+            user_agent: req.headers()
+                .get(USER_AGENT)
+                .and_then(|v| v.to_str().ok())
+                .map(|s| s.to_string()),
+        });
+
+        let n = self.count.fetch_add(1, Ordering::SeqCst) + 1;
+        if let Some(every) = self.option.rate_limit_every {
+            if n % every == 0 {
+                return response(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    "<html>Please wait a few minutes before you try again.</html>".into(),
+                );
+            }
+        }
+
+        let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        match segments.as_slice() {
+            ["explore", "tags", hashtag] => {
+                let page = query_param(query.as_str(), "max_id")
+                    .and_then(|s| s.parse::<usize>().ok())
+                    .unwrap_or(0);
+                response(StatusCode::OK, self.hashtag_page(&host, hashtag, page))
+            }
+            ["ig_hashtag_search"] => {
+                let q = query_param(query.as_str(), "q").unwrap_or("");
+                response(StatusCode::OK, format!(r#"{{"data":[{{"id":"h_{}"}}]}}"#, q))
+            }
+            [_, "recent_media"] => {
+                let page = query_param(query.as_str(), "after")
+                    .and_then(|s| s.parse::<usize>().ok())
+                    .unwrap_or(0);
+                response(StatusCode::OK, self.graph_media_page(page, true))
+            }
+            // Top media is the first page of recent media.
+            [_, "top_media"] => response(StatusCode::OK, self.graph_media_page(0, false)),
+            ["p", id] => match self.post(&host, id) {
+                Some(body) => response(StatusCode::OK, body),
+                None => response(StatusCode::NOT_FOUND, "{}".into()),
+            },
+            _ => response(StatusCode::NOT_FOUND, "".into()),
+        }
+    }
+
+    fn hashtag_page(&self, host: &str, hashtag: &str, page: usize) -> String {
+        let edges: Vec<String> = (0..self.option.posts_per_page)
+            .map(|i| {
+                format!(
+                    r#"{{"node":{{"shortcode":"{}","display_url":"{}"}}}}"#,
+                    post_id(page, i),
+                    self.image_url(host, &post_id(page, i))
+                )
+            })
+            .collect();
+        let has_next_page = page + 1 < self.option.pages;
+        let end_cursor = match has_next_page {
+            true => format!(r#""{}""#, page + 1),
+            false => "null".into(),
+        };
+        format!(
+            r#"{{"graphql":{{"hashtag":{{"name":"{}","edge_hashtag_to_media":{{"edges":[{}],"page_info":{{"end_cursor":{},"has_next_page":{}}}}}}}}}}}"#,
+            hashtag,
+            edges.join(","),
+            end_cursor,
+            has_next_page
+        )
+    }
+
+    fn graph_media_page(&self, page: usize, has_paging: bool) -> String {
+        let mut media: Vec<String> = (0..self.option.posts_per_page)
+            .map(|i| {
+                format!(
+                    r#"{{"id":"g{}n{}","media_type":"IMAGE","media_url":"{}","permalink":"https://www.instagram.com/p/{}/"}}"#,
+                    page,
+                    i,
+                    image_url(),
+                    post_id(page, i)
+                )
+            })
+            .collect();
+        media.push(format!(
+            r#"{{"id":"v{}","media_type":"VIDEO","media_url":"https://example.com/v{}.mp4","permalink":"https://www.instagram.com/p/v{}/"}}"#,
+            page, page, page
+        ));
+        let paging = match has_paging && page + 1 < self.option.pages {
+            true => format!(
+                r#","paging":{{"cursors":{{"after":"{}"}},"next":"https://example.com/next"}}"#,
+                page + 1
+            ),
+            false => "".into(),
+        };
+        format!(r#"{{"data":[{}]{}}}"#, media.join(","), paging)
+    }
+
+    // The second post of each page is a carousel of an image and a video.
+    fn post(&self, host: &str, id: &str) -> Option<String> {
+        let index = (0..self.option.pages)
+            .flat_map(|page| (0..self.option.posts_per_page).map(move |i| (page, i)))
+            .find(|&(page, i)| post_id(page, i) == id)
+            .map(|(_, i)| i)?;
+        let children = match index {
+            1 => format!(
+                r#","edge_sidecar_to_children":{{"edges":[{{"node":{{"display_url":"{}","is_video":false}}}},{{"node":{{"display_url":"{}","is_video":true}}}}]}}"#,
+                image_url(),
+                image_url()
+            ),
+            _ => "".into(),
+        };
+        Some(format!(
+            r#"{{"graphql":{{"shortcode_media":{{"shortcode":"{}","display_url":"{}","is_video":false,"owner":{{"username":"user_{}"}}{}}}}}}}"#,
+            id,
+            self.image_url(host, id),
+            id,
+            children
+        ))
+    }
+
+    fn image_url(&self, host: &str, id: &str) -> String {
+        match self.option.missing_image.as_ref().map(|s| s.as_str()) {
+            Some(missing) if missing == id => format!("http://{}/images/{}.png", host, id),
+            _ => image_url(),
+        }
+    }
+}
+
+fn query_param<'a>(query: &'a str, key: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|kv| {
+            let mut kv = kv.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some(k), Some(v)) if k == key => Some(v),
+                _ => None,
+            }
+        })
+        .next()
+}
+
+pub fn post_id(page: usize, i: usize) -> String {
+    format!("p{}n{}", page, i)
+}
+
+// Images are served as data URI so that no image server is needed.
+fn image_url() -> String {
+    let png = Image::clear_image(2, 2).to_png_bytes();
+    format!("data:image/png;base64,{}", ::base64::encode(png.as_slice()))
+}
+
+fn response(status: StatusCode, body: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(body))
+        .unwrap()
+}
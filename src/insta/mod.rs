@@ -1,5 +1,14 @@
 pub mod feeder;
 pub mod api;
+pub mod graph;
+pub mod poller;
+pub mod rate_limit;
+#[cfg(test)]
+pub mod mock;
 
-pub use self::feeder::InstaFeeder;
-pub use self::api::{InstaApi, InstaHashtagResponse, InstaPostResponse};
+pub use self::feeder::{InstaFeeder, InstaFeederConfig};
+pub use self::api::{InstaApi, InstaApiConfig, InstaBackend, InstaHashtagResponse, InstaImage,
+                    InstaPage, InstaPostResponse, PageFuture, PageStream};
+pub use self::graph::{GraphApi, GraphApiConfig};
+pub use self::poller::{HashtagPollers, PollStream, Subscription};
+pub use self::rate_limit::{RateLimitConfig, RateLimiter};
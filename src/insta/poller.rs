@@ -0,0 +1,196 @@
+use std::{collections::HashMap, thread, sync::{Arc, Mutex, atomic::{AtomicUsize, Ordering}},
+          time::{Duration, Instant}};
+use futures::{Future, Poll, Stream, future::{loop_fn, Loop},
+              sync::{mpsc::{self, UnboundedReceiver, UnboundedSender}, oneshot}};
+use tokio::timer::Delay;
+
+use post::Hashtag;
+use error::Error;
+
+// Wait before polling a hashtag again after polling fails.
+const RETRY_INTERVAL_SEC: u64 = 10;
+
+pub type PollStream<T> = Box<Stream<Item = T, Error = Error> + Send>;
+
+type PollFn<T> = Fn(&Hashtag) -> PollStream<T> + Send + Sync;
+type Subscribers<T> = Arc<Mutex<HashMap<usize, UnboundedSender<T>>>>;
+
+/// Runs at most one poller for each hashtag over the process
+/// and fans every polled item out to all subscribers of the hashtag.
+///
+/// A poller starts on the first subscription of its hashtag
+/// and stops when the last subscription is dropped.
+pub struct HashtagPollers<T> {
+    poll: Arc<PollFn<T>>,
+    pollers: Arc<Mutex<HashMap<Hashtag, Poller<T>>>>,
+    next_id: AtomicUsize,
+}
+
+struct Poller<T> {
+    subscribers: Subscribers<T>,
+    shutdown_tx: oneshot::Sender<()>,
+}
+
+impl<T: Clone + Send + 'static> HashtagPollers<T> {
+    /// `poll` creates a stream of new items of a hashtag. It is called again if the stream fails.
+    pub fn new<F>(poll: F) -> HashtagPollers<T>
+    where
+        F: Fn(&Hashtag) -> PollStream<T> + Send + Sync + 'static,
+    {
+        HashtagPollers {
+            poll: Arc::new(poll),
+            pollers: Arc::new(Mutex::new(HashMap::new())),
+            next_id: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn subscribe(&self, hashtag: &Hashtag) -> Subscription<T> {
+        let (tx, rx) = mpsc::unbounded();
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let mut pollers = self.pollers.lock().unwrap();
+        let poll = &self.poll;
+        pollers
+            .entry(hashtag.clone())
+            .or_insert_with(|| start_poller(hashtag.clone(), poll.clone()))
+            .subscribers
+            .lock()
+            .unwrap()
+            .insert(id, tx);
+        Subscription {
+            rx: rx,
+            id: id,
+            hashtag: hashtag.clone(),
+            pollers: self.pollers.clone(),
+        }
+    }
+
+    /// Number of running pollers.
+    pub fn len(&self) -> usize {
+        self.pollers.lock().unwrap().len()
+    }
+}
+
+fn start_poller<T: Clone + Send + 'static>(hashtag: Hashtag, poll: Arc<PollFn<T>>) -> Poller<T> {
+    info!("Start polling #{}", hashtag.as_str());
+    let subscribers: Subscribers<T> = Arc::new(Mutex::new(HashMap::new()));
+    let subscribers2 = subscribers.clone();
+    let (shutdown_tx, shutdown_rx) = oneshot::channel();
+
+    thread::spawn(move || {
+        let hashtag2 = hashtag.clone();
+        // Subscribers keep receiving items even if polling fails.
+        let polling = loop_fn((), move |()| {
+            let subscribers = subscribers2.clone();
+            let hashtag = hashtag2.clone();
+            (*poll)(&hashtag)
+                .for_each(move |item| {
+                    publish(&subscribers, item);
+                    Ok(())
+                })
+                .then(move |res| {
+                    if let Err(e) = res {
+                        warn!("Fail to poll #{} : {:?}", hashtag.as_str(), e);
+                    }
+                    let retry = Instant::now() + Duration::from_secs(RETRY_INTERVAL_SEC);
+                    Delay::new(retry).then(|_| Ok::<Loop<(), ()>, ()>(Loop::Continue(())))
+                })
+        });
+
+        // shutdown_tx is never dropped until shutdown.
+        let shutdown = shutdown_rx.then(|_| Ok::<_, ()>(()));
+        let work = polling.select(shutdown).map(|_| ()).map_err(|_| ());
+        ::tokio::run(work);
+        info!("Stop polling #{}", hashtag.as_str());
+    });
+
+    Poller {
+        subscribers: subscribers,
+        shutdown_tx: shutdown_tx,
+    }
+}
+
+fn publish<T: Clone>(subscribers: &Mutex<HashMap<usize, UnboundedSender<T>>>, item: T) {
+    for tx in subscribers.lock().unwrap().values() {
+        let _ = tx.unbounded_send(item.clone());
+    }
+}
+
+/// Stream of items of a hashtag.
+/// Dropping it unsubscribes the hashtag.
+pub struct Subscription<T> {
+    rx: UnboundedReceiver<T>,
+    id: usize,
+    hashtag: Hashtag,
+    pollers: Arc<Mutex<HashMap<Hashtag, Poller<T>>>>,
+}
+
+impl<T> Stream for Subscription<T> {
+    type Item = T;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<T>, Error> {
+        // Receiver never fails.
+        Ok(self.rx.poll().unwrap())
+    }
+}
+
+impl<T> Drop for Subscription<T> {
+    fn drop(&mut self) {
+        let mut pollers = self.pollers.lock().unwrap();
+        let is_last = match pollers.get(&self.hashtag) {
+            Some(poller) => {
+                let mut subscribers = poller.subscribers.lock().unwrap();
+                subscribers.remove(&self.id);
+                let is_empty = subscribers.is_empty();
+                is_empty
+            }
+            None => false,
+        };
+        if is_last {
+            if let Some(poller) = pollers.remove(&self.hashtag) {
+                let _ = poller.shutdown_tx.send(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::timer::Interval;
+
+    #[test]
+    fn share_poller_of_hashtag() {
+        let starts = Arc::new(AtomicUsize::new(0));
+        let starts2 = starts.clone();
+        let pollers = HashtagPollers::new(move |hashtag: &Hashtag| {
+            starts2.fetch_add(1, Ordering::SeqCst);
+            let name = hashtag.as_str().to_string();
+            Box::new(
+                Interval::new(Instant::now(), Duration::from_millis(10))
+                    .map(move |_| name.clone())
+                    .map_err(Error::from),
+            ) as PollStream<String>
+        });
+
+        let mut a1 = pollers.subscribe(&Hashtag::new("a").unwrap());
+        let mut a2 = pollers.subscribe(&Hashtag::new("a").unwrap());
+        let mut b = pollers.subscribe(&Hashtag::new("b").unwrap());
+        assert_eq!(a1.by_ref().wait().next().unwrap().unwrap(), "a");
+        assert_eq!(a2.by_ref().wait().next().unwrap().unwrap(), "a");
+        assert_eq!(b.by_ref().wait().next().unwrap().unwrap(), "b");
+        assert_eq!(starts.load(Ordering::SeqCst), 2);
+        assert_eq!(pollers.len(), 2);
+
+        // Poller keeps running while a subscriber remains.
+        drop(a1);
+        assert_eq!(pollers.len(), 2);
+        drop(a2);
+        assert_eq!(pollers.len(), 1);
+
+        // Poller starts again on a new subscription.
+        let mut a3 = pollers.subscribe(&Hashtag::new("a").unwrap());
+        assert_eq!(a3.by_ref().wait().next().unwrap().unwrap(), "a");
+        assert_eq!(starts.load(Ordering::SeqCst), 3);
+    }
+}
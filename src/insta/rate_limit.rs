@@ -0,0 +1,183 @@
+use std::{sync::{Arc, Mutex}, time::{Duration, Instant}};
+use futures::Future;
+use tokio::timer::Delay;
+
+use error::Error;
+
+// Rate is never reduced below this fraction of configured rate.
+const MIN_RATE_FRACTION: f64 = 1.0 / 16.0;
+// Fraction of configured rate which is restored on each successful call.
+const RECOVERY_FRACTION: f64 = 1.0 / 10.0;
+
+#[derive(Debug, Clone)]
+pub struct RateLimitConfig {
+    // Requests per second.
+    pub rate: f64,
+    // Max number of requests which can be sent at once after idle.
+    pub burst: u32,
+    // Nobody calls API during this duration after API is limited.
+    pub cooling: Duration,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> RateLimitConfig {
+        RateLimitConfig {
+            rate: 1.0 / 3.0,
+            burst: 1,
+            cooling: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Token bucket shared by every Instagram API call.
+///
+/// Rate is halved when API is limited and gradually restored on successful calls.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    config: Arc<RateLimitConfig>,
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    // Negative while callers are waiting for tokens.
+    tokens: f64,
+    // Current rate which may be lower than configured rate.
+    rate: f64,
+    last_refill: Instant,
+    last_penalized: Option<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> RateLimiter {
+        let now = Instant::now();
+        let bucket = Bucket {
+            tokens: config.burst as f64,
+            rate: config.rate,
+            last_refill: now,
+            last_penalized: None,
+        };
+        RateLimiter {
+            config: Arc::new(config),
+            bucket: Arc::new(Mutex::new(bucket)),
+        }
+    }
+
+    /// Resolves when a request can be sent.
+    pub fn acquire(&self) -> impl Future<Item = (), Error = Error> {
+        let now = Instant::now();
+        let wait = self.reserve(now);
+        Delay::new(now + wait).map_err(Error::from)
+    }
+
+    /// Called when API is limited.
+    pub fn penalize(&self) {
+        self.penalize_at(Instant::now());
+    }
+
+    /// Called when API call succeeds.
+    pub fn reward(&self) {
+        self.reward_at(Instant::now());
+    }
+
+    // Tokens are reserved in order of calls so that callers are served in FIFO order.
+    fn reserve(&self, now: Instant) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill(now, self.config.burst as f64);
+        bucket.tokens -= 1.0;
+        if bucket.tokens >= 0.0 {
+            Duration::new(0, 0)
+        } else {
+            from_secs_f64(-bucket.tokens / bucket.rate)
+        }
+    }
+
+    fn penalize_at(&self, now: Instant) {
+        let mut bucket = self.bucket.lock().unwrap();
+        // Concurrent calls which were limited at the same time are penalized once.
+        if let Some(last) = bucket.last_penalized {
+            if now.duration_since(last) < self.config.cooling {
+                return;
+            }
+        }
+        bucket.last_penalized = Some(now);
+        bucket.refill(now, self.config.burst as f64);
+        bucket.rate = (bucket.rate / 2.0).max(self.config.rate * MIN_RATE_FRACTION);
+        // Every caller waits at least `cooling`.
+        let debt = as_secs_f64(self.config.cooling) * bucket.rate;
+        bucket.tokens = bucket.tokens.min(0.0) - debt;
+        warn!(
+            "Instagram API is limited. Slow down to {:.3} requests/sec",
+            bucket.rate
+        );
+    }
+
+    fn reward_at(&self, now: Instant) {
+        let mut bucket = self.bucket.lock().unwrap();
+        if bucket.rate < self.config.rate {
+            bucket.refill(now, self.config.burst as f64);
+            let rate = bucket.rate + self.config.rate * RECOVERY_FRACTION;
+            bucket.rate = rate.min(self.config.rate);
+        }
+    }
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant, burst: f64) {
+        if now > self.last_refill {
+            let elapsed = as_secs_f64(now.duration_since(self.last_refill));
+            self.tokens = (self.tokens + elapsed * self.rate).min(burst);
+            self.last_refill = now;
+        }
+    }
+}
+
+fn as_secs_f64(d: Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 * 1e-9
+}
+
+fn from_secs_f64(secs: f64) -> Duration {
+    Duration::new(secs.trunc() as u64, (secs.fract() * 1e9) as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter() -> RateLimiter {
+        RateLimiter::new(RateLimitConfig {
+            rate: 2.0,
+            burst: 2,
+            cooling: Duration::from_secs(10),
+        })
+    }
+
+    #[test]
+    fn wait_after_burst() {
+        let limiter = limiter();
+        let now = Instant::now();
+        assert_eq!(limiter.reserve(now), Duration::new(0, 0));
+        assert_eq!(limiter.reserve(now), Duration::new(0, 0));
+        assert_eq!(limiter.reserve(now), Duration::from_millis(500));
+        assert_eq!(limiter.reserve(now), Duration::from_millis(1000));
+        // Two tokens are refilled in a second but both are reserved by previous callers.
+        assert_eq!(limiter.reserve(now + Duration::from_secs(1)), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn slow_down_when_limited() {
+        let limiter = limiter();
+        let now = Instant::now();
+        limiter.reserve(now);
+        limiter.reserve(now);
+        limiter.penalize_at(now);
+        // Penalized only once while cooling.
+        limiter.penalize_at(now);
+
+        // Rate is 1 request/sec and 10 tokens are owed for cooling.
+        assert_eq!(limiter.reserve(now), Duration::from_secs(11));
+
+        limiter.reward_at(now);
+        assert!((limiter.bucket.lock().unwrap().rate - 1.2).abs() < 1e-9);
+    }
+}
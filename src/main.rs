@@ -15,6 +15,8 @@ extern crate rocket_contrib;
 extern crate rocket_cors;
 
 extern crate image;
+extern crate gif;
+extern crate zip;
 
 #[macro_use]
 extern crate serde_derive;
@@ -28,6 +30,10 @@ extern crate mongodb;
 #[macro_use]
 extern crate error_chain;
 extern crate rand;
+extern crate sha2;
+extern crate hmac;
+extern crate unicode_normalization;
+extern crate caseless;
 #[macro_use]
 extern crate log;
 extern crate env_logger;
@@ -35,14 +41,21 @@ extern crate env_logger;
 pub mod mosaic;
 pub mod images;
 pub mod insta;
+pub mod source;
 pub mod api_server;
 pub mod worker;
 pub mod error;
 pub mod db;
 pub mod post;
+pub mod moderation;
+pub mod takedown;
+pub mod query;
 pub mod util;
+pub mod config;
+pub mod cassette;
 
 use self::db::Mongodb;
+use self::config::Config;
 
 fn main() {
     env_logger::init();
@@ -50,7 +63,8 @@ fn main() {
     let mongodb_port = get_env_u16("MONGODB_PORT");
     let mongodb_db = get_env_str("MONGODB_DB");
     let mongodb = Mongodb::new(mongodb_host.as_str(), mongodb_port, mongodb_db.as_str());
-    api_server::run(mongodb);
+    let config = Config::from_env();
+    api_server::run(mongodb, config);
 }
 
 fn get_env_str(key: &str) -> String {
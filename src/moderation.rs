@@ -0,0 +1,35 @@
+use images::Size;
+use post::{GenericPost, Post};
+
+/// Posts are held in the pending queue of a worker until they are approved
+/// if moderation is enabled on the worker.
+/// Pending posts of a worker which stops are taken over by the next moderated worker
+/// which selects them.
+#[derive(Debug, Clone, Default)]
+pub struct ModerationOption {
+    // Posts of these users are approved without review.
+    pub trusted_users: Vec<String>,
+}
+
+impl ModerationOption {
+    pub fn is_trusted<S: Size>(&self, post: &GenericPost<S>) -> bool {
+        let user_name = post.user_name();
+        !user_name.is_empty() && self.trusted_users.iter().any(|u| u == user_name)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, PartialEq, Eq, Hash)]
+pub struct PendingPostId(pub String);
+
+impl PendingPostId {
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+/// Post waiting for review in the pending queue of a worker.
+#[derive(Debug, Clone)]
+pub struct PendingPost<S> {
+    pub id: PendingPostId,
+    pub post: GenericPost<S>,
+}
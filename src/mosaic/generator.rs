@@ -1,3 +1,5 @@
+use std::sync::Arc;
+
 use images::{MultipleOf, Size, SizedImage, SmallerThan};
 use post::{GenericPost, HashtagList, Post};
 use util::{Id, IdGenerator};
@@ -6,6 +8,7 @@ use super::{Distance, DistanceFunc, MeanGrayscale, MosaicPiece, MosaicPieceVec};
 pub struct MosaicArt<S, SS> {
     pub id: Id, // Used by api_server to determine whether rerurn cached response or construct a new response.
     pub image: SizedImage<S>,
+    pub origin: Arc<SizedImage<S>>, // Only used when rendering. Never painted on `image`.
     pub posts: Vec<GenericPost<SS>>,
     pub hashtags: HashtagList,
 }
@@ -14,12 +17,14 @@ impl<S, SS> MosaicArt<S, SS> {
     fn new(
         id: Id,
         image: SizedImage<S>,
+        origin: Arc<SizedImage<S>>,
         posts: Vec<GenericPost<SS>>,
         hashtags: HashtagList,
     ) -> MosaicArt<S, SS> {
         MosaicArt {
             id: id,
             image: image,
+            origin: origin,
             posts: posts,
             hashtags: hashtags,
         }
@@ -28,7 +33,7 @@ impl<S, SS> MosaicArt<S, SS> {
 
 pub struct MosaicArtGenerator<S, SS, D = MeanGrayscale<S, SS>> {
     // immutable
-    _origin_image: SizedImage<S>,
+    origin_image: Arc<SizedImage<S>>,
     hashtags: HashtagList,
     distance_f: D,
     id_gen: IdGenerator,
@@ -51,15 +56,17 @@ where
         let pieces = MosaicPieceVec::with_origin_image(&origin);
         let distance_f = MeanGrayscale::from_origin(&origin);
         let mut id_gen = IdGenerator::new();
+        let origin = Arc::new(origin);
 
         let init_art = MosaicArt::new(
             id_gen.next_id(),
             init_image.clone(),
+            origin.clone(),
             pieces.iter().map(|p| p.post.clone()).collect(),
             hashtags.clone(),
         );
         let generator = MosaicArtGenerator {
-            _origin_image: origin,
+            origin_image: origin,
             hashtags: hashtags.clone(),
             distance_f: distance_f,
             id_gen: IdGenerator::new(),
@@ -89,12 +96,34 @@ where
         };
         let (pos, _replaced) = self.pieces.replace_piece(piece.clone());
         self.current_img.overpaint_by(piece.post.image(), pos);
+        self.current_art()
+    }
+
+    /// Removes pieces of posts matching `pred` and clears their cells.
+    /// Returns number of removed pieces.
+    pub fn remove_posts<F>(&mut self, pred: F) -> usize
+    where
+        F: Fn(&GenericPost<SS>) -> bool,
+    {
+        let positions = self.pieces.remove_pieces(pred);
+        let clear = SizedImage::<SS>::clear_image();
+        for pos in positions.iter() {
+            self.current_img.overpaint_by(&clear, *pos);
+        }
+        positions.len()
+    }
+
+    /// Posts used as pieces.
+    pub fn posts(&self) -> impl Iterator<Item = &GenericPost<SS>> {
+        self.pieces.iter().map(|piece| &piece.post)
+    }
 
-        // Create a new MosaicArt
+    pub fn current_art(&mut self) -> MosaicArt<S, SS> {
         let image = self.current_img.clone();
         let posts = self.pieces.iter().map(|piece| piece.post.clone()).collect();
         let hashtags = self.hashtags.clone();
-        MosaicArt::new(self.id_gen.next_id(), image, posts, hashtags)
+        let origin = self.origin_image.clone();
+        MosaicArt::new(self.id_gen.next_id(), image, origin, posts, hashtags)
     }
 
     pub fn has_enough_pieces(&self) -> bool {
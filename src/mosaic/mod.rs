@@ -1,7 +1,11 @@
 pub mod piece;
 pub mod distance;
 pub mod generator;
+pub mod timelapse;
+pub mod style;
 
 pub use self::piece::{MosaicPiece, MosaicPieceVec};
 pub use self::distance::{Distance, DistanceFunc, MeanGrayscale};
 pub use self::generator::{MosaicArt, MosaicArtGenerator};
+pub use self::timelapse::{Timelapse, TimelapseOption};
+pub use self::style::{Shadow, TileStyle};
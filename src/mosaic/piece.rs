@@ -77,6 +77,26 @@ where
         (Self::index_to_pos(idx), old_piece)
     }
 
+    // Empties pieces whose post matches `pred` so that other posts fill them.
+    // Returns positions of emptied pieces.
+    pub fn remove_pieces<F>(&mut self, pred: F) -> Vec<Position>
+    where
+        F: Fn(&GenericPost<SS>) -> bool,
+    {
+        let mut removed = Vec::new();
+        for (idx, piece) in self.pieces.iter_mut().enumerate() {
+            let matched = match piece.1 {
+                Some(ref p) => pred(&p.post),
+                None => false,
+            };
+            if matched {
+                *piece = (Distance::max_value(), None);
+                removed.push(Self::index_to_pos(idx));
+            }
+        }
+        removed
+    }
+
     fn index_to_pos(idx: usize) -> Position {
         let nx = S::WIDTH / SS::WIDTH;
         let ny = S::HEIGHT / SS::WIDTH;
@@ -85,3 +105,50 @@ where
         Position { x: x, y: y }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgba, RgbaImage};
+    use images::{Image, size::{Size100x100, Size1500x1500}};
+    use post::{Hashtag, LocalPost, LocalPostId, Post};
+
+    type TestPieceVec = MosaicPieceVec<Size1500x1500, Size100x100>;
+
+    fn piece_vec() -> TestPieceVec {
+        let origin = Image::new(RgbaImage::from_pixel(1500, 1500, Rgba {
+            data: [0, 0, 0, 255],
+        }));
+        MosaicPieceVec::with_origin_image(&SizedImage::with_resize(origin))
+    }
+
+    // A piece which is closest to the origin piece at `idx`.
+    fn piece(idx: usize, user_name: &str) -> MosaicPiece<Size100x100> {
+        let image = SizedImage::with_resize(Image::new(RgbaImage::from_pixel(100, 100, Rgba {
+            data: [0, 0, 0, 255],
+        })));
+        let id = LocalPostId(idx.to_string());
+        let post = LocalPost::new(id, image, user_name, Hashtag::new("tokyo").unwrap());
+        let mut distance_vec = vec![Distance::max_value(); 225];
+        distance_vec[idx] = 0;
+        MosaicPiece {
+            post: GenericPost::LocalPost(post),
+            distance_vec: distance_vec,
+        }
+    }
+
+    #[test]
+    fn remove_pieces_of_matched_posts() {
+        let mut pieces = piece_vec();
+        pieces.replace_piece(piece(0, "alice"));
+        pieces.replace_piece(piece(16, "alice"));
+        pieces.replace_piece(piece(1, "bob"));
+
+        let removed = pieces.remove_pieces(|p| p.user_name() == "alice");
+        assert_eq!(removed, vec![Position { x: 0, y: 0 }, Position { x: 100, y: 100 }]);
+        let users: Vec<&str> = pieces.iter().map(|p| p.post.user_name()).collect();
+        assert_eq!(users, vec!["bob"]);
+        // Emptied cells are filled by any post.
+        assert_eq!(pieces.pieces[0].0, Distance::max_value());
+    }
+}
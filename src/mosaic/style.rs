@@ -0,0 +1,163 @@
+use image::Rgba;
+
+use images::{Image, Size, SizedImage};
+
+/// Decoration of each tile which is applied only when rendering.
+/// Cell geometry is never changed; tiles are shrunk inside their cells instead.
+#[derive(Debug, Clone, Copy)]
+pub struct TileStyle {
+    pub grout_width: u32,
+    pub grout_color: Rgba<u8>,
+    pub corner_radius: u32,
+    pub shadow: Option<Shadow>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Shadow {
+    pub offset: u32,
+    pub opacity: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Region {
+    Tile,
+    Shadow,
+    Grout,
+}
+
+impl TileStyle {
+    pub fn apply<S, SS>(&self, image: &SizedImage<S>) -> Image
+    where
+        S: Size,
+        SS: Size,
+    {
+        let mask = self.cell_mask(SS::WIDTH, SS::HEIGHT);
+        let shadow_color = match self.shadow {
+            Some(shadow) => darken(self.grout_color, shadow.opacity),
+            None => self.grout_color,
+        };
+
+        let mut res = image.image.clone();
+        for (x, y, pixel) in res.enumerate_pixels_mut() {
+            // Keep transparent (never filled) area as it is.
+            if pixel.data[3] == 0 {
+                continue;
+            }
+            let idx = ((y % SS::HEIGHT) * SS::WIDTH + (x % SS::WIDTH)) as usize;
+            match mask[idx] {
+                Region::Tile => {}
+                Region::Shadow => *pixel = shadow_color,
+                Region::Grout => *pixel = self.grout_color,
+            }
+        }
+        res
+    }
+
+    fn cell_mask(&self, width: u32, height: u32) -> Vec<Region> {
+        let mut mask = Vec::with_capacity((width * height) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                let region = if self.is_tile(x as i64, y as i64, width, height) {
+                    Region::Tile
+                } else {
+                    match self.shadow {
+                        Some(shadow)
+                            if self.is_tile(
+                                x as i64 - shadow.offset as i64,
+                                y as i64 - shadow.offset as i64,
+                                width,
+                                height,
+                            ) =>
+                        {
+                            Region::Shadow
+                        }
+                        _ => Region::Grout,
+                    }
+                };
+                mask.push(region);
+            }
+        }
+        mask
+    }
+
+    fn is_tile(&self, x: i64, y: i64, width: u32, height: u32) -> bool {
+        let left = (self.grout_width / 2) as i64;
+        let top = left;
+        let right = width as i64 - (self.grout_width - self.grout_width / 2) as i64;
+        let bottom = height as i64 - (self.grout_width - self.grout_width / 2) as i64;
+        if x < left || right <= x || y < top || bottom <= y {
+            return false;
+        }
+
+        let r = self.corner_radius.min(((right - left) / 2) as u32) as f64;
+        if r == 0.0 {
+            return true;
+        }
+        // Distance from the nearest corner circle center
+        let cx = (x as f64 + 0.5).max(left as f64 + r).min(right as f64 - r);
+        let cy = (y as f64 + 0.5).max(top as f64 + r).min(bottom as f64 - r);
+        let dx = x as f64 + 0.5 - cx;
+        let dy = y as f64 + 0.5 - cy;
+        dx * dx + dy * dy <= r * r
+    }
+}
+
+fn darken(color: Rgba<u8>, opacity: f64) -> Rgba<u8> {
+    let opacity = opacity.max(0.0).min(1.0);
+    let mut data = color.data;
+    for c in data.iter_mut().take(3) {
+        *c = (*c as f64 * (1.0 - opacity)).round() as u8;
+    }
+    Rgba { data: data }
+}
+
+/// Parse "rrggbb" or "rrggbbaa" hex string.
+pub fn parse_hex_color(s: &str) -> Option<Rgba<u8>> {
+    let s = s.trim_left_matches('#');
+    if s.len() != 6 && s.len() != 8 {
+        return None;
+    }
+    let mut data = [0, 0, 0, 255];
+    for i in 0..(s.len() / 2) {
+        data[i] = u8::from_str_radix(s.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(Rgba { data: data })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn style(grout_width: u32, corner_radius: u32) -> TileStyle {
+        TileStyle {
+            grout_width: grout_width,
+            grout_color: Rgba { data: [255, 255, 255, 255] },
+            corner_radius: corner_radius,
+            shadow: None,
+        }
+    }
+
+    #[test]
+    fn grout_surrounds_tile() {
+        let mask = style(2, 0).cell_mask(10, 10);
+        assert_eq!(mask[0], Region::Grout);
+        assert_eq!(mask[1 * 10 + 1], Region::Tile);
+        assert_eq!(mask[9 * 10 + 9], Region::Grout);
+        assert_eq!(mask[8 * 10 + 8], Region::Tile);
+    }
+
+    #[test]
+    fn rounded_corner_is_not_tile() {
+        let mask = style(0, 4).cell_mask(10, 10);
+        assert_eq!(mask[0], Region::Grout);
+        assert_eq!(mask[5 * 10 + 5], Region::Tile);
+        assert_eq!(mask[0 * 10 + 5], Region::Tile);
+    }
+
+    #[test]
+    fn parse_color() {
+        assert_eq!(parse_hex_color("#ff0080").unwrap().data, [255, 0, 128, 255]);
+        assert_eq!(parse_hex_color("00000080").unwrap().data, [0, 0, 0, 128]);
+        assert!(parse_hex_color("fff").is_none());
+    }
+}
@@ -0,0 +1,306 @@
+use std::{collections::HashMap, io::{Cursor, Write}, sync::Arc, time::{Duration, Instant}};
+use gif::{Encoder, Frame, Repeat, SetParameter};
+use zip::{ZipWriter, write::FileOptions};
+
+use images::{Image, Size, SizedImage};
+use takedown::PostIdentity;
+use error::{Error, ErrorKind};
+
+// When the number of frames reaches this limit, every other frame is dropped
+// and the recording interval is doubled. So memory usage is bounded even if
+// a worker runs for a long time.
+const MAX_FRAMES: usize = 512;
+
+// Frames are kept in memory without compression, so that frames of a worker take up to
+// MAX_FRAMES * MAX_FRAME_WIDTH^2 * 4 bytes (512 MB) for a square art.
+// Frames are not wider than the art either.
+const MAX_FRAME_WIDTH: u32 = 500;
+
+#[derive(Debug, Clone, Copy)]
+pub struct TimelapseOption {
+    pub interval: Duration,
+    pub frame_width: u32,
+}
+
+impl TimelapseOption {
+    pub fn validate(&self) -> Result<(), Error> {
+        // Interval is doubled at thinning, which never grows zero interval.
+        if self.interval == Duration::from_secs(0) {
+            bail!(ErrorKind::InvalidTimelapseOption(
+                "interval must be positive".into()
+            ));
+        }
+        if self.frame_width == 0 || self.frame_width > MAX_FRAME_WIDTH {
+            bail!(ErrorKind::InvalidTimelapseOption(format!(
+                "frame_width must be in 1..={}",
+                MAX_FRAME_WIDTH
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Records downscaled revisions of a mosaic art.
+/// Cloning is cheap because frames are shared.
+#[derive(Clone)]
+pub struct Timelapse {
+    interval: Duration,
+    frame_width: u32,
+    last_recorded: Option<Instant>,
+    frames: Vec<Arc<Image>>,
+    // Index of the first frame in which each post may appear.
+    // Pieces of each frame are not kept, so that a post is assumed to be in every later frame.
+    first_frames: HashMap<PostIdentity, usize>,
+}
+
+impl Timelapse {
+    pub fn new(option: TimelapseOption) -> Timelapse {
+        Timelapse {
+            interval: option.interval,
+            frame_width: option.frame_width,
+            last_recorded: None,
+            frames: Vec::new(),
+            first_frames: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_due(&self) -> bool {
+        match self.last_recorded {
+            Some(t) => t.elapsed() >= self.interval,
+            None => true,
+        }
+    }
+
+    /// Notes that a post is used as a piece of frames recorded from now on.
+    pub fn add_post(&mut self, post: PostIdentity) {
+        let next = self.frames.len();
+        self.first_frames.entry(post).or_insert(next);
+    }
+
+    /// Drops frames from the first one in which a post matching `pred` may appear,
+    /// e.g. when the post is taken down. Returns number of dropped frames.
+    pub fn purge<F>(&mut self, pred: F) -> usize
+    where
+        F: Fn(&PostIdentity) -> bool,
+    {
+        let first = self.first_frames
+            .iter()
+            .filter(|&(post, _)| pred(post))
+            .map(|(_, &first)| first)
+            .min();
+        self.first_frames.retain(|post, _| !pred(post));
+        let first = match first {
+            Some(first) if first < self.frames.len() => first,
+            _ => return 0,
+        };
+        let dropped = self.frames.len() - first;
+        self.frames.truncate(first);
+        for first_frame in self.first_frames.values_mut() {
+            *first_frame = (*first_frame).min(first);
+        }
+        // Art without the post is recorded at once.
+        self.last_recorded = None;
+        dropped
+    }
+
+    /// Record a given image as a new frame if interval has passed since last record.
+    pub fn record_if_due<S: Size>(&mut self, image: &SizedImage<S>) {
+        if !self.is_due() {
+            return;
+        }
+        let width = self.frame_width.min(S::WIDTH);
+        let height = width * S::HEIGHT / S::WIDTH;
+        self.frames.push(Arc::new(image.resize(width, height)));
+        self.last_recorded = Some(Instant::now());
+
+        if self.frames.len() >= MAX_FRAMES {
+            let mut idx = 0;
+            self.frames.retain(|_| {
+                idx += 1;
+                idx % 2 == 1
+            });
+            // Frames of even indices are kept.
+            for first_frame in self.first_frames.values_mut() {
+                *first_frame = (*first_frame + 1) / 2;
+            }
+            self.interval *= 2;
+            debug!("Timelapse is thinned out. New interval : {:?}", self.interval);
+        }
+    }
+
+    /// Encode recorded frames into an animated GIF.
+    /// `frame_delay` is rounded to 10 ms which is a unit of GIF.
+    /// Returns empty bytes if no frame has been recorded.
+    pub fn to_gif(&self, frame_delay: Duration) -> Result<Vec<u8>, Error> {
+        let mut vec = Vec::new();
+        if let Some(first) = self.frames.first() {
+            let delay = (frame_delay.as_secs() * 100 + frame_delay.subsec_millis() as u64 / 10)
+                .min(u16::max_value() as u64) as u16;
+            let mut encoder =
+                Encoder::new(&mut vec, first.width() as u16, first.height() as u16, &[])?;
+            encoder.set(Repeat::Infinite)?;
+            for image in self.frames.iter() {
+                let mut pixels = (***image).clone().into_raw();
+                let mut frame =
+                    Frame::from_rgba(image.width() as u16, image.height() as u16, &mut pixels);
+                frame.delay = delay;
+                encoder.write_frame(&frame)?;
+            }
+            // GIF trailer is written when encoder is dropped.
+        }
+        Ok(vec)
+    }
+
+    /// Pack recorded frames into a zip file as a sequence of PNG images.
+    pub fn to_zip(&self) -> Result<Vec<u8>, Error> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for (i, image) in self.frames.iter().enumerate() {
+            zip.start_file(format!("frame_{:05}.png", i), FileOptions::default())?;
+            zip.write_all(image.to_png_bytes().as_slice())?;
+        }
+        Ok(zip.finish()?.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use image::{Rgba, RgbaImage};
+    use zip::ZipArchive;
+    use images::size::Size30x30;
+    use post::{BluummPost, GenericPost, Hashtag};
+
+    fn image(v: u8) -> SizedImage<Size30x30> {
+        SizedImage::new(Image::new(RgbaImage::from_pixel(30, 30, Rgba { data: [v, v, v, 255] })))
+            .unwrap()
+    }
+
+    fn post(user_name: &str) -> PostIdentity {
+        let post = BluummPost::new(image(0), user_name, Hashtag::new("tokyo").unwrap());
+        PostIdentity::of(&GenericPost::BluummPost(post))
+    }
+
+    fn timelapse(interval: Duration) -> Timelapse {
+        Timelapse::new(TimelapseOption {
+            interval: interval,
+            frame_width: 10,
+        })
+    }
+
+    #[test]
+    fn validate_frame_width() {
+        let option = |w| TimelapseOption {
+            interval: Duration::from_secs(1),
+            frame_width: w,
+        };
+        assert!(option(300).validate().is_ok());
+        assert!(option(0).validate().is_err());
+        assert!(option(MAX_FRAME_WIDTH + 1).validate().is_err());
+    }
+
+    #[test]
+    fn validate_interval() {
+        let option = |secs| TimelapseOption {
+            interval: Duration::from_secs(secs),
+            frame_width: 300,
+        };
+        assert!(option(1).validate().is_ok());
+        assert!(option(0).validate().is_err());
+    }
+
+    #[test]
+    fn record_only_when_due() {
+        let mut timelapse = timelapse(Duration::from_secs(3600));
+        timelapse.record_if_due(&image(0));
+        timelapse.record_if_due(&image(255));
+        assert_eq!(timelapse.len(), 1);
+        assert_eq!(timelapse.frames[0].width(), 10);
+        assert_eq!(timelapse.frames[0].height(), 10);
+    }
+
+    #[test]
+    fn record_frames_not_wider_than_art() {
+        let mut timelapse = Timelapse::new(TimelapseOption {
+            interval: Duration::from_secs(1),
+            frame_width: 300,
+        });
+        timelapse.record_if_due(&image(0));
+        assert_eq!(timelapse.frames[0].width(), 30);
+    }
+
+    #[test]
+    fn thin_out_frames_at_limit() {
+        let mut timelapse = timelapse(Duration::from_secs(0));
+        for _ in 0..MAX_FRAMES {
+            timelapse.record_if_due(&image(0));
+        }
+        assert_eq!(timelapse.len(), MAX_FRAMES / 2);
+    }
+
+    #[test]
+    fn purge_frames_since_post_appeared() {
+        let mut timelapse = timelapse(Duration::from_secs(0));
+        timelapse.record_if_due(&image(0));
+        timelapse.add_post(post("alice"));
+        timelapse.record_if_due(&image(1));
+        timelapse.add_post(post("bob"));
+        timelapse.record_if_due(&image(2));
+        timelapse.record_if_due(&image(3));
+
+        assert_eq!(timelapse.purge(|p| p == &post("bob")), 2);
+        assert_eq!(timelapse.len(), 2);
+        assert_eq!(timelapse.purge(|p| p == &post("bob")), 0);
+        assert_eq!(timelapse.purge(|p| p == &post("alice")), 1);
+        assert_eq!(timelapse.len(), 1);
+    }
+
+    #[test]
+    fn keep_first_frames_of_posts_through_thinning() {
+        let mut timelapse = timelapse(Duration::from_secs(0));
+        for _ in 0..3 {
+            timelapse.record_if_due(&image(0));
+        }
+        timelapse.add_post(post("alice"));
+        for _ in 3..MAX_FRAMES {
+            timelapse.record_if_due(&image(0));
+        }
+        // 4th frame is dropped and 5th one becomes 3rd one.
+        assert_eq!(timelapse.purge(|p| p == &post("alice")), MAX_FRAMES / 2 - 2);
+    }
+
+    #[test]
+    fn encode_gif() {
+        let mut timelapse = timelapse(Duration::from_secs(0));
+        timelapse.record_if_due(&image(0));
+        timelapse.record_if_due(&image(255));
+        let gif = timelapse.to_gif(Duration::from_millis(200)).unwrap();
+        assert_eq!(&gif[0..6], b"GIF89a");
+        // Logical screen width and height in little endian.
+        assert_eq!(&gif[6..10], &[10, 0, 10, 0]);
+    }
+
+    #[test]
+    fn encode_empty_gif() {
+        let timelapse = timelapse(Duration::from_secs(0));
+        assert!(timelapse.to_gif(Duration::from_millis(200)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn pack_frames_into_zip() {
+        let mut timelapse = timelapse(Duration::from_secs(0));
+        timelapse.record_if_due(&image(0));
+        timelapse.record_if_due(&image(255));
+        let bytes = timelapse.to_zip().unwrap();
+        let mut zip = ZipArchive::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(zip.len(), 2);
+        let mut png = Vec::new();
+        zip.by_name("frame_00001.png").unwrap().read_to_end(&mut png).unwrap();
+        let frame = Image::from_bytes(png.as_slice()).unwrap();
+        assert_eq!(frame.get_pixel(0, 0).data, [255, 255, 255, 255]);
+    }
+}
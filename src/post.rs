@@ -1,18 +1,27 @@
 use std::sync::Arc;
 use serde::ser::{Serialize, Serializer};
+use caseless::Caseless;
+use unicode_normalization::{UnicodeNormalization, char::is_combining_mark};
 
-use images::{Size, SizedImage};
+use images::{ImageHash, Size, SizedImage};
+use error::{Error, ErrorKind};
 
 pub trait Post {
     type ImageSize: Size;
     fn image(&self) -> &SizedImage<Self::ImageSize>;
     fn user_name(&self) -> &str;
     fn hashtag(&self) -> &Hashtag;
+
+    /// Every hashtag on the post including `hashtag()`.
+    fn hashtags(&self) -> Vec<Hashtag> {
+        vec![self.hashtag().clone()]
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct BluummPost<S> {
     image: Arc<SizedImage<S>>,
+    phash: ImageHash,
     user_name: Arc<String>,
     hashtag: Hashtag,
 }
@@ -24,6 +33,7 @@ impl<S: Size> BluummPost<S> {
         hashtag: Hashtag,
     ) -> BluummPost<S> {
         BluummPost {
+            phash: ImageHash::dhash(&image),
             image: Arc::new(image),
             user_name: Arc::new(user_name.into()),
             hashtag: hashtag,
@@ -51,17 +61,40 @@ impl<S: Size> Post for BluummPost<S> {
 pub struct InstaPost<S> {
     pub post_id: InstaPostId,
     image: Arc<SizedImage<S>>,
+    phash: ImageHash,
     user_name: Arc<String>,
     hashtag: Hashtag,
+    // Hashtags in the caption.
+    tags: Arc<Vec<Hashtag>>,
+    // Image is the thumbnail of a video.
+    pub is_video: bool,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
 pub struct InstaPostId(pub String);
 
+// Images of a carousel except the first one have the shortcode and index
+// separated by `CAROUSEL_INDEX_SEPARATOR` as id.
+const CAROUSEL_INDEX_SEPARATOR: char = ':';
+
 impl InstaPostId {
     pub fn as_str(&self) -> &str {
         self.0.as_str()
     }
+
+    /// Id of `index`-th image of a carousel.
+    /// The first image has the same id as the post so that the post is found by its id.
+    pub fn child(&self, index: usize) -> InstaPostId {
+        match index {
+            0 => self.clone(),
+            i => InstaPostId(format!("{}{}{}", self.0, CAROUSEL_INDEX_SEPARATOR, i)),
+        }
+    }
+
+    /// Shortcode of the post which is used in its URL.
+    pub fn shortcode(&self) -> &str {
+        self.0.split(CAROUSEL_INDEX_SEPARATOR).next().unwrap()
+    }
 }
 
 impl ::std::fmt::Display for InstaPostId {
@@ -76,14 +109,24 @@ impl<S: Size> InstaPost<S> {
         image: SizedImage<S>,
         user_name: T,
         hashtag: Hashtag,
+        tags: Vec<Hashtag>,
+        is_video: bool,
     ) -> InstaPost<S> {
         InstaPost {
             post_id: id,
+            phash: ImageHash::dhash(&image),
             image: Arc::new(image),
             user_name: Arc::new(user_name.into()),
             hashtag: hashtag,
+            tags: Arc::new(tags),
+            is_video: is_video,
         }
     }
+
+    /// Hashtags in the caption.
+    pub fn tags(&self) -> &[Hashtag] {
+        self.tags.as_slice()
+    }
 }
 
 impl<S: Size> Post for InstaPost<S> {
@@ -99,12 +142,258 @@ impl<S: Size> Post for InstaPost<S> {
     fn hashtag(&self) -> &Hashtag {
         &self.hashtag
     }
+
+    fn hashtags(&self) -> Vec<Hashtag> {
+        let mut hashtags = self.tags.as_ref().clone();
+        if !hashtags.contains(&self.hashtag) {
+            hashtags.push(self.hashtag.clone());
+        }
+        hashtags
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LocalPost<S> {
+    pub post_id: LocalPostId,
+    image: Arc<SizedImage<S>>,
+    phash: ImageHash,
+    user_name: Arc<String>,
+    hashtag: Hashtag,
+}
+
+/// SHA-256 of an image file.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub struct LocalPostId(pub String);
+
+impl LocalPostId {
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+impl<S: Size> LocalPost<S> {
+    pub fn new<T: Into<String>>(
+        id: LocalPostId,
+        image: SizedImage<S>,
+        user_name: T,
+        hashtag: Hashtag,
+    ) -> LocalPost<S> {
+        LocalPost {
+            post_id: id,
+            phash: ImageHash::dhash(&image),
+            image: Arc::new(image),
+            user_name: Arc::new(user_name.into()),
+            hashtag: hashtag,
+        }
+    }
+}
+
+impl<S: Size> Post for LocalPost<S> {
+    type ImageSize = S;
+    fn image(&self) -> &SizedImage<S> {
+        &self.image
+    }
+
+    fn user_name(&self) -> &str {
+        self.user_name.as_str()
+    }
+
+    fn hashtag(&self) -> &Hashtag {
+        &self.hashtag
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MastodonPost<S> {
+    pub post_id: MastodonPostId,
+    // Used to credit the original status.
+    pub status_url: String,
+    image: Arc<SizedImage<S>>,
+    phash: ImageHash,
+    user_name: Arc<String>,
+    hashtag: Hashtag,
+    tags: Arc<Vec<Hashtag>>,
+}
+
+/// Id of a media attachment which is prefixed by host of its instance
+/// (e.g. "mastodon.social/102345").
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub struct MastodonPostId(pub String);
+
+impl MastodonPostId {
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+impl<S: Size> MastodonPost<S> {
+    pub fn new<T: Into<String>, U: Into<String>>(
+        id: MastodonPostId,
+        status_url: U,
+        image: SizedImage<S>,
+        user_name: T,
+        hashtag: Hashtag,
+        tags: Vec<Hashtag>,
+    ) -> MastodonPost<S> {
+        MastodonPost {
+            post_id: id,
+            status_url: status_url.into(),
+            phash: ImageHash::dhash(&image),
+            image: Arc::new(image),
+            user_name: Arc::new(user_name.into()),
+            hashtag: hashtag,
+            tags: Arc::new(tags),
+        }
+    }
+
+    /// Hashtags of the status.
+    pub fn tags(&self) -> &[Hashtag] {
+        self.tags.as_slice()
+    }
+}
+
+impl<S: Size> Post for MastodonPost<S> {
+    type ImageSize = S;
+    fn image(&self) -> &SizedImage<S> {
+        &self.image
+    }
+
+    fn user_name(&self) -> &str {
+        self.user_name.as_str()
+    }
+
+    fn hashtag(&self) -> &Hashtag {
+        &self.hashtag
+    }
+
+    fn hashtags(&self) -> Vec<Hashtag> {
+        let mut hashtags = self.tags.as_ref().clone();
+        if !hashtags.contains(&self.hashtag) {
+            hashtags.push(self.hashtag.clone());
+        }
+        hashtags
+    }
+}
+
+/// Post pushed by a third-party provider through webhook.
+#[derive(Debug, Clone)]
+pub struct ExternalPost<S> {
+    pub post_id: ExternalPostId,
+    image: Arc<SizedImage<S>>,
+    phash: ImageHash,
+    user_name: Arc<String>,
+    hashtag: Hashtag,
+}
+
+/// Id given by the provider.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub struct ExternalPostId(pub String);
+
+impl ExternalPostId {
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+impl<S: Size> ExternalPost<S> {
+    pub fn new<T: Into<String>>(
+        id: ExternalPostId,
+        image: SizedImage<S>,
+        user_name: T,
+        hashtag: Hashtag,
+    ) -> ExternalPost<S> {
+        ExternalPost {
+            post_id: id,
+            phash: ImageHash::dhash(&image),
+            image: Arc::new(image),
+            user_name: Arc::new(user_name.into()),
+            hashtag: hashtag,
+        }
+    }
+}
+
+impl<S: Size> Post for ExternalPost<S> {
+    type ImageSize = S;
+    fn image(&self) -> &SizedImage<S> {
+        &self.image
+    }
+
+    fn user_name(&self) -> &str {
+        self.user_name.as_str()
+    }
+
+    fn hashtag(&self) -> &Hashtag {
+        &self.hashtag
+    }
 }
 
 #[derive(Debug, Clone)]
 pub enum GenericPost<S> {
     BluummPost(BluummPost<S>),
     InstaPost(InstaPost<S>),
+    LocalPost(LocalPost<S>),
+    MastodonPost(MastodonPost<S>),
+    ExternalPost(ExternalPost<S>),
+}
+
+impl<S> GenericPost<S> {
+    /// Returns true if image is the thumbnail of a video.
+    pub fn is_video(&self) -> bool {
+        match self {
+            &GenericPost::InstaPost(ref p) => p.is_video,
+            _ => false,
+        }
+    }
+
+    /// Name of the source of the post.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            &GenericPost::BluummPost(_) => "bluumm",
+            &GenericPost::InstaPost(_) => "insta",
+            &GenericPost::LocalPost(_) => "local",
+            &GenericPost::MastodonPost(_) => "mastodon",
+            &GenericPost::ExternalPost(_) => "external",
+        }
+    }
+
+    /// Id of the post in its source. `None` for posts which do not have id.
+    pub fn id(&self) -> Option<&str> {
+        match self {
+            &GenericPost::BluummPost(_) => None,
+            &GenericPost::InstaPost(ref p) => Some(p.post_id.as_str()),
+            &GenericPost::LocalPost(ref p) => Some(p.post_id.as_str()),
+            &GenericPost::MastodonPost(ref p) => Some(p.post_id.as_str()),
+            &GenericPost::ExternalPost(ref p) => Some(p.post_id.as_str()),
+        }
+    }
+
+    /// Returns false if either post does not have id.
+    pub fn is_same_post(&self, other: &GenericPost<S>) -> bool {
+        self.kind() == other.kind() && self.id().is_some() && self.id() == other.id()
+    }
+
+    /// Perceptual hash of the image which is computed once when the post is created.
+    pub fn phash(&self) -> ImageHash {
+        match self {
+            &GenericPost::BluummPost(ref p) => p.phash,
+            &GenericPost::InstaPost(ref p) => p.phash,
+            &GenericPost::LocalPost(ref p) => p.phash,
+            &GenericPost::MastodonPost(ref p) => p.phash,
+            &GenericPost::ExternalPost(ref p) => p.phash,
+        }
+    }
+
+    /// Replaces the hash with the one stored with the post.
+    pub fn with_phash(mut self, phash: ImageHash) -> GenericPost<S> {
+        match self {
+            GenericPost::BluummPost(ref mut p) => p.phash = phash,
+            GenericPost::InstaPost(ref mut p) => p.phash = phash,
+            GenericPost::LocalPost(ref mut p) => p.phash = phash,
+            GenericPost::MastodonPost(ref mut p) => p.phash = phash,
+            GenericPost::ExternalPost(ref mut p) => p.phash = phash,
+        }
+        self
+    }
 }
 
 impl<S: Size> Post for GenericPost<S> {
@@ -113,18 +402,36 @@ impl<S: Size> Post for GenericPost<S> {
         match self {
             &GenericPost::BluummPost(ref p) => p.image(),
             &GenericPost::InstaPost(ref p) => p.image(),
+            &GenericPost::LocalPost(ref p) => p.image(),
+            &GenericPost::MastodonPost(ref p) => p.image(),
+            &GenericPost::ExternalPost(ref p) => p.image(),
         }
     }
     fn user_name(&self) -> &str {
         match self {
             &GenericPost::BluummPost(ref p) => p.user_name(),
             &GenericPost::InstaPost(ref p) => p.user_name(),
+            &GenericPost::LocalPost(ref p) => p.user_name(),
+            &GenericPost::MastodonPost(ref p) => p.user_name(),
+            &GenericPost::ExternalPost(ref p) => p.user_name(),
         }
     }
     fn hashtag(&self) -> &Hashtag {
         match self {
             &GenericPost::BluummPost(ref p) => p.hashtag(),
             &GenericPost::InstaPost(ref p) => p.hashtag(),
+            &GenericPost::LocalPost(ref p) => p.hashtag(),
+            &GenericPost::MastodonPost(ref p) => p.hashtag(),
+            &GenericPost::ExternalPost(ref p) => p.hashtag(),
+        }
+    }
+    fn hashtags(&self) -> Vec<Hashtag> {
+        match self {
+            &GenericPost::BluummPost(ref p) => p.hashtags(),
+            &GenericPost::InstaPost(ref p) => p.hashtags(),
+            &GenericPost::LocalPost(ref p) => p.hashtags(),
+            &GenericPost::MastodonPost(ref p) => p.hashtags(),
+            &GenericPost::ExternalPost(ref p) => p.hashtags(),
         }
     }
 }
@@ -132,9 +439,52 @@ impl<S: Size> Post for GenericPost<S> {
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Hashtag(pub Arc<String>);
 
+// Instagram does not accept longer hashtags.
+const MAX_HASHTAG_CHARS: usize = 100;
+
 impl Hashtag {
-    pub fn new<S: Into<String>>(s: S) -> Hashtag {
-        Hashtag(Arc::new(s.into()))
+    /// Normalizes `s` so that the same hashtag written in different ways is equal.
+    /// Leading `#` is stripped, then NFKC_Casefold is applied,
+    /// i.e. NFKC normalization, full case folding and NFKC normalization again.
+    /// Returns error if the result is empty, too long or contains characters other than
+    /// letters, digits, combining marks and `_`.
+    pub fn new<S: AsRef<str>>(s: S) -> Result<Hashtag, Error> {
+        let raw = s.as_ref();
+        // Full-width `#` and spaces are also stripped after NFKC normalization.
+        let nfkc: String = raw.nfkc().collect();
+        let normalized: String = nfkc.trim()
+            .trim_left_matches('#')
+            .chars()
+            .default_case_fold()
+            .nfkc()
+            .collect();
+        let invalid = |reason: String| ErrorKind::InvalidHashtag(raw.to_string(), reason);
+        if normalized.is_empty() {
+            bail!(invalid("hashtag is empty".into()));
+        }
+        if normalized.chars().count() > MAX_HASHTAG_CHARS {
+            bail!(invalid(format!("longer than {} characters", MAX_HASHTAG_CHARS)));
+        }
+        if let Some(c) = normalized.chars().find(|&c| !is_hashtag_char(c)) {
+            bail!(invalid(format!("{:?} is not allowed", c)));
+        }
+        Ok(Hashtag(Arc::new(normalized)))
+    }
+
+    /// Valid hashtags written in `text` such as a caption, without duplicates.
+    pub fn find_in(text: &str) -> Vec<Hashtag> {
+        let nfkc: String = text.nfkc().collect();
+        let mut hashtags: Vec<Hashtag> = Vec::new();
+        for word in nfkc.split('#').skip(1) {
+            let end = word.find(|c: char| !is_hashtag_char(c))
+                .unwrap_or(word.len());
+            if let Ok(hashtag) = Hashtag::new(&word[..end]) {
+                if !hashtags.contains(&hashtag) {
+                    hashtags.push(hashtag);
+                }
+            }
+        }
+        hashtags
     }
 
     pub fn as_str(&self) -> &str {
@@ -142,6 +492,11 @@ impl Hashtag {
     }
 }
 
+// Combining marks such as vowel signs of Devanagari and Thai are parts of words.
+fn is_hashtag_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || is_combining_mark(c)
+}
+
 impl Serialize for Hashtag {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -155,9 +510,16 @@ impl Serialize for Hashtag {
 pub struct HashtagList(pub Arc<Vec<Hashtag>>);
 
 impl HashtagList {
-    pub fn new(vec: Vec<String>) -> HashtagList {
-        let hashtags = vec.iter().map(|s| Hashtag::new(s.as_str())).collect();
-        HashtagList(Arc::new(hashtags))
+    /// Hashtags which are the same after normalization are merged.
+    pub fn new(vec: Vec<String>) -> Result<HashtagList, Error> {
+        let mut hashtags: Vec<Hashtag> = Vec::with_capacity(vec.len());
+        for s in vec {
+            let hashtag = Hashtag::new(s)?;
+            if !hashtags.contains(&hashtag) {
+                hashtags.push(hashtag);
+            }
+        }
+        Ok(HashtagList(Arc::new(hashtags)))
     }
 
     pub fn len(&self) -> usize {
@@ -199,3 +561,86 @@ impl Iterator for HashtagIterator {
         }
     }
 }
+
+/// Posts shared by tests.
+/// Every post has the same image and hashtag "tokyo".
+#[cfg(test)]
+pub mod fixtures {
+    use image::{Rgba, RgbaImage};
+    use images::{Image, SizedImage, size::Size100x100};
+    use super::*;
+
+    pub fn image() -> SizedImage<Size100x100> {
+        SizedImage::with_resize(Image::new(RgbaImage::from_fn(100, 100, |x, y| Rgba {
+            data: [x as u8, y as u8, (x * y) as u8, 255],
+        })))
+    }
+
+    pub fn tokyo() -> Hashtag {
+        Hashtag::new("tokyo").unwrap()
+    }
+
+    pub fn insta_post(id: &str, user_name: &str) -> GenericPost<Size100x100> {
+        let id = InstaPostId(id.to_string());
+        GenericPost::InstaPost(InstaPost::new(id, image(), user_name, tokyo(), vec![], false))
+    }
+
+    pub fn local_post(id: &str, user_name: &str) -> GenericPost<Size100x100> {
+        let id = LocalPostId(id.to_string());
+        GenericPost::LocalPost(LocalPost::new(id, image(), user_name, tokyo()))
+    }
+
+    pub fn bluumm_post(user_name: &str) -> BluummPost<Size100x100> {
+        BluummPost::new(image(), user_name, tokyo())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_hashtag() {
+        for s in &["tokyo", "#Tokyo", " #TOKYO ", "\u{ff54}\u{ff4f}\u{ff4b}\u{ff59}\u{ff4f}"] {
+            assert_eq!(Hashtag::new(s).unwrap().as_str(), "tokyo");
+        }
+        assert_eq!(Hashtag::new("#東京_2020").unwrap().as_str(), "東京_2020");
+        // Full case folding, not only lowercasing.
+        assert_eq!(Hashtag::new("straße").unwrap(), Hashtag::new("STRASSE").unwrap());
+        assert_eq!(Hashtag::new("ΣΊΣΥΦΟΣ").unwrap(), Hashtag::new("σίσυφος").unwrap());
+    }
+
+    #[test]
+    fn accept_combining_marks() {
+        // Vowel signs and virama are combining marks.
+        for s in &["#\u{939}\u{93f}\u{928}\u{94d}\u{926}\u{940}", "#\u{e01}\u{e23}\u{e38}\u{e07}"] {
+            let hashtag = Hashtag::new(s).unwrap();
+            assert_eq!(Hashtag::find_in(s), vec![hashtag]);
+        }
+    }
+
+    #[test]
+    fn reject_invalid_hashtag() {
+        for s in &["", "#", "tokyo tower", "tokyo#shibuya", "tokyo-tower"] {
+            assert!(Hashtag::new(s).is_err(), "{:?} should be rejected", s);
+        }
+        assert!(Hashtag::new("a".repeat(MAX_HASHTAG_CHARS + 1)).is_err());
+    }
+
+    #[test]
+    fn find_hashtags_in_caption() {
+        let caption = "Sunset at #Beach!\n#summer#BrandX, # #beach \u{ff03}ad";
+        let hashtags: Vec<String> = Hashtag::find_in(caption)
+            .into_iter()
+            .map(|h| h.as_str().to_string())
+            .collect();
+        assert_eq!(hashtags, vec!["beach", "summer", "brandx", "ad"]);
+    }
+
+    #[test]
+    fn merge_same_hashtags() {
+        let list = HashtagList::new(vec!["Tokyo".into(), "#tokyo".into(), "kyoto".into()]).unwrap();
+        let hashtags: Vec<&str> = list.0.iter().map(|h| h.as_str()).collect();
+        assert_eq!(hashtags, vec!["tokyo", "kyoto"]);
+    }
+}
@@ -0,0 +1,167 @@
+use std::sync::Arc;
+
+use post::{Hashtag, HashtagList};
+use error::{Error, ErrorKind};
+
+/// Boolean expression over hashtags which selects posts of a worker.
+/// It is evaluated against every hashtag on a post, including hashtags in its caption.
+/// Posts from a local directory or webhook only have the hashtag they were posted with,
+/// so that NOT can not exclude them by other hashtags.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HashtagQuery {
+    Tag(Hashtag),
+    And(Vec<HashtagQuery>),
+    Or(Vec<HashtagQuery>),
+    Not(Box<HashtagQuery>),
+}
+
+/// JSON form of `HashtagQuery`.
+/// e.g. `{"and": ["brandx", {"or": ["summer", "beach"]}, {"not": "ad"}]}`
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum RawHashtagQuery {
+    Tag(String),
+    And { and: Vec<RawHashtagQuery> },
+    Or { or: Vec<RawHashtagQuery> },
+    Not { not: Box<RawHashtagQuery> },
+}
+
+impl HashtagQuery {
+    /// Query which matches posts having any of `hashtags`.
+    pub fn any_of(hashtags: &HashtagList) -> HashtagQuery {
+        HashtagQuery::Or(hashtags.iter().map(HashtagQuery::Tag).collect())
+    }
+
+    /// Returns error if a hashtag is invalid or some posts matching the query can not be
+    /// searched, e.g. `{"or": ["a", {"not": "b"}]}` matches posts which have no hashtag
+    /// to be searched.
+    pub fn from_raw(raw: RawHashtagQuery) -> Result<HashtagQuery, Error> {
+        let query = HashtagQuery::convert(raw)?;
+        if !query.is_searchable(true) {
+            bail!(ErrorKind::InvalidHashtagQuery(
+                "every matching post must have a hashtag which is not under NOT".into()
+            ));
+        }
+        Ok(query)
+    }
+
+    // True if every post matching the query has one of the searched hashtags.
+    // `positive` is false under NOT, where AND and OR are swapped by De Morgan's laws.
+    fn is_searchable(&self, positive: bool) -> bool {
+        match (self, positive) {
+            (&HashtagQuery::Tag(_), _) => positive,
+            (&HashtagQuery::And(ref queries), true) | (&HashtagQuery::Or(ref queries), false) => {
+                queries.iter().any(|q| q.is_searchable(positive))
+            }
+            (&HashtagQuery::And(ref queries), false) | (&HashtagQuery::Or(ref queries), true) => {
+                queries.iter().all(|q| q.is_searchable(positive))
+            }
+            (&HashtagQuery::Not(ref query), _) => query.is_searchable(!positive),
+        }
+    }
+
+    fn convert(raw: RawHashtagQuery) -> Result<HashtagQuery, Error> {
+        let convert_all = |raws: Vec<RawHashtagQuery>| -> Result<Vec<HashtagQuery>, Error> {
+            if raws.is_empty() {
+                bail!(ErrorKind::InvalidHashtagQuery("empty AND or OR".into()));
+            }
+            raws.into_iter().map(HashtagQuery::convert).collect()
+        };
+        Ok(match raw {
+            RawHashtagQuery::Tag(s) => HashtagQuery::Tag(Hashtag::new(s)?),
+            RawHashtagQuery::And { and } => HashtagQuery::And(convert_all(and)?),
+            RawHashtagQuery::Or { or } => HashtagQuery::Or(convert_all(or)?),
+            RawHashtagQuery::Not { not } => {
+                HashtagQuery::Not(Box::new(HashtagQuery::convert(*not)?))
+            }
+        })
+    }
+
+    pub fn matches(&self, hashtags: &[Hashtag]) -> bool {
+        match self {
+            &HashtagQuery::Tag(ref tag) => hashtags.contains(tag),
+            &HashtagQuery::And(ref queries) => queries.iter().all(|q| q.matches(hashtags)),
+            &HashtagQuery::Or(ref queries) => queries.iter().any(|q| q.matches(hashtags)),
+            &HashtagQuery::Not(ref query) => !query.matches(hashtags),
+        }
+    }
+
+    /// Hashtags which are searched to find matching posts.
+    /// Excluded hashtags are not searched.
+    pub fn hashtags(&self) -> HashtagList {
+        let mut hashtags = Vec::new();
+        self.collect_hashtags(true, &mut hashtags);
+        HashtagList(Arc::new(hashtags))
+    }
+
+    fn collect_hashtags(&self, positive: bool, hashtags: &mut Vec<Hashtag>) {
+        match self {
+            &HashtagQuery::Tag(ref tag) => {
+                if positive && !hashtags.contains(tag) {
+                    hashtags.push(tag.clone());
+                }
+            }
+            &HashtagQuery::And(ref queries) | &HashtagQuery::Or(ref queries) => {
+                for q in queries {
+                    q.collect_hashtags(positive, hashtags);
+                }
+            }
+            &HashtagQuery::Not(ref query) => query.collect_hashtags(!positive, hashtags),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(tags: &[&str]) -> Vec<Hashtag> {
+        tags.iter().map(|t| Hashtag::new(t).unwrap()).collect()
+    }
+
+    fn brand_query() -> HashtagQuery {
+        let raw = ::serde_json::from_str(
+            r##"{"and": ["#BrandX", {"or": ["summer", "beach"]}, {"not": "ad"}]}"##,
+        ).unwrap();
+        HashtagQuery::from_raw(raw).unwrap()
+    }
+
+    #[test]
+    fn evaluate_query() {
+        let query = brand_query();
+        assert!(query.matches(&tags(&["brandx", "beach"])));
+        assert!(query.matches(&tags(&["summer", "brandx", "tokyo"])));
+        assert!(!query.matches(&tags(&["brandx"])));
+        assert!(!query.matches(&tags(&["summer", "beach"])));
+        assert!(!query.matches(&tags(&["brandx", "beach", "ad"])));
+    }
+
+    #[test]
+    fn search_only_required_hashtags() {
+        assert_eq!(
+            brand_query().hashtags(),
+            HashtagList(Arc::new(tags(&["brandx", "summer", "beach"])))
+        );
+    }
+
+    #[test]
+    fn reject_query_without_required_hashtag() {
+        let raw = ::serde_json::from_str(r#"{"not": "ad"}"#).unwrap();
+        assert!(HashtagQuery::from_raw(raw).is_err());
+        let raw = ::serde_json::from_str(r#"{"or": []}"#).unwrap();
+        assert!(HashtagQuery::from_raw(raw).is_err());
+    }
+
+    #[test]
+    fn reject_query_matching_posts_without_searched_hashtag() {
+        let check = |json: &str| HashtagQuery::from_raw(::serde_json::from_str(json).unwrap());
+        assert!(check(r#"{"or": ["a", {"not": "b"}]}"#).is_err());
+        assert!(check(r#"{"and": [{"not": "a"}, {"or": ["b", {"not": "c"}]}]}"#).is_err());
+        assert!(check(r#"{"not": {"and": ["a", "b"]}}"#).is_err());
+
+        assert!(check(r#"{"or": ["a", {"and": ["b", {"not": "c"}]}]}"#).is_ok());
+        // Same as `{"and": ["a", {"not": "b"}]}`.
+        assert!(check(r#"{"not": {"or": [{"not": "a"}, "b"]}}"#).is_ok());
+        assert!(check(r#"{"not": {"not": "a"}}"#).is_ok());
+    }
+}
@@ -0,0 +1,57 @@
+use std::sync::Arc;
+use futures::{Stream, stream::empty};
+
+use insta::{HashtagPollers, InstaFeeder, PollStream};
+use images::Size;
+use post::{GenericPost, Hashtag, HashtagList, InstaPost};
+use error::Error;
+use super::{PostSource, PostStream};
+
+/// Workers watching the same hashtag share one poller of the hashtag
+/// so that every new post is fetched once and sent to all of them.
+pub struct InstaSource<SS> {
+    feeder: Arc<InstaFeeder>,
+    pollers: HashtagPollers<InstaPost<SS>>,
+}
+
+impl<SS: Size> InstaSource<SS> {
+    pub fn new(feeder: Arc<InstaFeeder>) -> InstaSource<SS> {
+        let feeder2 = feeder.clone();
+        let pollers = HashtagPollers::new(move |hashtag: &Hashtag| {
+            let hashtags = HashtagList(Arc::new(vec![hashtag.clone()]));
+            Box::new(feeder2.get_update_posts(&hashtags)) as PollStream<InstaPost<SS>>
+        });
+        InstaSource {
+            feeder: feeder,
+            pollers: pollers,
+        }
+    }
+}
+
+impl<SS: Size> PostSource<SS> for InstaSource<SS> {
+    // Backfill ends instead of stopping the worker if Instagram keeps failing.
+    fn backfill(&self, hashtags: &HashtagList) -> PostStream<SS> {
+        let stream = self.feeder
+            .get_bunch_of_posts(hashtags)
+            .then(|res| {
+                if let Err(ref e) = res {
+                    warn!("Stop backfill of Instagram posts : {}", e);
+                }
+                Ok::<_, Error>(res.ok())
+            })
+            .take_while(|p| Ok(p.is_some()))
+            .filter_map(|p| p.map(GenericPost::InstaPost));
+        Box::new(stream)
+    }
+
+    fn updates(&self, hashtags: &HashtagList) -> PostStream<SS> {
+        hashtags
+            .iter()
+            .fold(Box::new(empty()) as PostStream<SS>, |merged, hashtag| {
+                let subscription = self.pollers
+                    .subscribe(&hashtag)
+                    .map(|p| GenericPost::InstaPost(p));
+                Box::new(merged.select(subscription)) as PostStream<SS>
+            })
+    }
+}
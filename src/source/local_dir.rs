@@ -0,0 +1,385 @@
+use std::{collections::{HashMap, HashSet}, fs, path::{Path, PathBuf}, sync::{Arc, Mutex},
+          thread, time::{Duration, SystemTime}};
+use futures::{Stream, stream::empty, sync::mpsc::{self, UnboundedSender}};
+
+use images::{Image, Size, SizedImage};
+use post::{GenericPost, Hashtag, HashtagList, LocalPost, LocalPostId, Post};
+use db::Mongodb;
+use util::sha256_hex;
+use error::Error;
+use super::{PostSource, PostStream};
+
+const IMAGE_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "gif", "bmp", "webp"];
+
+// An image file without sidecar is not read until this time passes since it is modified,
+// because its sidecar may be written after it.
+const SIDECAR_WAIT_SECS: u64 = 10;
+
+#[derive(Debug, Clone)]
+pub struct LocalDirConfig {
+    pub dir: PathBuf,
+    // Used when an image does not have a sidecar or its sidecar does not have a hashtag.
+    pub default_hashtag: Option<String>,
+    pub poll_interval: Duration,
+}
+
+/// Watches a directory and feeds new image files as posts.
+///
+/// An image file may have a JSON sidecar which has the same file stem
+/// (e.g. "photo.json" for "photo.jpg") like
+/// `{ "user_name": "alice", "hashtag": "wedding" }`.
+///
+/// The directory is scanned in a dedicated thread so that blocking file reads never stall
+/// workers. New posts are sent to every running worker whose hashtags contain
+/// the hashtag of the post.
+pub struct LocalDirSource<SS> {
+    subscribers: Arc<Mutex<Vec<Subscriber<SS>>>>,
+}
+
+struct Subscriber<SS> {
+    hashtags: HashtagList,
+    tx: UnboundedSender<LocalPost<SS>>,
+}
+
+#[derive(Deserialize, Default)]
+struct Sidecar {
+    user_name: Option<String>,
+    hashtag: Option<String>,
+}
+
+impl<SS: Size> LocalDirSource<SS> {
+    pub fn new(config: LocalDirConfig, db: Mongodb) -> LocalDirSource<SS> {
+        info!("Watch local directory {:?}", config.dir);
+        let subscribers = Arc::new(Mutex::new(Vec::new()));
+        let subscribers2 = subscribers.clone();
+        let poll_interval = config.poll_interval;
+        let mut scanner = Scanner {
+            config: config,
+            db: db,
+            modified: HashMap::new(),
+            seen: HashSet::new(),
+            sidecar_wait: Duration::from_secs(SIDECAR_WAIT_SECS),
+        };
+
+        thread::spawn(move || loop {
+            for post in scanner.scan::<SS>() {
+                publish(&subscribers2, post);
+            }
+            thread::sleep(poll_interval);
+        });
+
+        LocalDirSource {
+            subscribers: subscribers,
+        }
+    }
+}
+
+impl<SS: Size> PostSource<SS> for LocalDirSource<SS> {
+    // Files which existed before are loaded from db by worker.
+    fn backfill(&self, _hashtags: &HashtagList) -> PostStream<SS> {
+        Box::new(empty())
+    }
+
+    fn updates(&self, hashtags: &HashtagList) -> PostStream<SS> {
+        let (tx, rx) = mpsc::unbounded();
+        self.subscribers.lock().unwrap().push(Subscriber {
+            hashtags: hashtags.clone(),
+            tx: tx,
+        });
+        let stream = rx.map(|p| GenericPost::LocalPost(p))
+            .then(|res| Ok::<_, Error>(res.unwrap()));
+        Box::new(stream)
+    }
+}
+
+// Subscribers of stopped workers are removed.
+fn publish<SS: Size>(subscribers: &Mutex<Vec<Subscriber<SS>>>, post: LocalPost<SS>) {
+    subscribers.lock().unwrap().retain(|subscriber| {
+        if !subscriber.hashtags.iter().any(|h| &h == post.hashtag()) {
+            return true;
+        }
+        subscriber.tx.unbounded_send(post.clone()).is_ok()
+    });
+}
+
+// Shared by every worker. Each file is read and stored once.
+struct Scanner {
+    config: LocalDirConfig,
+    db: Mongodb,
+    // Last modified time of each image file and its sidecar which have been read.
+    modified: HashMap<PathBuf, (SystemTime, Option<SystemTime>)>,
+    // Posts which have been stored.
+    seen: HashSet<LocalPostId>,
+    sidecar_wait: Duration,
+}
+
+impl Scanner {
+    fn scan<SS: Size>(&mut self) -> Vec<LocalPost<SS>> {
+        let entries = match fs::read_dir(&self.config.dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Fail to read directory {:?} : {:?}", self.config.dir, e);
+                return Vec::new();
+            }
+        };
+
+        let mut posts = Vec::new();
+        for entry in entries.filter_map(|e| e.ok()) {
+            let path = entry.path();
+            if !is_image_file(&path) {
+                continue;
+            }
+            let modified = match modified_time(&path) {
+                Some(modified) => modified,
+                None => continue,
+            };
+            // Image is read again when its sidecar is written or updated.
+            let sidecar_modified = modified_time(&path.with_extension("json"));
+            if self.modified.get(&path) == Some(&(modified, sidecar_modified)) {
+                continue;
+            }
+            if sidecar_modified.is_none() && self.is_recent(modified) {
+                continue;
+            }
+            self.modified.insert(path.clone(), (modified, sidecar_modified));
+
+            match self.read_post(&path) {
+                Ok(Some(post)) => posts.push(post),
+                Ok(None) => {}
+                Err(e) => warn!("Fail to read {:?} : {:?}", path, e),
+            }
+        }
+        posts
+    }
+
+    fn is_recent(&self, modified: SystemTime) -> bool {
+        SystemTime::now()
+            .duration_since(modified)
+            .map(|elapsed| elapsed < self.sidecar_wait)
+            .unwrap_or(false)
+    }
+
+    // A post is not marked as seen until its metadata is resolved,
+    // so that it is read again when its sidecar is fixed.
+    fn read_post<SS: Size>(&mut self, path: &Path) -> Result<Option<LocalPost<SS>>, Error> {
+        let bytes = fs::read(path)?;
+        let id = LocalPostId(sha256_hex(bytes.as_slice()));
+        if self.seen.contains(&id) {
+            return Ok(None);
+        }
+        // Stored posts are loaded from db by workers.
+        if self.db.contains_post("local", id.as_str()) {
+            self.seen.insert(id);
+            return Ok(None);
+        }
+
+        let (user_name, hashtag) =
+            match read_metadata(path, self.config.default_hashtag.as_ref())? {
+                Some(metadata) => metadata,
+                None => return Ok(None),
+            };
+        if self.db.is_blocked_user(user_name.as_str()) {
+            self.seen.insert(id);
+            return Ok(None);
+        }
+        let image = SizedImage::with_resize(Image::from_bytes(bytes.as_slice())?);
+        let post = LocalPost::new(id, image, user_name, hashtag);
+        self.db.insert_post(&GenericPost::LocalPost(post.clone()), None);
+        self.seen.insert(post.post_id.clone());
+        info!("New local post : {:?}", path);
+        Ok(Some(post))
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+// Returns user name and hashtag of an image file.
+// `None` is returned if the file does not have a valid hashtag.
+fn read_metadata(
+    path: &Path,
+    default_hashtag: Option<&String>,
+) -> Result<Option<(String, Hashtag)>, Error> {
+    let sidecar = read_sidecar(path)?;
+    let hashtag = match sidecar.hashtag.or(default_hashtag.cloned()) {
+        Some(hashtag) => match Hashtag::new(hashtag) {
+            Ok(hashtag) => hashtag,
+            Err(e) => {
+                warn!("{:?} has invalid hashtag : {}", path, e);
+                return Ok(None);
+            }
+        },
+        None => {
+            warn!("{:?} does not have any hashtag", path);
+            return Ok(None);
+        }
+    };
+    Ok(Some((sidecar.user_name.unwrap_or_default(), hashtag)))
+}
+
+fn is_image_file(path: &Path) -> bool {
+    path.is_file() && path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| IMAGE_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+fn read_sidecar(path: &Path) -> Result<Sidecar, Error> {
+    let sidecar_path = path.with_extension("json");
+    if !sidecar_path.exists() {
+        return Ok(Sidecar::default());
+    }
+    let bytes = fs::read(sidecar_path)?;
+    Ok(::serde_json::from_slice(bytes.as_slice())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgba, RgbaImage};
+    use images::size::Size30x30;
+    use db::test_db;
+    use util::TempDir;
+    use takedown::Takedown;
+
+    fn temp_dir() -> TempDir {
+        TempDir::new("local_dir")
+    }
+
+    fn scanner(name: &str, dir: &TempDir, default_hashtag: Option<&str>) -> Scanner {
+        Scanner {
+            config: LocalDirConfig {
+                dir: dir.path().to_path_buf(),
+                default_hashtag: default_hashtag.map(|h| h.to_string()),
+                poll_interval: Duration::from_secs(1),
+            },
+            db: test_db(name),
+            modified: HashMap::new(),
+            seen: HashSet::new(),
+            sidecar_wait: Duration::from_secs(3600),
+        }
+    }
+
+    fn write_image(path: &Path) {
+        let image = Image::new(RgbaImage::from_pixel(30, 30, Rgba { data: [0, 0, 0, 255] }));
+        fs::write(path, image.to_png_bytes()).unwrap();
+    }
+
+    #[test]
+    #[ignore] // requires MongoDB
+    fn wait_for_sidecar_written_after_image() {
+        let dir = temp_dir();
+        let mut scanner = scanner("sidecar_after_image", &dir, Some("party"));
+        write_image(&dir.path().join("photo.png"));
+        assert!(scanner.scan::<Size30x30>().is_empty());
+
+        fs::write(
+            dir.path().join("photo.json"),
+            r#"{ "user_name": "alice", "hashtag": "wedding" }"#,
+        ).unwrap();
+        let posts = scanner.scan::<Size30x30>();
+        assert_eq!(posts.len(), 1);
+        assert_eq!(posts[0].user_name(), "alice");
+        assert_eq!(posts[0].hashtag(), &Hashtag::new("wedding").unwrap());
+        assert!(scanner.scan::<Size30x30>().is_empty());
+    }
+
+    #[test]
+    #[ignore] // requires MongoDB
+    fn skip_images_of_blocked_users() {
+        let dir = temp_dir();
+        let mut scanner = scanner("local_blocked_user", &dir, None);
+        scanner.sidecar_wait = Duration::from_secs(0);
+        scanner.db.add_to_blocklist(&Takedown::User("alice".into()));
+        write_image(&dir.path().join("photo.png"));
+        fs::write(
+            dir.path().join("photo.json"),
+            r#"{ "user_name": "alice", "hashtag": "wedding" }"#,
+        ).unwrap();
+        assert!(scanner.scan::<Size30x30>().is_empty());
+        let hashtags = HashtagList::new(vec!["wedding".into()]).unwrap();
+        assert!(scanner.db.find_posts_by_hashtags::<Size30x30>(&hashtags, &[], 1).is_empty());
+    }
+
+    #[test]
+    #[ignore] // requires MongoDB
+    fn read_image_again_when_sidecar_is_written() {
+        let dir = temp_dir();
+        let mut scanner = scanner("sidecar_written_later", &dir, None);
+        scanner.sidecar_wait = Duration::from_secs(0);
+        write_image(&dir.path().join("photo.png"));
+        // Skipped because it has no hashtag.
+        assert!(scanner.scan::<Size30x30>().is_empty());
+
+        fs::write(dir.path().join("photo.json"), r#"{ "hashtag": "wedding" }"#).unwrap();
+        assert_eq!(scanner.scan::<Size30x30>().len(), 1);
+    }
+
+    #[test]
+    fn detect_image_file_by_extension() {
+        let dir = temp_dir();
+        let dir = dir.path();
+        for name in &["a.jpg", "b.PNG", "c.json", "d"] {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+        assert!(is_image_file(&dir.join("a.jpg")));
+        assert!(is_image_file(&dir.join("b.PNG")));
+        assert!(!is_image_file(&dir.join("c.json")));
+        assert!(!is_image_file(&dir.join("d")));
+        assert!(!is_image_file(&dir.join("missing.jpg")));
+    }
+
+    #[test]
+    fn read_metadata_from_sidecar() {
+        let temp = temp_dir();
+        let dir = temp.path();
+        fs::write(
+            dir.join("photo.json"),
+            r#"{ "user_name": "alice", "hashtag": "#Wedding" }"#,
+        ).unwrap();
+        let default = "party".to_string();
+        let (user_name, hashtag) = read_metadata(&dir.join("photo.jpg"), Some(&default))
+            .unwrap()
+            .unwrap();
+        assert_eq!(user_name, "alice");
+        assert_eq!(hashtag, Hashtag::new("wedding").unwrap());
+    }
+
+    #[test]
+    fn use_default_hashtag_without_sidecar_hashtag() {
+        let temp = temp_dir();
+        let dir = temp.path();
+        fs::write(dir.join("photo.json"), r#"{ "user_name": "alice" }"#).unwrap();
+        let default = "party".to_string();
+        let (user_name, hashtag) = read_metadata(&dir.join("photo.jpg"), Some(&default))
+            .unwrap()
+            .unwrap();
+        assert_eq!(user_name, "alice");
+        assert_eq!(hashtag, Hashtag::new("party").unwrap());
+
+        let (user_name, hashtag) = read_metadata(&dir.join("other.jpg"), Some(&default))
+            .unwrap()
+            .unwrap();
+        assert_eq!(user_name, "");
+        assert_eq!(hashtag, Hashtag::new("party").unwrap());
+    }
+
+    #[test]
+    fn skip_file_without_valid_hashtag() {
+        let temp = temp_dir();
+        let dir = temp.path();
+        fs::write(dir.join("invalid.json"), r#"{ "hashtag": "not a tag" }"#).unwrap();
+        let default = "party".to_string();
+        assert!(read_metadata(&dir.join("photo.jpg"), None).unwrap().is_none());
+        assert!(read_metadata(&dir.join("invalid.jpg"), Some(&default)).unwrap().is_none());
+    }
+
+    #[test]
+    fn fail_on_broken_sidecar() {
+        let temp = temp_dir();
+        let dir = temp.path();
+        fs::write(dir.join("photo.json"), "{").unwrap();
+        assert!(read_metadata(&dir.join("photo.jpg"), None).is_err());
+    }
+}
@@ -0,0 +1,466 @@
+use std::{collections::HashMap, str::FromStr, sync::{Arc, Mutex}, time::{Duration, Instant}};
+use futures::{Future, IntoFuture, Stream, future, stream::{iter_ok, unfold}};
+use hyper::{Body, Request, Uri, client::{Client, HttpConnector}, header::AUTHORIZATION};
+use hyper_tls::HttpsConnector;
+use tokio::timer::Interval;
+use percent_encoding::{utf8_percent_encode, PATH_SEGMENT_ENCODE_SET};
+
+use images::{ImageFetcher, Size};
+use post::{GenericPost, Hashtag, HashtagList, MastodonPost, MastodonPostId};
+use db::Mongodb;
+use error::{Error, ErrorKind};
+use super::{is_http_url, PostSource, PostStream};
+
+// Max number of statuses Mastodon returns at once.
+const PAGE_LIMIT: usize = 40;
+const DOWNLOAD_CONCURRENCY: usize = 4;
+
+#[derive(Debug, Clone)]
+pub struct MastodonConfig {
+    // e.g. "https://mastodon.social"
+    pub instance_url: String,
+    // Some instances require authorization to read public timelines.
+    pub access_token: Option<String>,
+    pub poll_interval: Duration,
+    // Max number of pages read for each hashtag before a worker starts.
+    pub backfill_pages: usize,
+}
+
+/// Polls hashtag timelines of a Mastodon instance.
+/// Each image attachment of a status becomes a post.
+pub struct MastodonSource {
+    api: MastodonApi,
+    db: Mongodb,
+    image_fetcher: Arc<ImageFetcher>,
+}
+
+impl MastodonSource {
+    pub fn new(
+        config: MastodonConfig,
+        db: Mongodb,
+        image_fetcher: Arc<ImageFetcher>,
+    ) -> MastodonSource {
+        info!("Poll hashtag timelines of {}", config.instance_url);
+        MastodonSource {
+            api: MastodonApi::new(config),
+            db: db,
+            image_fetcher: image_fetcher,
+        }
+    }
+
+    fn complete_posts<SS, St>(
+        &self,
+        media: St,
+    ) -> impl Stream<Item = GenericPost<SS>, Error = Error>
+    where
+        SS: Size,
+        St: Stream<Item = (Hashtag, Media), Error = Error>,
+    {
+        let db = self.db.clone();
+        let db2 = self.db.clone();
+        let image_fetcher = self.image_fetcher.clone();
+
+        media
+            .filter(move |(_, m)| {
+                !db.contains_post("mastodon", m.id.as_str())
+                    && !db.is_blocked_user(m.user_name.as_str())
+            })
+            .map(move |(hashtag, m)| {
+                info!("New mastodon post : {:?}", m);
+                let db = db2.clone();
+                let image_url = m.image_url.clone();
+                image_fetcher
+                    .fetch_image::<SS>(m.image_url.as_str())
+                    .into_future()
+                    .and_then(|img_fut| img_fut)
+                    .map(move |img| {
+                        let post = GenericPost::MastodonPost(MastodonPost::new(
+                            m.id,
+                            m.status_url,
+                            img,
+                            m.user_name,
+                            hashtag,
+                            m.tags,
+                        ));
+                        db.insert_post(&post, Some(m.image_url.as_str()));
+                        post
+                    })
+                    // An attachment which can not be fetched is just skipped.
+                    .then(move |res| match res {
+                        Ok(post) => Ok::<_, Error>(Some(post)),
+                        Err(e) => {
+                            warn!("Fail to fetch {} : {:?}", image_url, e);
+                            Ok(None)
+                        }
+                    })
+            })
+            .buffered(DOWNLOAD_CONCURRENCY)
+            .filter_map(|post| post)
+    }
+}
+
+impl<SS: Size> PostSource<SS> for MastodonSource {
+    fn backfill(&self, hashtags: &HashtagList) -> PostStream<SS> {
+        let api = self.api.clone();
+        let media = iter_ok::<_, Error>(hashtags.iter())
+            .map(move |hashtag| api.older_media(hashtag))
+            .flatten();
+        Box::new(self.complete_posts(media))
+    }
+
+    fn updates(&self, hashtags: &HashtagList) -> PostStream<SS> {
+        let api = self.api.clone();
+        let hashtags = hashtags.clone();
+        // Newest status id of each hashtag which has been read.
+        let since_ids = Arc::new(Mutex::new(HashMap::new()));
+        let media = Interval::new(Instant::now(), self.api.config.poll_interval)
+            .map_err(Error::from)
+            .map(move |_| {
+                let (api, since_ids) = (api.clone(), since_ids.clone());
+                iter_ok::<_, Error>(hashtags.iter())
+                    .map(move |hashtag| api.newer_media(hashtag, since_ids.clone()))
+                    .flatten()
+            })
+            .flatten();
+        Box::new(self.complete_posts(media))
+    }
+}
+
+#[derive(Debug)]
+struct Media {
+    id: MastodonPostId,
+    status_url: String,
+    image_url: String,
+    user_name: String,
+    tags: Vec<Hashtag>,
+}
+
+#[derive(Deserialize)]
+struct Status {
+    id: String,
+    uri: String,
+    url: Option<String>,
+    #[serde(default)]
+    sensitive: bool,
+    account: Account,
+    media_attachments: Vec<Attachment>,
+    #[serde(default)]
+    tags: Vec<Tag>,
+}
+
+#[derive(Deserialize)]
+struct Account {
+    acct: String,
+}
+
+#[derive(Deserialize)]
+struct Tag {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct Attachment {
+    id: String,
+    #[serde(rename = "type")]
+    kind: String,
+    // Null while the media is being processed.
+    url: Option<String>,
+}
+
+#[derive(Clone)]
+struct MastodonApi {
+    client: Client<HttpsConnector<HttpConnector>>,
+    config: Arc<MastodonConfig>,
+    // Used as prefix of post id because attachment id is unique only in an instance.
+    host: String,
+}
+
+impl MastodonApi {
+    fn new(config: MastodonConfig) -> MastodonApi {
+        let host = Uri::from_str(config.instance_url.as_str())
+            .ok()
+            .and_then(|uri| uri.host().map(|h| h.to_string()))
+            .unwrap_or(config.instance_url.clone());
+        MastodonApi {
+            client: Client::builder().build(HttpsConnector::new(1).unwrap()),
+            config: Arc::new(config),
+            host: host,
+        }
+    }
+
+    // Walks pages from the newest status to older ones by `max_id`.
+    fn older_media(&self, hashtag: Hashtag) -> impl Stream<Item = (Hashtag, Media), Error = Error> {
+        let api = self.clone();
+        let host = self.host.clone();
+        let hashtag2 = hashtag.clone();
+        let max_pages = self.config.backfill_pages;
+        unfold((None, 0, true), move |(max_id, page, has_next)| {
+            if has_next == false || page >= max_pages {
+                return None;
+            }
+            let f = api.get_tag_timeline(&hashtag, max_id, None).then(move |res| {
+                let statuses = match res {
+                    Ok(statuses) => statuses,
+                    Err(e) => {
+                        warn!("Fail to read mastodon timeline : {:?}", e);
+                        Vec::new()
+                    }
+                };
+                let max_id = statuses.last().map(|s| s.id.clone());
+                let has_next = max_id.is_some();
+                Ok::<_, Error>((statuses, (max_id, page + 1, has_next)))
+            });
+            Some(f)
+        }).map(move |statuses| iter_ok::<_, Error>(to_media(&hashtag2, host.as_str(), statuses)))
+            .flatten()
+    }
+
+    // Walks pages from the last read status to newer ones by `min_id` until caught up.
+    // Only the newest page is read at the first poll because older ones are backfilled.
+    fn newer_media(
+        &self,
+        hashtag: Hashtag,
+        since_ids: Arc<Mutex<HashMap<Hashtag, String>>>,
+    ) -> impl Stream<Item = (Hashtag, Media), Error = Error> {
+        let api = self.clone();
+        let host = self.host.clone();
+        let hashtag2 = hashtag.clone();
+        let min_id = since_ids.lock().unwrap().get(&hashtag).cloned();
+        unfold(Some(min_id), move |min_id| {
+            let min_id = min_id?;
+            let (hashtag, since_ids) = (hashtag.clone(), since_ids.clone());
+            let f = api.get_tag_timeline(&hashtag, None, min_id.clone())
+                .then(move |res| match res {
+                    Ok(statuses) => {
+                        // Page right after `min_id` is also sorted from the newest.
+                        let newest = statuses.first().map(|s| s.id.clone());
+                        if let Some(ref newest) = newest {
+                            since_ids.lock().unwrap().insert(hashtag, newest.clone());
+                        }
+                        let next = match min_id.is_some() && statuses.len() >= PAGE_LIMIT {
+                            true => newest.map(Some),
+                            false => None,
+                        };
+                        Ok::<_, Error>((statuses, next))
+                    }
+                    // Polling should continue even if a request fails.
+                    // Unread statuses are read at the next poll.
+                    Err(e) => {
+                        warn!("Fail to read mastodon timeline : {:?}", e);
+                        Ok((Vec::new(), None))
+                    }
+                });
+            Some(f)
+        }).map(move |statuses| iter_ok::<_, Error>(to_media(&hashtag2, host.as_str(), statuses)))
+            .flatten()
+    }
+
+    fn get_tag_timeline(
+        &self,
+        hashtag: &Hashtag,
+        max_id: Option<String>,
+        min_id: Option<String>,
+    ) -> impl Future<Item = Vec<Status>, Error = Error> {
+        let mut url = format!(
+            "{}/api/v1/timelines/tag/{}?limit={}",
+            self.config.instance_url,
+            utf8_percent_encode(hashtag.as_str(), PATH_SEGMENT_ENCODE_SET),
+            PAGE_LIMIT
+        );
+        if let Some(max_id) = max_id {
+            url.push_str(format!("&max_id={}", max_id).as_str());
+        }
+        if let Some(min_id) = min_id {
+            url.push_str(format!("&min_id={}", min_id).as_str());
+        }
+
+        let client = self.client.clone();
+        let config = self.config.clone();
+        future::result(build_request(&config, url.as_str()))
+            .and_then(move |req| client.request(req).map_err(Error::from))
+            .and_then(move |res| {
+                let status = res.status().as_u16();
+                res.into_body()
+                    .concat2()
+                    .map_err(Error::from)
+                    .and_then(move |chunk| -> Result<Vec<Status>, Error> {
+                        if status < 200 || status >= 300 {
+                            bail!(ErrorKind::FetchBadStatus(url, status));
+                        }
+                        Ok(::serde_json::from_slice(&chunk)?)
+                    })
+            })
+    }
+}
+
+fn build_request(config: &MastodonConfig, url: &str) -> Result<Request<Body>, Error> {
+    let mut req = Request::get(Uri::from_str(url)?);
+    if let Some(ref token) = config.access_token {
+        req.header(AUTHORIZATION, format!("Bearer {}", token).as_str());
+    }
+    Ok(req.body(Body::empty())?)
+}
+
+// Sensitive statuses are skipped because arts are displayed in public.
+fn to_media(hashtag: &Hashtag, host: &str, statuses: Vec<Status>) -> Vec<(Hashtag, Media)> {
+    let mut media = Vec::new();
+    for status in statuses.into_iter().filter(|s| !s.sensitive) {
+        let status_url = status.url.unwrap_or(status.uri);
+        let tags: Vec<Hashtag> = status.tags
+            .iter()
+            .filter_map(|t| Hashtag::new(t.name.as_str()).ok())
+            .collect();
+        for attachment in status.media_attachments {
+            if attachment.kind != "image" {
+                continue;
+            }
+            let image_url = match attachment.url {
+                Some(ref url) if !is_http_url(url) => {
+                    warn!("Attachment {} has unsupported url : {}", attachment.id, url);
+                    continue;
+                }
+                Some(url) => url,
+                None => {
+                    debug!("Attachment {} is still being processed", attachment.id);
+                    continue;
+                }
+            };
+            media.push((
+                hashtag.clone(),
+                Media {
+                    id: MastodonPostId(format!("{}/{}", host, attachment.id)),
+                    status_url: status_url.clone(),
+                    image_url: image_url,
+                    user_name: status.account.acct.clone(),
+                    tags: tags.clone(),
+                },
+            ));
+        }
+    }
+    media
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::SocketAddr, thread};
+    use hyper::{Response, Server, service::service_fn_ok};
+    use tokio::runtime::Runtime;
+
+    // Serves a tag timeline of statuses whose ids are 1 to `count`.
+    fn serve_timeline(count: usize) -> SocketAddr {
+        let (addr_tx, addr_rx) = ::std::sync::mpsc::channel();
+        thread::spawn(move || {
+            let addr = ([127, 0, 0, 1], 0).into();
+            let server = Server::bind(&addr).serve(move || {
+                service_fn_ok(move |req: Request<Body>| {
+                    let param = |key: &str| {
+                        req.uri()
+                            .query()
+                            .unwrap_or("")
+                            .split('&')
+                            .filter_map(|kv| {
+                                let mut kv = kv.splitn(2, '=');
+                                match (kv.next(), kv.next()) {
+                                    (Some(k), Some(v)) if k == key => v.parse::<usize>().ok(),
+                                    _ => None,
+                                }
+                            })
+                            .next()
+                    };
+                    let limit = param("limit").unwrap_or(PAGE_LIMIT);
+                    // Oldest ones after `min_id` or the newest ones, sorted from the newest.
+                    let ids = match param("min_id") {
+                        Some(min_id) => min_id + 1..(min_id + limit).min(count) + 1,
+                        None => count.saturating_sub(limit) + 1..count + 1,
+                    };
+                    let statuses: Vec<String> = ids.rev()
+                        .map(|id| {
+                            format!(
+                                r#"{{"id":"{}","uri":"https://m.example/s/{}","url":null,
+                                "account":{{"acct":"alice"}},"media_attachments":[
+                                {{"id":"{}","type":"image","url":"https://m.example/{}.png"}}]}}"#,
+                                id, id, id, id
+                            )
+                        })
+                        .collect();
+                    Response::new(Body::from(format!("[{}]", statuses.join(","))))
+                })
+            });
+            addr_tx.send(server.local_addr()).unwrap();
+            ::tokio::run(server.map_err(|e| error!("Test server error : {:?}", e)));
+        });
+        addr_rx.recv().unwrap()
+    }
+
+    fn api(addr: SocketAddr) -> MastodonApi {
+        MastodonApi::new(MastodonConfig {
+            instance_url: format!("http://{}", addr),
+            access_token: None,
+            poll_interval: Duration::from_secs(1),
+            backfill_pages: 1,
+        })
+    }
+
+    #[test]
+    fn follow_pages_until_caught_up() {
+        let api = api(serve_timeline(100));
+        let tokyo = Hashtag::new("tokyo").unwrap();
+        let since_ids = Arc::new(Mutex::new(HashMap::new()));
+        since_ids.lock().unwrap().insert(tokyo.clone(), "10".to_string());
+
+        let media = Runtime::new()
+            .unwrap()
+            .block_on(api.newer_media(tokyo.clone(), since_ids.clone()).collect())
+            .unwrap();
+        let mut ids: Vec<String> = media.into_iter().map(|(_, m)| m.id.0).collect();
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), 90);
+        assert_eq!(since_ids.lock().unwrap().get(&tokyo), Some(&"100".to_string()));
+    }
+
+    #[test]
+    fn read_only_newest_page_at_first_poll() {
+        let api = api(serve_timeline(100));
+        let tokyo = Hashtag::new("tokyo").unwrap();
+        let since_ids = Arc::new(Mutex::new(HashMap::new()));
+
+        let media = Runtime::new()
+            .unwrap()
+            .block_on(api.newer_media(tokyo.clone(), since_ids.clone()).collect())
+            .unwrap();
+        assert_eq!(media.len(), PAGE_LIMIT);
+        assert_eq!(since_ids.lock().unwrap().get(&tokyo), Some(&"100".to_string()));
+    }
+
+    #[test]
+    fn extract_image_attachments() {
+        let statuses: Vec<Status> = ::serde_json::from_str(
+            r#"[
+                {"id": "2", "uri": "https://m.example/s/2", "url": null, "sensitive": false,
+                 "account": {"acct": "alice"},
+                 "tags": [{"name": "Tokyo"}, {"name": "Ad"}],
+                 "media_attachments": [
+                    {"id": "20", "type": "image", "url": "https://m.example/20.png"},
+                    {"id": "21", "type": "video", "url": "https://m.example/21.mp4"},
+                    {"id": "22", "type": "image", "url": null},
+                    {"id": "23", "type": "image", "url": "file:///etc/passwd"}]},
+                {"id": "1", "uri": "https://m.example/s/1", "url": "https://m.example/@bob/1",
+                 "sensitive": true, "account": {"acct": "bob"},
+                 "media_attachments": [
+                    {"id": "10", "type": "image", "url": "https://m.example/10.png"}]}
+            ]"#,
+        ).unwrap();
+        let media = to_media(&Hashtag::new("tokyo").unwrap(), "m.example", statuses);
+
+        assert_eq!(media.len(), 1);
+        let (ref hashtag, ref m) = media[0];
+        assert_eq!(hashtag.as_str(), "tokyo");
+        assert_eq!(m.id.as_str(), "m.example/20");
+        assert_eq!(m.status_url, "https://m.example/s/2");
+        assert_eq!(m.user_name, "alice");
+        let tags: Vec<&str> = m.tags.iter().map(|h| h.as_str()).collect();
+        assert_eq!(tags, vec!["tokyo", "ad"]);
+    }
+}
@@ -0,0 +1,39 @@
+pub mod insta;
+pub mod local_dir;
+pub mod mastodon;
+pub mod webhook;
+
+pub use self::insta::InstaSource;
+pub use self::local_dir::{LocalDirConfig, LocalDirSource};
+pub use self::mastodon::{MastodonConfig, MastodonSource};
+pub use self::webhook::{IngestPost, WebhookConfig, WebhookSource};
+
+use std::str::FromStr;
+use futures::Stream;
+use hyper::Uri;
+
+use post::{GenericPost, HashtagList};
+use error::Error;
+
+/// Urls given by remote input must be http or https
+/// so that local files or other schemes are never read.
+pub fn is_http_url(url: &str) -> bool {
+    match Uri::from_str(url).ok().and_then(|uri| uri.scheme_part().cloned()) {
+        Some(scheme) => scheme.as_str() == "http" || scheme.as_str() == "https",
+        None => false,
+    }
+}
+
+pub type PostStream<SS> = Box<Stream<Item = GenericPost<SS>, Error = Error> + Send>;
+
+/// Source of posts which are fed into workers.
+/// Worker consumes any set of sources without knowing where posts come from.
+pub trait PostSource<SS>: Send + Sync {
+    /// Posts which had been posted before a worker started.
+    /// Worker stops consuming this stream once its mosaic art has enough pieces.
+    fn backfill(&self, hashtags: &HashtagList) -> PostStream<SS>;
+
+    /// Posts which are posted after a worker started.
+    /// This stream is expected to never end.
+    fn updates(&self, hashtags: &HashtagList) -> PostStream<SS>;
+}
@@ -0,0 +1,351 @@
+use std::{collections::{HashMap, HashSet}, sync::{Arc, Mutex}, thread,
+          time::{SystemTime, UNIX_EPOCH}};
+use futures::{Future, IntoFuture, Stream, future::{self, Either},
+              stream::empty, sync::mpsc::{self, UnboundedSender}};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use images::{ImageFetcher, Size};
+use post::{ExternalPost, ExternalPostId, GenericPost, Hashtag, HashtagList, Post};
+use db::Mongodb;
+use error::{Error, ErrorKind};
+use super::{is_http_url, PostSource, PostStream};
+
+// Number of images fetched at the same time.
+const INGEST_CONCURRENCY: usize = 4;
+// Requests signed more than this seconds before or after now are rejected.
+const SIGNATURE_TOLERANCE_SECS: u64 = 300;
+
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    // Shared with providers to sign request bodies.
+    pub secret: String,
+}
+
+/// Post pushed by a provider.
+#[derive(Deserialize, Debug)]
+pub struct IngestPost {
+    pub external_id: String,
+    // Either of `image_url` or base64 encoded `image` is required.
+    pub image_url: Option<String>,
+    pub image: Option<String>,
+    pub user_name: String,
+    pub hashtag: String,
+}
+
+/// Receives posts pushed through `POST /ingest`.
+///
+/// Ingested posts are fetched and persisted in an ingest thread,
+/// then sent to every running worker whose hashtags contain the hashtag of the post.
+pub struct WebhookSource<SS> {
+    config: WebhookConfig,
+    ingest_tx: UnboundedSender<IngestPost>,
+    subscribers: Arc<Mutex<Vec<Subscriber<SS>>>>,
+    replay_guard: ReplayGuard,
+}
+
+struct Subscriber<SS> {
+    hashtags: HashtagList,
+    tx: UnboundedSender<ExternalPost<SS>>,
+}
+
+impl<SS: Size> WebhookSource<SS> {
+    pub fn new(
+        config: WebhookConfig,
+        db: Mongodb,
+        image_fetcher: Arc<ImageFetcher>,
+    ) -> WebhookSource<SS> {
+        let (ingest_tx, ingest_rx) = mpsc::unbounded();
+        let subscribers = Arc::new(Mutex::new(Vec::new()));
+        let subscribers2 = subscribers.clone();
+        let in_flight = InFlight::new();
+
+        thread::spawn(move || {
+            let work = ingest_rx
+                .map(move |post| {
+                    fetch_post(&db, &image_fetcher, &in_flight, post).then(|res| match res {
+                        Ok(post) => Ok(post),
+                        Err(e) => {
+                            warn!("Fail to ingest a post : {:?}", e);
+                            Ok(None)
+                        }
+                    })
+                })
+                .buffer_unordered(INGEST_CONCURRENCY)
+                .for_each(move |post| {
+                    if let Some(post) = post {
+                        publish(&subscribers2, post);
+                    }
+                    Ok(())
+                });
+            ::tokio::run(work);
+        });
+
+        WebhookSource {
+            config: config,
+            ingest_tx: ingest_tx,
+            subscribers: subscribers,
+            replay_guard: ReplayGuard::new(),
+        }
+    }
+
+    /// Verifies `signature` of `timestamp` and `body`, then queues the post.
+    /// `timestamp` is unix time in seconds when the request is signed.
+    pub fn ingest(&self, body: &[u8], timestamp: &str, signature: &str) -> Result<(), Error> {
+        if !verify_signature(self.config.secret.as_bytes(), timestamp, body, signature) {
+            bail!(ErrorKind::InvalidSignature);
+        }
+        self.replay_guard.check(timestamp, signature, unix_now())?;
+
+        let post: IngestPost = ::serde_json::from_slice(body)?;
+        Hashtag::new(post.hashtag.as_str())?;
+        match (post.image_url.as_ref(), post.image.as_ref()) {
+            (None, None) => bail!(ErrorKind::InvalidIngestPost(
+                "image_url or image is required".into()
+            )),
+            (Some(url), None) if !is_http_url(url) => bail!(ErrorKind::InvalidIngestPost(
+                "image_url must be http or https".into()
+            )),
+            _ => {}
+        }
+        info!("Ingest post : {}", post.external_id);
+        self.ingest_tx
+            .unbounded_send(post)
+            .map_err(|_| Error::from(ErrorKind::IngestUnavailable))
+    }
+}
+
+impl<SS: Size> PostSource<SS> for WebhookSource<SS> {
+    // Posts which were ingested before are loaded from db by worker.
+    fn backfill(&self, _hashtags: &HashtagList) -> PostStream<SS> {
+        Box::new(empty())
+    }
+
+    fn updates(&self, hashtags: &HashtagList) -> PostStream<SS> {
+        let (tx, rx) = mpsc::unbounded();
+        self.subscribers.lock().unwrap().push(Subscriber {
+            hashtags: hashtags.clone(),
+            tx: tx,
+        });
+        let stream = rx.map(|p| GenericPost::ExternalPost(p))
+            .then(|res| Ok::<_, Error>(res.unwrap()));
+        Box::new(stream)
+    }
+}
+
+fn fetch_post<SS: Size>(
+    db: &Mongodb,
+    image_fetcher: &ImageFetcher,
+    in_flight: &InFlight,
+    post: IngestPost,
+) -> impl Future<Item = Option<ExternalPost<SS>>, Error = Error> {
+    let IngestPost {
+        external_id,
+        image_url,
+        image,
+        user_name,
+        hashtag,
+    } = post;
+    let id = ExternalPostId(external_id);
+    // Providers may retry sending the same post while the first one is being fetched.
+    let entry = match in_flight.start(&id) {
+        Some(entry) => entry,
+        None => return Either::A(future::ok(None)),
+    };
+    if db.contains_post("external", id.as_str()) || db.is_blocked_user(user_name.as_str()) {
+        return Either::A(future::ok(None));
+    }
+    let hashtag = match Hashtag::new(hashtag) {
+        Ok(hashtag) => hashtag,
+        Err(e) => return Either::A(future::err(e)),
+    };
+
+    // Base64 encoded image is read by image fetcher as data URI.
+    // Only http URL is stored because images of data URIs are not cached.
+    let (url, image_url) = match image {
+        Some(image) => (format!("data:;base64,{}", image), None),
+        None => (image_url.clone().unwrap_or_default(), image_url),
+    };
+    let db = db.clone();
+    let f = image_fetcher
+        .fetch_image::<SS>(url.as_str())
+        .into_future()
+        .and_then(|img_fut| img_fut)
+        .map(move |img| ExternalPost::new(id, img, user_name, hashtag))
+        .inspect(move |post| {
+            let image_url = image_url.as_ref().map(|url| url.as_str());
+            db.insert_post(&GenericPost::ExternalPost(post.clone()), image_url)
+        })
+        .map(Some)
+        // The post is in flight until it is stored or fails.
+        .then(move |res| {
+            drop(entry);
+            res
+        });
+    Either::B(f)
+}
+
+/// Ids of posts which are being fetched.
+#[derive(Clone)]
+struct InFlight(Arc<Mutex<HashSet<ExternalPostId>>>);
+
+impl InFlight {
+    fn new() -> InFlight {
+        InFlight(Arc::new(Mutex::new(HashSet::new())))
+    }
+
+    // Returns `None` if the post is already in flight.
+    // The post is in flight until the returned entry is dropped.
+    fn start(&self, id: &ExternalPostId) -> Option<InFlightEntry> {
+        if !self.0.lock().unwrap().insert(id.clone()) {
+            return None;
+        }
+        Some(InFlightEntry {
+            in_flight: self.clone(),
+            id: id.clone(),
+        })
+    }
+}
+
+struct InFlightEntry {
+    in_flight: InFlight,
+    id: ExternalPostId,
+}
+
+impl Drop for InFlightEntry {
+    fn drop(&mut self) {
+        self.in_flight.0.lock().unwrap().remove(&self.id);
+    }
+}
+
+// Subscribers of stopped workers are removed.
+fn publish<SS: Size>(subscribers: &Mutex<Vec<Subscriber<SS>>>, post: ExternalPost<SS>) {
+    subscribers.lock().unwrap().retain(|subscriber| {
+        if !subscriber.hashtags.iter().any(|h| &h == post.hashtag()) {
+            return true;
+        }
+        subscriber.tx.unbounded_send(post.clone()).is_ok()
+    });
+}
+
+/// Rejects requests signed long ago and requests which have already been accepted,
+/// so that a captured request can not be replayed.
+struct ReplayGuard {
+    // Timestamp of each accepted signature within tolerance.
+    accepted: Mutex<HashMap<String, u64>>,
+}
+
+impl ReplayGuard {
+    fn new() -> ReplayGuard {
+        ReplayGuard {
+            accepted: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn check(&self, timestamp: &str, signature: &str, now: u64) -> Result<(), Error> {
+        let timestamp = match timestamp.parse::<u64>() {
+            Ok(timestamp) => timestamp,
+            Err(_) => bail!(ErrorKind::IngestReplayed("invalid timestamp".into())),
+        };
+        let lag = if now > timestamp { now - timestamp } else { timestamp - now };
+        if lag > SIGNATURE_TOLERANCE_SECS {
+            bail!(ErrorKind::IngestReplayed("timestamp is too old or new".into()));
+        }
+        let mut accepted = self.accepted.lock().unwrap();
+        accepted.retain(|_, t| *t + SIGNATURE_TOLERANCE_SECS >= now);
+        if accepted.insert(signature.to_string(), timestamp).is_some() {
+            bail!(ErrorKind::IngestReplayed("request has been accepted before".into()));
+        }
+        Ok(())
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// `signature` is "sha256=<hex encoded HMAC-SHA256 of "<timestamp>.<body>">".
+fn verify_signature(secret: &[u8], timestamp: &str, body: &[u8], signature: &str) -> bool {
+    let code = match decode_hex(signature.trim_left_matches("sha256=")) {
+        Some(code) => code,
+        None => return false,
+    };
+    let mut mac = match Hmac::<Sha256>::new_varkey(secret) {
+        Ok(mac) => mac,
+        Err(_) => return false,
+    };
+    mac.input(timestamp.as_bytes());
+    mac.input(b".");
+    mac.input(body);
+    // Compared in constant time.
+    mac.verify(code.as_slice()).is_ok()
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| s.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // HMAC-SHA256 of "1500000000.hello" with key "secret".
+    const SIGNATURE: &str =
+        "sha256=d0b065844dc042414fbf4709d4dc8965c5296f64c75ddac5139fbf4cb98bf3da";
+
+    #[test]
+    fn accept_valid_signature() {
+        assert!(verify_signature(b"secret", "1500000000", b"hello", SIGNATURE));
+    }
+
+    #[test]
+    fn reject_invalid_signature() {
+        assert!(!verify_signature(b"secret", "1500000000", b"hello!", SIGNATURE));
+        assert!(!verify_signature(b"secret", "1500000001", b"hello", SIGNATURE));
+        assert!(!verify_signature(b"another", "1500000000", b"hello", SIGNATURE));
+        assert!(!verify_signature(b"secret", "1500000000", b"hello", "sha256=zz"));
+    }
+
+    #[test]
+    fn accept_only_http_image_url() {
+        assert!(is_http_url("https://example.com/a.png"));
+        assert!(is_http_url("http://example.com/a.png"));
+        assert!(!is_http_url("file:///etc/passwd"));
+        assert!(!is_http_url("ftp://example.com/a.png"));
+        assert!(!is_http_url("/etc/passwd"));
+    }
+
+    #[test]
+    fn skip_post_in_flight() {
+        let in_flight = InFlight::new();
+        let (a, b) = (ExternalPostId("a".into()), ExternalPostId("b".into()));
+        let entry = in_flight.start(&a).unwrap();
+        assert!(in_flight.start(&a).is_none());
+        assert!(in_flight.start(&b).is_some());
+        drop(entry);
+        assert!(in_flight.start(&a).is_some());
+    }
+
+    #[test]
+    fn reject_stale_or_replayed_request() {
+        let guard = ReplayGuard::new();
+        let now = 1500000000;
+        assert!(guard.check("1500000000", "sha256=a", now).is_ok());
+        assert!(guard.check("1500000000", "sha256=a", now + 1).is_err());
+        assert!(guard.check("1499999000", "sha256=b", now).is_err());
+        assert!(guard.check("1500001000", "sha256=c", now).is_err());
+        assert!(guard.check("abc", "sha256=d", now).is_err());
+        // Expired signatures are forgotten because their timestamps are rejected anyway.
+        assert!(guard.check("1500000400", "sha256=e", now + 400).is_ok());
+        assert!(guard.accepted.lock().unwrap().get("sha256=a").is_none());
+    }
+}
@@ -0,0 +1,103 @@
+use images::Size;
+use post::{GenericPost, InstaPostId, Post};
+
+/// Request to remove posts from every mosaic art and database.
+/// Removed posts are blocked so that they are never ingested again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Takedown {
+    // Every image of the post is removed if it is a carousel.
+    InstaPost(InstaPostId),
+    // Every post of the user over all sources.
+    // Instagram posts found through Graph API do not match because they have no user name.
+    User(String),
+}
+
+/// Fields of a post which takedown is matched against.
+/// It is kept instead of the post where its image is not needed.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PostIdentity {
+    kind: &'static str,
+    id: Option<String>,
+    user_name: String,
+}
+
+impl PostIdentity {
+    pub fn of<S: Size>(post: &GenericPost<S>) -> PostIdentity {
+        PostIdentity {
+            kind: post.kind(),
+            id: post.id().map(|id| id.to_string()),
+            user_name: post.user_name().to_string(),
+        }
+    }
+}
+
+impl Takedown {
+    pub fn matches<S: Size>(&self, post: &GenericPost<S>) -> bool {
+        self.matches_fields(post.kind(), post.id(), post.user_name())
+    }
+
+    pub fn matches_identity(&self, identity: &PostIdentity) -> bool {
+        self.matches_fields(
+            identity.kind,
+            identity.id.as_ref().map(|id| id.as_str()),
+            identity.user_name.as_str(),
+        )
+    }
+
+    fn matches_fields(&self, kind: &str, id: Option<&str>, user_name: &str) -> bool {
+        match self {
+            &Takedown::InstaPost(ref target) => match (kind, id) {
+                ("insta", Some(id)) => {
+                    InstaPostId(id.to_string()).shortcode() == target.shortcode()
+                }
+                _ => false,
+            },
+            &Takedown::User(ref target) => user_name == target.as_str(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use post::fixtures::{insta_post, local_post};
+
+    #[test]
+    fn match_every_image_of_insta_post() {
+        let takedown = Takedown::InstaPost(InstaPostId("abc".into()));
+        assert!(takedown.matches(&insta_post("abc", "alice")));
+        assert!(takedown.matches(&insta_post("abc:2", "alice")));
+        assert!(!takedown.matches(&insta_post("abcd", "alice")));
+        // Only Instagram posts have shortcodes.
+        assert!(!takedown.matches(&local_post("abc", "alice")));
+    }
+
+    #[test]
+    fn match_posts_of_user_over_sources() {
+        let takedown = Takedown::User("alice".into());
+        assert!(takedown.matches(&insta_post("abc", "alice")));
+        assert!(takedown.matches(&local_post("abc", "alice")));
+        assert!(!takedown.matches(&local_post("abc", "bob")));
+        // Graph API posts have no user name.
+        assert!(!takedown.matches(&insta_post("abc", "")));
+    }
+
+    #[test]
+    fn match_identity_same_as_post() {
+        let takedowns = vec![
+            Takedown::InstaPost(InstaPostId("abc".into())),
+            Takedown::User("alice".into()),
+        ];
+        let posts = vec![
+            insta_post("abc:1", "bob"),
+            insta_post("xyz", "alice"),
+            local_post("abc", "bob"),
+        ];
+        for takedown in takedowns.iter() {
+            for post in posts.iter() {
+                let identity = PostIdentity::of(post);
+                assert_eq!(takedown.matches_identity(&identity), takedown.matches(post));
+            }
+        }
+    }
+}
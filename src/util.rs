@@ -1,5 +1,8 @@
 use std::{mem, collections::HashMap, hash::{BuildHasher, Hasher}};
+#[cfg(test)]
+use std::{fs, path::{Path, PathBuf}};
 use rand::{FromEntropy, RngCore, prng::XorShiftRng};
+use sha2::{Digest, Sha256};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Id(u64);
@@ -51,6 +54,10 @@ impl<V> IdHashMap<V> {
     pub fn remove(&mut self, id: &Id) -> Option<V> {
         self.0.remove(id)
     }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.0.values()
+    }
 }
 
 pub struct NothingU64HasherBuilder;
@@ -95,3 +102,56 @@ impl Hasher for NothingU64Hasher {
         self.n = i;
     }
 }
+
+// Query parameters whose values must not be written into logs or fixtures.
+const SECRET_QUERY_PARAMS: &[&str] = &["access_token"];
+
+/// Replaces values of secret query parameters such as `access_token` in `url`.
+pub fn redact_url(url: &str) -> String {
+    let (base, query) = match url.find('?') {
+        Some(i) => (&url[..i], &url[i + 1..]),
+        None => return url.to_string(),
+    };
+    let query: Vec<String> = query
+        .split('&')
+        .map(|kv| match kv.find('=') {
+            Some(i) if SECRET_QUERY_PARAMS.contains(&&kv[..i]) => {
+                format!("{}=REDACTED", &kv[..i])
+            }
+            _ => kv.to_string(),
+        })
+        .collect();
+    format!("{}?{}", base, query.join("&"))
+}
+
+/// Returns lower case hex string of SHA-256 digest.
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Directory in the temporary directory of the system which is removed when dropped.
+#[cfg(test)]
+pub struct TempDir(PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    pub fn new(prefix: &str) -> TempDir {
+        let path = ::std::env::temp_dir().join(format!("{}_{}", prefix, ::rand::random::<u64>()));
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        self.0.as_path()
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
@@ -1,17 +1,28 @@
-use std::{ops::DerefMut, sync::{Arc, Mutex}};
-use futures::{Future, Stream, sync::{mpsc::{self, UnboundedSender}, oneshot::{self, Sender}}};
+use std::{ops::DerefMut, sync::{Arc, Mutex}, time::{Duration, Instant}};
+use futures::{Future, Stream, stream::iter_ok,
+              sync::{mpsc::{self, UnboundedSender}, oneshot::{self, Sender}}};
+use tokio::timer::Delay;
 
 use insta::InstaFeeder;
+use source::{InstaSource, LocalDirSource, MastodonSource, PostSource, PostStream, WebhookSource};
 use db::Mongodb;
-use post::{BluummPost, GenericPost, HashtagList};
-use images::{SizedImage, size::{MultipleOf, Size, SmallerThan}};
-use mosaic::{MosaicArt, MosaicArtGenerator};
+use config::Config;
+use post::{BluummPost, GenericPost, Hashtag, HashtagList, Post};
+use query::HashtagQuery;
+use moderation::{ModerationOption, PendingPost, PendingPostId};
+use takedown::{PostIdentity, Takedown};
+use images::{Image, ImageCache, ImageFetcher, ImageHash, QualityConfig, SizedImage,
+             size::{MultipleOf, Size, SmallerThan}};
+use mosaic::{MosaicArt, MosaicArtGenerator, Timelapse, TimelapseOption};
 use util::{Id, IdGenerator, IdHashMap};
 use error::Error;
 
 pub struct WorkerManager<S, SS> {
-    insta_feeder: Arc<InstaFeeder>,
+    sources: Vec<Arc<PostSource<SS>>>,
+    webhook: Option<Arc<WebhookSource<SS>>>,
     db: Mongodb,
+    image_cache: Option<Arc<ImageCache>>,
+    quality: QualityConfig,
     container: WorkerContainer<S, SS>,
 }
 
@@ -20,24 +31,106 @@ where
     S: Size + MultipleOf<SS>,
     SS: Size + SmallerThan<S>,
 {
-    pub fn new(db: Mongodb) -> WorkerManager<S, SS> {
-        let feeder = Arc::new(InstaFeeder::new(db.clone()));
+    pub fn new(db: Mongodb, config: &Config) -> WorkerManager<S, SS> {
+        // Image fetcher is shared by sources so that connections to each host are limited
+        // over all sources.
+        let image_cache = config.image_cache.clone().map(|c| {
+            Arc::new(ImageCache::open(c).expect("Fail to open image cache"))
+        });
+        let image_fetcher = Arc::new(ImageFetcher::new(
+            config.image_fetcher.clone(),
+            image_cache.clone(),
+        ));
+
+        db.detach_all_pending_posts();
+
+        let feeder = Arc::new(InstaFeeder::new(db.clone(), config, image_fetcher.clone()));
+        let mut sources: Vec<Arc<PostSource<SS>>> = vec![Arc::new(InstaSource::<SS>::new(feeder))];
+        if let Some(ref local_dir) = config.local_dir {
+            sources.push(Arc::new(LocalDirSource::<SS>::new(local_dir.clone(), db.clone())));
+        }
+        if let Some(ref mastodon) = config.mastodon {
+            sources.push(Arc::new(MastodonSource::new(
+                mastodon.clone(),
+                db.clone(),
+                image_fetcher.clone(),
+            )));
+        }
+        let webhook = config.webhook.clone().map(|webhook| {
+            Arc::new(WebhookSource::new(webhook, db.clone(), image_fetcher.clone()))
+        });
+        if let Some(ref webhook) = webhook {
+            sources.push(webhook.clone());
+        }
         WorkerManager {
-            insta_feeder: feeder,
+            sources: sources,
+            webhook: webhook,
             db: db,
+            image_cache: image_cache,
+            quality: config.quality.clone(),
             container: WorkerContainer::new(),
         }
     }
 
-    pub fn start_worker(&mut self, origin: SizedImage<S>, hashtags: HashtagList) -> WorkerId {
-        let worker = Worker::start(self.insta_feeder.clone(), self.db.clone(), origin, hashtags);
-        self.container.add(worker)
+    pub fn start_worker(
+        &mut self,
+        origin: SizedImage<S>,
+        query: HashtagQuery,
+        option: WorkerOption,
+    ) -> WorkerId {
+        let id = self.container.next_id();
+        let worker = Worker::start(
+            id,
+            self.sources.clone(),
+            self.db.clone(),
+            origin,
+            query,
+            option,
+        );
+        self.container.insert(id, worker);
+        id
+    }
+
+    /// Checks quality of the image of a post added through API.
+    /// A rejected post is recorded with the reason.
+    /// Resolution is not checked because clients may send images resized into a piece.
+    pub fn check_quality(
+        &self,
+        image: &Image,
+        user_name: &str,
+        hashtag: &Hashtag,
+    ) -> Result<(), Error> {
+        self.quality.check_content(image).map_err(|e| {
+            let reason = e.to_string();
+            self.db
+                .insert_rejected_post("bluumm", None, user_name, hashtag, &reason);
+            e
+        })
+    }
+
+    /// Returns `None` if webhook ingestion is not enabled.
+    pub fn webhook(&self) -> Option<Arc<WebhookSource<SS>>> {
+        self.webhook.clone()
     }
 
     pub fn get_worker(&self, id: WorkerId) -> Option<&Worker<S, SS>> {
         self.container.get(id)
     }
 
+    /// Removes posts from every worker, database and image cache, and blocks them.
+    pub fn take_down(&self, takedown: Takedown) {
+        self.db.add_to_blocklist(&takedown);
+        if let Some(ref image_cache) = self.image_cache {
+            for url in self.db.find_image_urls(&takedown) {
+                image_cache.remove(url.as_str());
+            }
+        }
+        self.db.delete_posts(&takedown);
+        for worker in self.container.iter() {
+            worker.take_down(&takedown);
+        }
+    }
+
     pub fn stop_worker(&mut self, id: WorkerId) -> bool {
         if let Some(worker) = self.container.take(id) {
             worker.stop();
@@ -49,10 +142,46 @@ where
 }
 
 const FILL_PROCESS_BOOST: usize = 4;
+// Updates of a source are read again after this delay when they fail.
+const SOURCE_RETRY_DELAY_SECS: u64 = 10;
+const DEFAULT_DUPLICATE_DISTANCE: u32 = 4;
+
+#[derive(Debug, Clone)]
+pub struct WorkerOption {
+    pub timelapse: Option<TimelapseOption>,
+    // Thumbnails of videos are used as pieces if true.
+    pub include_videos: bool,
+    // Posts are not used until approved if some.
+    pub moderation: Option<ModerationOption>,
+    // A post is a duplicate if the perceptual hash of its image is within this Hamming distance
+    // from another post which the worker has seen, including stored posts of its hashtags.
+    // Duplicates are not checked if none.
+    pub duplicate_distance: Option<u32>,
+}
+
+impl Default for WorkerOption {
+    fn default() -> WorkerOption {
+        WorkerOption {
+            timelapse: None,
+            include_videos: true,
+            moderation: None,
+            duplicate_distance: Some(DEFAULT_DUPLICATE_DISTANCE),
+        }
+    }
+}
 
 pub struct Worker<S, SS> {
+    id: WorkerId,
+    db: Mongodb,
+    query: Arc<HashtagQuery>,
+    include_videos: bool,
+    moderation: Option<ModerationOption>,
+    seen_hashes: Option<Arc<Mutex<HashPool>>>,
+    generator: Arc<Mutex<MosaicArtGenerator<S, SS>>>,
     current_art: Arc<Mutex<Arc<MosaicArt<S, SS>>>>,
+    timelapse: Option<Arc<Mutex<Timelapse>>>,
     bluumm_post_tx: UnboundedSender<BluummPost<SS>>,
+    approved_post_tx: UnboundedSender<GenericPost<SS>>,
     shutdown_tx: Sender<()>,
 }
 
@@ -62,74 +191,119 @@ where
     SS: Size + SmallerThan<S>,
 {
     fn start(
-        insta_feeder: Arc<InstaFeeder>,
+        id: WorkerId,
+        sources: Vec<Arc<PostSource<SS>>>,
         db: Mongodb,
         origin: SizedImage<S>,
-        hashtags: HashtagList,
+        query: HashtagQuery,
+        option: WorkerOption,
     ) -> Worker<S, SS> {
+        // Posts are searched by hashtags required by the query, then filtered by the query.
+        let hashtags = query.hashtags();
         let (mut generator, initial_art) = MosaicArtGenerator::new(origin, hashtags.clone());
 
         // Initialize
         info!("Initializing mosaic art...");
         let piece_n = ((S::WIDTH * S::HEIGHT) / (SS::WIDTH * SS::HEIGHT)) as i64;
-        let mut init_insta_posts = db.find_insta_posts_by_hashtags(&hashtags, piece_n);
-        let mut init_bluumm_posts = db.find_bluumm_posts_by_hashtags(&hashtags, piece_n);
-        let init_insta_posts_iter = init_insta_posts
-            .drain(..)
-            .map(|p| GenericPost::InstaPost(p));
-        let init_bluumm_posts_iter = init_bluumm_posts
-            .drain(..)
-            .map(|p| GenericPost::BluummPost(p));
-
-        let init_posts = init_bluumm_posts_iter // BluummPost have priority over InstaPost
-            .chain(init_insta_posts_iter)
+        let query = Arc::new(query);
+        let query2 = query.clone();
+        let include_videos = option.include_videos;
+        let init_posts = stored_posts(&db, &hashtags, &option.moderation, &[], piece_n)
+            .into_iter()
+            .filter(|p| include_videos || !p.is_video())
+            .filter(|p| is_selected(&query, p))
             .take(piece_n as usize);
+        let moderation = option.moderation.clone();
+        let moderation2 = option.moderation.clone();
+        let seen_hashes = option
+            .duplicate_distance
+            .map(|d| Arc::new(Mutex::new(HashPool::new(&db, &hashtags, d))));
+        let seen_hashes2 = seen_hashes.clone();
         for post in init_posts {
+            if is_duplicate(&seen_hashes, &post) {
+                continue;
+            }
             let _applied = generator.apply_post(post);
         }
+        // Posts left unreviewed by stopped workers or previous process are reviewed here.
+        if moderation.is_some() {
+            let taken = db.attach_pending_posts(id.into_raw(), |p: &GenericPost<SS>| {
+                (include_videos || !p.is_video()) && is_selected(&query, p)
+            });
+            info!("Take over {} pending posts", taken);
+        }
         info!("Initialized!!");
 
         // Create some thread sahred items
         let art = Arc::new(Mutex::new(Arc::new(initial_art)));
         let art2 = art.clone();
+        let timelapse = option.timelapse.map(|op| {
+            let mut timelapse = Timelapse::new(op);
+            for post in generator.posts() {
+                timelapse.add_post(PostIdentity::of(post));
+            }
+            Arc::new(Mutex::new(timelapse))
+        });
+        let timelapse2 = timelapse.clone();
         let (shutdown_tx, shutdown_rx) = oneshot::channel();
         let (bluumm_post_tx, bluumm_post_rx) = mpsc::unbounded();
+        let (approved_post_tx, approved_post_rx) = mpsc::unbounded();
+        let db2 = db.clone();
+        let db3 = db.clone();
         let hashtags = generator.hashtags();
         let generator = Arc::new(Mutex::new(generator));
         let generator2 = generator.clone();
+        let generator3 = generator.clone();
 
         ::std::thread::spawn(move || {
             let post_stream = {
-                let insta_post_stream = {
-                    let init_insta_post_stream = insta_feeder
-                        .get_bunch_of_posts(&hashtags)
-                        .take_while(move |_| {
+                let bluumm_post_stream: PostStream<SS> = Box::new(
+                    bluumm_post_rx
+                        .map(|p| GenericPost::BluummPost(p))
+                        .then(|res| Ok::<_, Error>(res.unwrap())),
+                );
+                sources.iter().fold(bluumm_post_stream, |merged, source| {
+                    let generator = generator.clone();
+                    let backfill_stream =
+                        end_on_error(source.backfill(&hashtags)).take_while(move |_| {
                             Ok::<_, Error>(!generator.lock().unwrap().has_enough_pieces())
                         });
-                    let update_insta_post_stream = insta_feeder.get_update_posts(&hashtags);
-                    init_insta_post_stream
-                        .chain(update_insta_post_stream)
-                        .map(|p| GenericPost::InstaPost(p))
-                };
-                let bluumm_post_stream = bluumm_post_rx
-                    .map(|p| GenericPost::BluummPost(p))
-                    .then(|res| Ok::<_, Error>(res.unwrap()));
-                insta_post_stream.select(bluumm_post_stream)
+                    let update_stream = restart_on_error(source.clone(), hashtags.clone());
+                    Box::new(merged.select(backfill_stream.chain(update_stream))) as PostStream<SS>
+                })
             };
 
-            let running = post_stream.for_each(move |post| {
-                let mut generator = generator2.lock().unwrap();
-                // Copy a new arrived post if art does not have enough pieces.
-                let boost = generator.has_enough_pieces() as usize * FILL_PROCESS_BOOST;
-                for _ in 0..boost {
-                    let _art = generator.apply_post(post.clone());
-                }
-
-                // Always apply at least one time.
-                let art = generator.apply_post(post);
-                *art2.lock().unwrap().deref_mut() = Arc::new(art); // replace old art with new art
-                Ok(())
-            });
+            // Approved posts have already passed filters.
+            let approved_post_stream =
+                approved_post_rx.then(|res| Ok::<_, Error>(res.unwrap()));
+
+            let running = post_stream
+                .filter(move |p| include_videos || !p.is_video())
+                .filter(move |p| is_selected(&query2, p))
+                .filter(move |p| !db3.is_blocked(p))
+                .filter(move |p| !is_duplicate(&seen_hashes2, p))
+                .filter(move |p| pass_moderation(&moderation2, &db2, id, p))
+                .select(approved_post_stream)
+                .for_each(move |post| {
+                    let mut generator = generator2.lock().unwrap();
+                    // Copy a new arrived post if art does not have enough pieces.
+                    let boost = generator.has_enough_pieces() as usize * FILL_PROCESS_BOOST;
+                    for _ in 0..boost {
+                        let _art = generator.apply_post(post.clone());
+                    }
+
+                    // Always apply at least one time.
+                    let identity = PostIdentity::of(&post);
+                    let art = generator.apply_post(post);
+                    if let Some(ref timelapse) = timelapse2 {
+                        let mut timelapse = timelapse.lock().unwrap();
+                        timelapse.add_post(identity);
+                        timelapse.record_if_due(&art.image);
+                    }
+                    // replace old art with new art
+                    *art2.lock().unwrap().deref_mut() = Arc::new(art);
+                    Ok(())
+                });
 
             // shutdown_tx is never dropped until shutdown.
             let shutdown = shutdown_rx.then(|res| Ok::<_, Error>(res.unwrap()));
@@ -142,8 +316,17 @@ where
         });
 
         Worker {
+            id: id,
+            db: db,
+            query: query,
+            include_videos: include_videos,
+            moderation: moderation,
+            seen_hashes: seen_hashes,
+            generator: generator3,
             current_art: art,
+            timelapse: timelapse,
             bluumm_post_tx: bluumm_post_tx,
+            approved_post_tx: approved_post_tx,
             shutdown_tx: shutdown_tx,
         }
     }
@@ -152,16 +335,235 @@ where
         self.current_art.lock().unwrap().clone()
     }
 
+    /// Returns `None` if timelapse recording is not enabled on this worker.
+    pub fn get_timelapse(&self) -> Option<Arc<Mutex<Timelapse>>> {
+        self.timelapse.clone()
+    }
+
     pub fn add_bluumm_post(&self, post: BluummPost<SS>) {
         self.bluumm_post_tx.unbounded_send(post).unwrap();
     }
 
+    /// Oldest posts waiting for review.
+    pub fn pending_posts(&self, limit: i64) -> Vec<PendingPost<SS>> {
+        self.db.find_pending_posts(self.id.into_raw(), limit)
+    }
+
+    /// Returns false if the post is not in the pending queue of this worker.
+    pub fn approve_post(&self, id: &PendingPostId) -> bool {
+        match self.db.take_pending_post(self.id.into_raw(), id) {
+            Some(post) => {
+                self.db.approve_post(&post);
+                self.approved_post_tx.unbounded_send(post).unwrap();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns false if the post is not in the pending queue of this worker.
+    pub fn reject_post(&self, id: &PendingPostId) -> bool {
+        self.db
+            .take_pending_post::<SS>(self.id.into_raw(), id)
+            .is_some()
+    }
+
+    /// Removes pieces of posts to be taken down
+    /// and refills vacated cells with stored posts which are not used yet.
+    pub fn take_down(&self, takedown: &Takedown) {
+        let mut generator = self.generator.lock().unwrap();
+        let removed = generator.remove_posts(|p| takedown.matches(p));
+        // Frames can not be rendered again without the posts.
+        if let Some(ref timelapse) = self.timelapse {
+            let dropped = timelapse
+                .lock()
+                .unwrap()
+                .purge(|p| takedown.matches_identity(p));
+            if dropped > 0 {
+                info!("Drop {} timelapse frames : {:?}", dropped, takedown);
+            }
+        }
+        if removed == 0 {
+            return;
+        }
+        info!("Take down {} pieces : {:?}", removed, takedown);
+
+        let piece_n = ((S::WIDTH * S::HEIGHT) / (SS::WIDTH * SS::HEIGHT)) as i64;
+        let hashtags = generator.hashtags();
+        let refills: Vec<GenericPost<SS>> = {
+            // Used posts are skipped by DB so that they do not fill up the limit.
+            // Posts without ids such as BluummPost can only be skipped here.
+            let used: Vec<(&str, &str)> = generator
+                .posts()
+                .filter_map(|p| p.id().map(|id| (p.kind(), id)))
+                .collect();
+            stored_posts(&self.db, &hashtags, &self.moderation, &used, piece_n)
+                .into_iter()
+                .filter(|p| !takedown.matches(p))
+                .filter(|p| self.include_videos || !p.is_video())
+                .filter(|p| is_selected(&self.query, p))
+                .filter(|p| !generator.posts().any(|used| used.is_same_post(p)))
+                .filter(|p| !is_duplicate(&self.seen_hashes, p))
+                .take(removed)
+                .collect()
+        };
+        for post in refills {
+            let _applied = generator.apply_post(post);
+        }
+        *self.current_art.lock().unwrap().deref_mut() = Arc::new(generator.current_art());
+    }
+
     fn stop(self) {
         let _ = self.shutdown_tx.send(());
+        self.db.detach_pending_posts_of_worker(self.id.into_raw());
+    }
+}
+
+// Posts stored in DB which have any of hashtags.
+// Stored posts are not reviewed again if moderation is enabled.
+// Only approved ones are used because others were put in pending queues when they arrived.
+// Posts in `exclude` given as pairs of kind and id are skipped.
+fn stored_posts<SS: Size>(
+    db: &Mongodb,
+    hashtags: &HashtagList,
+    moderation: &Option<ModerationOption>,
+    exclude: &[(&str, &str)],
+    limit: i64,
+) -> Vec<GenericPost<SS>> {
+    let mut posts = match moderation {
+        &Some(ref moderation) => db.find_approved_posts_by_hashtags(
+            hashtags,
+            &moderation.trusted_users,
+            exclude,
+            limit,
+        ),
+        &None => db.find_posts_by_hashtags(hashtags, exclude, limit),
+    };
+    // BluummPost have priority over posts from other sources.
+    posts.sort_by_key(|p| p.kind() != "bluumm");
+    posts
+}
+
+// Stream of a source ends at its first error,
+// so that a failing source never stops a worker consuming other sources.
+fn end_on_error<SS: Size>(stream: PostStream<SS>) -> PostStream<SS> {
+    let stream = stream
+        .then(|res| Ok::<_, Error>(res))
+        .take_while(|res| {
+            if let &Err(ref e) = res {
+                error!("Error while reading a source : {:?}", e);
+            }
+            Ok(res.is_ok())
+        })
+        .filter_map(|res| res.ok());
+    Box::new(stream)
+}
+
+// Updates are read again from the source after a delay when they fail.
+fn restart_on_error<SS: Size>(
+    source: Arc<PostSource<SS>>,
+    hashtags: HashtagList,
+) -> PostStream<SS> {
+    let stream = iter_ok::<_, Error>(0u64..)
+        .and_then(|restarts| {
+            let delay = match restarts {
+                0 => Duration::from_secs(0),
+                _ => Duration::from_secs(SOURCE_RETRY_DELAY_SECS),
+            };
+            Delay::new(Instant::now() + delay).map_err(Error::from)
+        })
+        .map(move |_| end_on_error(source.updates(&hashtags)))
+        .flatten();
+    Box::new(stream)
+}
+
+// Posts added to the worker directly are always used.
+fn is_selected<SS: Size>(query: &HashtagQuery, post: &GenericPost<SS>) -> bool {
+    match post {
+        &GenericPost::BluummPost(_) => true,
+        _ => query.matches(&post.hashtags()),
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+// Reposts of an image which the worker has seen are duplicates.
+// Duplicates are not checked if `seen_hashes` is none.
+fn is_duplicate<SS: Size>(
+    seen_hashes: &Option<Arc<Mutex<HashPool>>>,
+    post: &GenericPost<SS>,
+) -> bool {
+    let duplicate = match seen_hashes {
+        &Some(ref seen_hashes) => !seen_hashes.lock().unwrap().insert(
+            post.kind(),
+            post.id(),
+            post.phash(),
+        ),
+        &None => false,
+    };
+    if duplicate {
+        debug!("Skip a duplicate post by {}", post.user_name());
+    }
+    duplicate
+}
+
+// Perceptual hashes of posts which a worker has seen with their kinds and ids.
+// Only hashes are kept so that every stored post of the hashtags can be compared,
+// even after its piece has been replaced.
+struct HashPool {
+    max_distance: u32,
+    hashes: Vec<(String, Option<String>, ImageHash)>,
+}
+
+impl HashPool {
+    // Stored posts are added from the oldest one, so that the original of reposts is kept.
+    fn new(db: &Mongodb, hashtags: &HashtagList, max_distance: u32) -> HashPool {
+        let mut pool = HashPool {
+            max_distance: max_distance,
+            hashes: Vec::new(),
+        };
+        for (kind, id, hash) in db.find_phashes_by_hashtags(hashtags) {
+            pool.insert(kind.as_str(), id.as_ref().map(|id| id.as_str()), hash);
+        }
+        pool
+    }
+
+    // Returns false if another post has a similar image, otherwise the post is added.
+    // Posts without ids are always different posts.
+    fn insert(&mut self, kind: &str, id: Option<&str>, hash: ImageHash) -> bool {
+        let max_distance = self.max_distance;
+        let is_same = |&&(ref k, ref i, _): &&(String, Option<String>, ImageHash)| {
+            id.is_some() && k.as_str() == kind && i.as_ref().map(|i| i.as_str()) == id
+        };
+        let similar = self.hashes
+            .iter()
+            .filter(|entry| !is_same(entry))
+            .any(|&(_, _, ref h)| h.distance(&hash) <= max_distance);
+        if similar {
+            return false;
+        }
+        if !self.hashes.iter().any(|entry| is_same(&entry)) {
+            self.hashes.push((kind.to_string(), id.map(|id| id.to_string()), hash));
+        }
+        true
+    }
+}
+
+// Puts the post in the pending queue instead if it needs review.
+fn pass_moderation<SS: Size>(
+    moderation: &Option<ModerationOption>,
+    db: &Mongodb,
+    id: WorkerId,
+    post: &GenericPost<SS>,
+) -> bool {
+    match moderation {
+        &Some(ref moderation) if !moderation.is_trusted(post) => {
+            db.insert_pending_post(id.into_raw(), post);
+            false
+        }
+        _ => true,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct WorkerId(Id);
 
 impl WorkerId {
@@ -193,10 +595,12 @@ impl<S, SS> WorkerContainer<S, SS> {
         }
     }
 
-    fn add(&mut self, worker: Worker<S, SS>) -> WorkerId {
-        let id = self.id_gen.next_id();
-        self.container.insert(id, worker);
-        WorkerId(id)
+    fn next_id(&mut self) -> WorkerId {
+        WorkerId(self.id_gen.next_id())
+    }
+
+    fn insert(&mut self, id: WorkerId, worker: Worker<S, SS>) {
+        self.container.insert(id.0, worker);
     }
 
     fn get(&self, id: WorkerId) -> Option<&Worker<S, SS>> {
@@ -206,4 +610,278 @@ impl<S, SS> WorkerContainer<S, SS> {
     fn take(&mut self, id: WorkerId) -> Option<Worker<S, SS>> {
         self.container.remove(&id.0)
     }
+
+    fn iter(&self) -> impl Iterator<Item = &Worker<S, SS>> {
+        self.container.values()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{thread, time::{Duration, Instant}};
+    use image::{Rgba, RgbaImage};
+    use db::test_db;
+    use images::size::{Size100x100, Size1500x1500};
+    use post::fixtures::{bluumm_post, local_post};
+
+    type TestWorker = Worker<Size1500x1500, Size100x100>;
+
+    fn start_worker(db: Mongodb, option: WorkerOption) -> TestWorker {
+        let origin = Image::new(RgbaImage::from_fn(1500, 1500, |x, y| Rgba {
+            data: [(x / 6) as u8, (y / 6) as u8, 128, 255],
+        }));
+        let query = HashtagQuery::any_of(&HashtagList::new(vec!["tokyo".into()]).unwrap());
+        let origin = SizedImage::with_resize(origin);
+        Worker::start(WorkerId::from_raw(1), Vec::new(), db, origin, query, option)
+    }
+
+    fn moderated(trusted_users: Vec<String>) -> WorkerOption {
+        WorkerOption {
+            moderation: Some(ModerationOption {
+                trusted_users: trusted_users,
+            }),
+            ..WorkerOption::default()
+        }
+    }
+
+    fn pieces(worker: &TestWorker) -> usize {
+        worker.generator.lock().unwrap().posts().count()
+    }
+
+    fn piece_ids(worker: &TestWorker) -> Vec<String> {
+        let mut ids: Vec<String> = worker
+            .generator
+            .lock()
+            .unwrap()
+            .posts()
+            .filter_map(|p| p.id().map(|id| id.to_string()))
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    // Posts are applied by the thread of the worker.
+    fn wait_until<F: Fn() -> bool>(f: F) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            if f() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+
+    struct FailingSource;
+
+    impl<SS: Size> PostSource<SS> for FailingSource {
+        fn backfill(&self, _hashtags: &HashtagList) -> PostStream<SS> {
+            Box::new(::futures::stream::once(Err(Error::from("broken backfill"))))
+        }
+
+        fn updates(&self, _hashtags: &HashtagList) -> PostStream<SS> {
+            Box::new(::futures::stream::once(Err(Error::from("broken updates"))))
+        }
+    }
+
+    #[test]
+    fn end_source_stream_at_error() {
+        let posts: Vec<Result<GenericPost<Size100x100>, Error>> = vec![
+            Ok(GenericPost::BluummPost(bluumm_post("alice"))),
+            Err(Error::from("broken")),
+            Ok(GenericPost::BluummPost(bluumm_post("bob"))),
+        ];
+        let stream: PostStream<Size100x100> = Box::new(::futures::stream::iter_result(posts));
+        let read = end_on_error(stream).collect().wait().unwrap();
+        assert_eq!(read.len(), 1);
+        assert_eq!(read[0].user_name(), "alice");
+    }
+
+    #[test]
+    #[ignore] // requires MongoDB
+    fn keep_working_when_source_fails() {
+        let origin = Image::new(RgbaImage::from_fn(1500, 1500, |x, y| Rgba {
+            data: [(x / 6) as u8, (y / 6) as u8, 128, 255],
+        }));
+        let query = HashtagQuery::any_of(&HashtagList::new(vec!["tokyo".into()]).unwrap());
+        let sources: Vec<Arc<PostSource<Size100x100>>> = vec![Arc::new(FailingSource)];
+        let worker: TestWorker = Worker::start(
+            WorkerId::from_raw(1),
+            sources,
+            test_db("failing_source"),
+            SizedImage::with_resize(origin),
+            query,
+            WorkerOption::default(),
+        );
+        // Wait for the source to fail.
+        thread::sleep(Duration::from_millis(100));
+        worker.add_bluumm_post(bluumm_post("alice"));
+        assert!(wait_until(|| pieces(&worker) == 1));
+        worker.stop();
+    }
+
+    #[test]
+    #[ignore] // requires MongoDB
+    fn approve_pending_post() {
+        let worker = start_worker(test_db("approve_pending_post"), moderated(vec![]));
+        worker.add_bluumm_post(bluumm_post("alice"));
+        assert!(wait_until(|| worker.pending_posts(10).len() == 1));
+        let pending = worker.pending_posts(10).remove(0);
+        assert_eq!(pending.post.user_name(), "alice");
+        assert_eq!(pieces(&worker), 0);
+
+        assert!(worker.approve_post(&pending.id));
+        assert!(wait_until(|| pieces(&worker) == 1));
+        assert!(worker.pending_posts(10).is_empty());
+        // A post is reviewed only once.
+        assert!(!worker.approve_post(&pending.id));
+        assert!(!worker.reject_post(&pending.id));
+        worker.stop();
+    }
+
+    #[test]
+    #[ignore] // requires MongoDB
+    fn reject_pending_post() {
+        let worker = start_worker(test_db("reject_pending_post"), moderated(vec![]));
+        worker.add_bluumm_post(bluumm_post("alice"));
+        assert!(wait_until(|| worker.pending_posts(10).len() == 1));
+        let pending = worker.pending_posts(10).remove(0);
+
+        assert!(worker.reject_post(&pending.id));
+        assert!(worker.pending_posts(10).is_empty());
+        assert!(!worker.approve_post(&pending.id));
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(pieces(&worker), 0);
+        worker.stop();
+    }
+
+    #[test]
+    #[ignore] // requires MongoDB
+    fn use_posts_of_trusted_users_without_review() {
+        let option = moderated(vec!["alice".into()]);
+        let worker = start_worker(test_db("trusted_users"), option);
+        worker.add_bluumm_post(bluumm_post("alice"));
+        assert!(wait_until(|| pieces(&worker) == 1));
+        assert!(worker.pending_posts(10).is_empty());
+        worker.stop();
+    }
+
+    #[test]
+    #[ignore] // requires MongoDB
+    fn skip_stored_posts_of_blocked_users() {
+        let db = test_db("skip_blocked_users");
+        db.add_to_blocklist(&Takedown::User("alice".into()));
+        // Stored by a source after the user is taken down.
+        db.insert_post(&local_post("a", "alice"), None);
+        db.insert_post(&local_post("b", "bob"), None);
+
+        let option = WorkerOption {
+            duplicate_distance: None,
+            ..WorkerOption::default()
+        };
+        let worker = start_worker(db, option);
+        assert_eq!(piece_ids(&worker), vec!["b"]);
+        worker.stop();
+    }
+
+    #[test]
+    #[ignore] // requires MongoDB
+    fn take_over_pending_posts_of_stopped_workers() {
+        let db = test_db("take_over_pending_posts");
+        db.insert_pending_post(7, &local_post("a", "alice"));
+        // Restart of process.
+        db.detach_all_pending_posts();
+
+        let worker = start_worker(db.clone(), moderated(vec![]));
+        assert_eq!(worker.pending_posts(10).len(), 1);
+        worker.stop();
+        let worker = start_worker(db.clone(), moderated(vec![]));
+        assert_eq!(worker.pending_posts(10).len(), 1);
+        worker.stop();
+    }
+
+    #[test]
+    #[ignore] // requires MongoDB
+    fn use_only_approved_stored_posts_at_start() {
+        let db = test_db("approved_stored_posts");
+        let approved = local_post("approved", "alice");
+        db.insert_post(&approved, None);
+        db.insert_post(&local_post("unreviewed", "alice"), None);
+        db.approve_post(&approved);
+
+        let worker = start_worker(db.clone(), moderated(vec![]));
+        assert_eq!(piece_ids(&worker), vec!["approved"]);
+        // Unreviewed stored posts are not put in the pending queue again.
+        assert!(worker.pending_posts(10).is_empty());
+        worker.stop();
+    }
+
+    #[test]
+    #[ignore] // requires MongoDB
+    fn skip_reposts_of_pieces() {
+        let db = test_db("skip_reposts");
+        // Images of test posts are the same.
+        db.insert_post(&local_post("a", "alice"), None);
+        db.insert_post(&local_post("b", "bob"), None);
+        let worker = start_worker(db, WorkerOption::default());
+        assert_eq!(pieces(&worker), 1);
+
+        worker.add_bluumm_post(bluumm_post("carol"));
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(pieces(&worker), 1);
+        worker.stop();
+    }
+
+    #[test]
+    fn skip_similar_hashes_of_other_posts() {
+        let mut pool = HashPool {
+            max_distance: 1,
+            hashes: Vec::new(),
+        };
+        assert!(pool.insert("local", Some("a"), ImageHash(0b100)));
+        // The same post is not a duplicate of itself.
+        assert!(pool.insert("local", Some("a"), ImageHash(0b100)));
+        assert!(!pool.insert("local", Some("b"), ImageHash(0b101)));
+        assert!(!pool.insert("bluumm", None, ImageHash(0b100)));
+        assert!(pool.insert("local", Some("c"), ImageHash(0b111)));
+        assert_eq!(pool.hashes.len(), 2);
+    }
+
+    #[test]
+    #[ignore] // requires MongoDB
+    fn skip_reposts_of_replaced_pieces() {
+        let db = test_db("skip_reposts_of_replaced");
+        db.insert_post(&local_post("a", "alice"), None);
+        let worker = start_worker(db, WorkerOption::default());
+        assert_eq!(piece_ids(&worker), vec!["a"]);
+        worker.take_down(&Takedown::User("alice".into()));
+        assert_eq!(pieces(&worker), 0);
+
+        // Images of test posts are the same.
+        worker.add_bluumm_post(bluumm_post("bob"));
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(pieces(&worker), 0);
+        worker.stop();
+    }
+
+    #[test]
+    #[ignore] // requires MongoDB
+    fn refill_taken_down_pieces_with_unused_posts() {
+        let db = test_db("refill_taken_down_pieces");
+        db.insert_post(&local_post("a", "alice"), None);
+        db.insert_post(&local_post("b", "bob"), None);
+        // Images of test posts are the same.
+        let option = WorkerOption {
+            duplicate_distance: None,
+            ..WorkerOption::default()
+        };
+        let worker = start_worker(db.clone(), option);
+        assert_eq!(piece_ids(&worker), vec!["a", "b"]);
+        db.insert_post(&local_post("c", "carol"), None);
+
+        worker.take_down(&Takedown::User("alice".into()));
+        assert_eq!(piece_ids(&worker), vec!["b", "c"]);
+        worker.stop();
+    }
 }